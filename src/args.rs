@@ -3,7 +3,7 @@ use std::path::PathBuf;
 
 use clap::{Args, Parser};
 
-use crate::{network, save};
+use crate::{network, procedural_functions, save, world::GeneratorType};
 
 pub fn get_args() -> GameArgs {
     GameArgs::parse()
@@ -16,6 +16,9 @@ pub enum GameArgs {
 
     /// Client mode
     Client(ClientArgs),
+
+    /// Draw the world to a PNG file, one pixel per block, without opening a window
+    RenderMap(MapArgs),
 }
 
 #[derive(Args, Debug, Clone)]
@@ -28,6 +31,24 @@ pub struct ServerArgs {
     /// Port to open server on
     #[arg(short = 'p', long, default_value_t = network::DEFAULT_SERVER_PORT)]
     pub port: u16,
+
+    /// How many players can be connected at once
+    #[arg(short = 'm', long, default_value_t = network::DEFAULT_MAX_PLAYERS)]
+    pub max_players: usize,
+
+    /// World seed, a number or any text; random if not given, ignored when loading a save
+    #[arg(short = 's', long, value_parser = parse_seed)]
+    pub seed: Option<u64>,
+
+    /// How to generate chunks; ignored when loading a save
+    #[arg(short = 'g', long, value_enum, default_value_t = GeneratorType::Default)]
+    pub generator: GeneratorType,
+}
+
+/// Numbers are used as-is, anything else is hashed into a seed
+fn parse_seed(s: &str) -> Result<u64, String> {
+    Ok(s.parse::<u64>()
+        .unwrap_or_else(|_| procedural_functions::seed_from_str(s)))
 }
 
 #[derive(Args, Debug, Clone)]
@@ -44,4 +65,55 @@ pub struct ClientArgs {
     /// Port of client
     #[arg(short = 'c', long, default_value_t = 0)]
     pub client_port: u16,
+
+    /// Name to play as, remembered for next time
+    #[arg(short = 'n', long)]
+    pub name: Option<String>,
+
+    /// File that keeps who you are, so that servers give you back your player when you rejoin
+    #[arg(long = "identity", default_value_os_t = save::default_identity_path_client())]
+    pub identity_file: PathBuf,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct MapArgs {
+    /// PNG file to draw the map to
+    #[arg(short = 'o', long, default_value = "map.png")]
+    pub output: PathBuf,
+
+    /// Save file to draw, instead of generating a new world
+    #[arg(short = 'f', long = "file")]
+    pub save_file: Option<PathBuf>,
+
+    /// World seed, a number or any text; random if not given, ignored when drawing a save
+    #[arg(short = 's', long, value_parser = parse_seed)]
+    pub seed: Option<u64>,
+
+    /// How to generate chunks; ignored when drawing a save
+    #[arg(short = 'g', long, value_enum, default_value_t = GeneratorType::Default)]
+    pub generator: GeneratorType,
+
+    /// Leftmost chunk column to generate
+    #[arg(short = 'l', long, default_value_t = -2, allow_hyphen_values = true)]
+    pub left: i64,
+
+    /// How many chunk columns to generate
+    #[arg(short = 'w', long, default_value_t = 4)]
+    pub width: u64,
+
+    /// How many rows of chunks to generate, starting at the surface
+    #[arg(short = 'd', long, default_value_t = 8)]
+    pub depth: u64,
+
+    /// Draw lines where the biomes change
+    #[arg(long)]
+    pub biomes: bool,
+
+    /// Outline the ore veins
+    #[arg(long)]
+    pub veins: bool,
+
+    /// Draw lines between chunks
+    #[arg(long)]
+    pub chunk_borders: bool,
 }
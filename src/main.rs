@@ -2,6 +2,7 @@ use bevy::{diagnostic, prelude::*, window::PresentMode};
 
 mod args;
 mod credit_image;
+mod map;
 mod menu;
 mod network;
 mod player;
@@ -13,6 +14,8 @@ mod world;
 const TITLE: &str = "The Krusty Krabs";
 const WIN_W: f32 = 1280.;
 const WIN_H: f32 = 720.;
+/// Where the textures and other assets are loaded from
+const ASSETS_DIR: &str = "assets";
 
 #[derive(Component)]
 pub struct CharacterCamera;
@@ -74,6 +77,13 @@ fn main() {
             // client network plugin
             app.add_plugin(network::client::ClientPlugin { args });
         }
+
+        args::GameArgs::RenderMap(args) => {
+            // no window or renderer, the map gets drawn once and then the app exits
+            app.add_plugin(bevy::log::LogPlugin)
+                .insert_resource(args)
+                .add_startup_system(map::render_map);
+        }
     }
 
     app.run();
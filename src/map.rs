@@ -0,0 +1,333 @@
+use bevy::prelude::*;
+use image::{Rgba, RgbaImage};
+use std::{collections::HashMap, path::Path};
+use strum::IntoEnumIterator;
+
+use crate::{
+    args::MapArgs,
+    save,
+    world::{
+        generator::DefaultGenerator, BlockType, Chunk, ChunkBiomes, ChunkStore, GeneratorType,
+        Terrain, CHUNK_HEIGHT, CHUNK_WIDTH,
+    },
+    ASSETS_DIR,
+};
+
+/// Empty blocks in the surface chunks are sky, the same color as the client's background
+const SKY_COLOR: Rgba<u8> = Rgba([0, 153, 204, 255]);
+/// Empty blocks below the surface chunks are caves
+const CAVE_COLOR: Rgba<u8> = Rgba([24, 20, 18, 255]);
+/// Blocks that have no texture to take the color from
+const MISSING_COLOR: Rgba<u8> = Rgba([255, 0, 255, 255]);
+const BIOME_COLOR: Rgba<u8> = Rgba([255, 40, 40, 255]);
+const VEIN_COLOR: Rgba<u8> = Rgba([255, 230, 0, 255]);
+const CHUNK_BORDER_COLOR: Rgba<u8> = Rgba([255, 255, 255, 255]);
+
+/// What to draw on top of the blocks
+#[derive(Default)]
+pub struct Overlays<'a> {
+    /// The generator and seed that the world was made with, needed for biomes and veins
+    pub generator: Option<(&'a DefaultGenerator, u64)>,
+    pub biomes: bool,
+    pub veins: bool,
+    pub chunk_borders: bool,
+}
+
+/// Generate or load a world and draw it to a PNG file
+/// Runs once as a startup system, with no window
+pub fn render_map(args: Res<MapArgs>) {
+    let (seed, generator_type, terrain) = match &args.save_file {
+        Some(path) => match save::read_save_file(path) {
+            Ok(load) => (load.seed.seed, load.generator, saved_chunks(path)),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        },
+        None => {
+            let seed = args.seed.unwrap_or_else(rand::random);
+            let generator = match args.generator.create() {
+                Ok(generator) => generator,
+                Err(e) => {
+                    error!("{}", e);
+                    return;
+                }
+            };
+
+            let mut terrain = Terrain::empty();
+            for chunk_x in args.left..args.left + args.width as i64 {
+                for chunk_number in 0..args.depth {
+                    terrain.insert_chunk(generator.chunk(seed, chunk_x, chunk_number));
+                }
+            }
+            (seed, args.generator, terrain)
+        }
+    };
+    info!("world seed is {}", seed);
+    info!("world generator is {:?}", generator_type);
+
+    // only the default generator has biomes and veins to draw
+    let wants_generator = args.biomes || args.veins;
+    if wants_generator && generator_type != GeneratorType::Default {
+        warn!(
+            "generator {:?} has no biomes or veins to draw",
+            generator_type
+        );
+    }
+    let generator = if wants_generator && generator_type == GeneratorType::Default {
+        match DefaultGenerator::new() {
+            Ok(generator) => Some(generator),
+            Err(e) => {
+                error!("{}", e);
+                return;
+            }
+        }
+    } else {
+        None
+    };
+
+    let overlays = Overlays {
+        generator: generator.as_ref().map(|generator| (generator, seed)),
+        biomes: args.biomes,
+        veins: args.veins,
+        chunk_borders: args.chunk_borders,
+    };
+    let colors = block_colors(Path::new(ASSETS_DIR));
+
+    let map = match draw_map(&terrain, &colors, &overlays) {
+        Some(map) => map,
+        None => {
+            error!("there are no chunks to draw");
+            return;
+        }
+    };
+    match map.save(&args.output) {
+        Ok(_) => info!(
+            "drew {} chunk(s) to {:?}, {}x{} pixels",
+            terrain.len(),
+            args.output,
+            map.width(),
+            map.height()
+        ),
+        Err(e) => error!("unable to write map, {}", e),
+    }
+}
+
+/// Every chunk of a save file's world that can be read, from its chunk store
+fn saved_chunks(save_file: &Path) -> Terrain {
+    let store = ChunkStore::new(save::chunk_store_path(save_file));
+    store
+        .positions()
+        .into_iter()
+        .filter_map(
+            |(chunk_x, chunk_number)| match store.load_chunk(chunk_x, chunk_number) {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    warn!("{}", e);
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+/// Draw every chunk of a terrain, one pixel per block, from the surface down
+/// Pixels for chunks that the terrain doesn't have are left transparent
+/// Returns None if there are no chunks
+pub fn draw_map(
+    terrain: &Terrain,
+    colors: &HashMap<BlockType, Rgba<u8>>,
+    overlays: &Overlays,
+) -> Option<RgbaImage> {
+    if terrain.is_empty() {
+        return None;
+    }
+    let left = terrain.chunks().map(|c| c.chunk_x).min()?;
+    let right = terrain.chunks().map(|c| c.chunk_x).max()?;
+    let bottom = terrain.chunks().map(|c| c.chunk_number).max()?;
+
+    let mut map = RgbaImage::new(
+        ((right - left + 1) as usize * CHUNK_WIDTH) as u32,
+        ((bottom + 1) as usize * CHUNK_HEIGHT) as u32,
+    );
+
+    for chunk in terrain.chunks() {
+        // top left pixel of the chunk
+        let left_px = ((chunk.chunk_x - left) as usize * CHUNK_WIDTH) as u32;
+        let top_px = (chunk.chunk_number as usize * CHUNK_HEIGHT) as u32;
+        let mut put = |x: usize, y: usize, color: Rgba<u8>| {
+            map.put_pixel(left_px + x as u32, top_px + y as u32, color);
+        };
+
+        for (y, row) in chunk.blocks.iter().enumerate() {
+            for (x, block) in row.iter().enumerate() {
+                let color = match block {
+                    Some(block) => *colors.get(&block.block_type).unwrap_or(&MISSING_COLOR),
+                    None if chunk.chunk_number == 0 => SKY_COLOR,
+                    None => CAVE_COLOR,
+                };
+                put(x, y, color);
+            }
+        }
+
+        if let Some((generator, seed)) = overlays.generator {
+            if overlays.veins {
+                for (x, y) in vein_outlines(generator, seed, chunk) {
+                    put(x, y, VEIN_COLOR);
+                }
+            }
+            if overlays.biomes {
+                for (x, y) in biome_boundary(generator, seed, chunk) {
+                    put(x, y, BIOME_COLOR);
+                }
+            }
+        }
+
+        // each chunk draws its top and left edges
+        if overlays.chunk_borders {
+            for x in 0..CHUNK_WIDTH {
+                put(x, 0, CHUNK_BORDER_COLOR);
+            }
+            for y in 0..CHUNK_HEIGHT {
+                put(0, y, CHUNK_BORDER_COLOR);
+            }
+        }
+    }
+
+    Some(map)
+}
+
+/// Blocks in a chunk where its upper biome meets its lower one
+fn biome_boundary(generator: &DefaultGenerator, seed: u64, chunk: &Chunk) -> Vec<(usize, usize)> {
+    let biomes = ChunkBiomes::new(generator.biomes(), seed, chunk.chunk_number);
+    if biomes.upper == biomes.lower {
+        return Vec::new();
+    }
+
+    (0..CHUNK_WIDTH)
+        .map(|x| {
+            let world_x = chunk.chunk_x * CHUNK_WIDTH as i64 + x as i64;
+            (
+                x,
+                generator.biome_change_y(seed, &biomes, chunk.chunk_number, world_x),
+            )
+        })
+        .filter(|(_, y)| *y < CHUNK_HEIGHT)
+        .collect()
+}
+
+/// Blocks in a chunk on the edges of ore veins, whether or not a cave or structure replaced them
+fn vein_outlines(generator: &DefaultGenerator, seed: u64, chunk: &Chunk) -> Vec<(usize, usize)> {
+    let veins = generator.veins(seed, chunk.chunk_x, chunk.chunk_number);
+    let in_vein = |x: f32, y: f32| {
+        veins.iter().any(|vein| {
+            let (vein_x, vein_y) = vein.local_position(chunk.chunk_x, chunk.chunk_number, 0, 0);
+            vein.contains(vein_x + x, vein_y + y)
+        })
+    };
+
+    let mut outlines = Vec::new();
+    for y in 0..CHUNK_HEIGHT {
+        for x in 0..CHUNK_WIDTH {
+            let (fx, fy) = (x as f32, y as f32);
+            let edge = in_vein(fx, fy)
+                && [(-1., 0.), (1., 0.), (0., -1.), (0., 1.)]
+                    .iter()
+                    .any(|(dx, dy)| !in_vein(fx + dx, fy + dy));
+            if edge {
+                outlines.push((x, y));
+            }
+        }
+    }
+    outlines
+}
+
+/// The average color of each block's texture
+pub fn block_colors(assets: &Path) -> HashMap<BlockType, Rgba<u8>> {
+    BlockType::iter()
+        .filter(|block_type| !block_type.image_file_path().is_empty())
+        .filter_map(
+            |block_type| match image::open(assets.join(block_type.image_file_path())) {
+                Ok(texture) => Some((block_type, average_color(&texture.to_rgba8()))),
+                Err(e) => {
+                    warn!("unable to load texture for {:?}, {}", block_type, e);
+                    None
+                }
+            },
+        )
+        .collect()
+}
+
+/// The average color of an image, where see-through pixels count for less
+fn average_color(image: &RgbaImage) -> Rgba<u8> {
+    let mut sums = [0u64; 3];
+    let mut total_alpha = 0u64;
+    for pixel in image.pixels() {
+        let alpha = pixel[3] as u64;
+        for (sum, channel) in sums.iter_mut().zip(pixel.0) {
+            *sum += channel as u64 * alpha;
+        }
+        total_alpha += alpha;
+    }
+
+    if total_alpha == 0 {
+        return Rgba([0, 0, 0, 0]);
+    }
+    let [r, g, b] = sums.map(|sum| (sum / total_alpha) as u8);
+    Rgba([r, g, b, 255])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{generator::FlatGenerator, ChunkGenerator};
+
+    #[test]
+    fn average_color_ignores_clear_pixels() {
+        let mut image = RgbaImage::new(2, 2);
+        image.put_pixel(0, 0, Rgba([200, 100, 0, 255]));
+        image.put_pixel(1, 0, Rgba([0, 100, 200, 255]));
+        image.put_pixel(0, 1, Rgba([255, 255, 255, 0]));
+        assert_eq!(average_color(&image), Rgba([100, 100, 100, 255]));
+
+        // every block with a texture gets a color
+        let colors = block_colors(Path::new(ASSETS_DIR));
+        assert!(colors.contains_key(&BlockType::Sand));
+        assert!(!colors.contains_key(&BlockType::CaveVoid));
+    }
+
+    #[test]
+    fn map_has_a_pixel_per_block() {
+        let mut terrain = Terrain::empty();
+        // leave a gap at chunk_x 0
+        for chunk_x in [-1, 1] {
+            for chunk_number in 0..2 {
+                terrain.insert_chunk(FlatGenerator.chunk(0, chunk_x, chunk_number));
+            }
+        }
+        let colors = block_colors(Path::new(ASSETS_DIR));
+        let overlays = Overlays {
+            chunk_borders: true,
+            ..default()
+        };
+        let map = draw_map(&terrain, &colors, &overlays).unwrap();
+
+        assert_eq!(map.width() as usize, 3 * CHUNK_WIDTH);
+        assert_eq!(map.height() as usize, 2 * CHUNK_HEIGHT);
+        // the top row of the surface is sky, the bottom row is limestone
+        assert_eq!(*map.get_pixel(1, 1), SKY_COLOR);
+        assert_eq!(
+            *map.get_pixel(1, map.height() - 1),
+            colors[&BlockType::Limestone]
+        );
+        // the missing chunk is see-through
+        assert_eq!(map.get_pixel(CHUNK_WIDTH as u32 + 5, 5)[3], 0);
+        // chunk borders
+        assert_eq!(
+            *map.get_pixel(2 * CHUNK_WIDTH as u32, 10),
+            CHUNK_BORDER_COLOR
+        );
+
+        assert!(draw_map(&Terrain::empty(), &colors, &overlays).is_none());
+    }
+}
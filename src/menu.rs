@@ -1,11 +1,13 @@
-use bevy::prelude::*;
+use bevy::{app::AppExit, prelude::*};
 use iyes_loopless::prelude::*;
 
+use crate::network::client::DisconnectReason;
 use crate::states::client::GameState;
 
 //crate::states;
 
 const TEXT_COLOR: Color = Color::rgb(0.9, 0.9, 0.9);
+const DISCONNECT_TEXT_COLOR: Color = Color::rgb(0.95, 0.6, 0.5);
 const BUTTON_BACKGROUND_COLOR: Color = Color::rgb(0.5, 0.5, 0.5);
 const NORMAL_BUTTON: Color = Color::rgb(0.717, 0.255, 0.055);
 const HOVERED_BUTTON: Color = Color::rgb(0.57, 0.20, 0.04);
@@ -58,7 +60,11 @@ fn button_system(
     }
 }
 
-fn main_menu_setup(mut commands: Commands, asset_server: Res<AssetServer>) {
+fn main_menu_setup(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    disconnect_reason: Option<Res<DisconnectReason>>,
+) {
     let font = asset_server.load("fonts/milky_coffee.ttf");
 
     let button_style = Style {
@@ -104,6 +110,24 @@ fn main_menu_setup(mut commands: Commands, asset_server: Res<AssetServer>) {
                 }),
             );
 
+            // Say why we're back from the game, if it didn't end on purpose
+            if let Some(reason) = &disconnect_reason {
+                parent.spawn_bundle(
+                    TextBundle::from_section(
+                        reason.0.clone(),
+                        TextStyle {
+                            font: font.clone(),
+                            font_size: 30.0,
+                            color: DISCONNECT_TEXT_COLOR,
+                        },
+                    )
+                    .with_style(Style {
+                        margin: UiRect::all(Val::Px(20.0)),
+                        ..default()
+                    }),
+                );
+            }
+
             parent
                 .spawn_bundle(ButtonBundle {
                     style: button_style.clone(),
@@ -136,13 +160,14 @@ fn menu_action(
         (Changed<Interaction>, With<Button>),
     >,
     mut commands: Commands,
+    mut exit: EventWriter<AppExit>,
 ) {
     for (interaction, menu_button_action) in &interaction_query {
         if *interaction == Interaction::Clicked {
             match menu_button_action {
                 MenuButtonAction::Quit => {
                     info!("quit button pressed");
-                    std::process::exit(0); // exit immediately
+                    exit.send(AppExit); // exit at the end of this frame
                 }
                 MenuButtonAction::Start => {
                     info!("start button pressed");
@@ -1,19 +1,27 @@
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
 
 use super::*;
 use crate::args::ClientArgs;
-use crate::player::client::{spawn_other_player_at, CameraBoundsBox, LocalPlayer, Player};
-use crate::player::{
-    self, Inventory, PlayerInput, PlayerPosition, CAMERA_BOUNDS_SIZE, PLAYER_AND_BLOCK_SIZE,
+use crate::player::client::{
+    spawn_other_player_at, CameraBoundsBox, LocalPlayer, Player, PredictedInputs,
 };
+use crate::player::{Inventory, JumpState, PlayerIdentity, PlayerInput, PlayerPosition};
+use crate::save;
 use crate::states;
 use crate::states::client::GameState;
-use crate::world::{derender_chunk, render_chunk, RenderedBlock, Terrain, WorldDelta};
+use crate::world::{Block, BlockAtlas, BlockPos, ChunkMeshes, Terrain, WorldDelta, WorldPos};
 use crate::{WIN_H, WIN_W};
-use bevy::prelude::*;
+use bevy::{app::AppExit, prelude::*};
 use iyes_loopless::prelude::*;
 
+/// How long to wait for the server to answer a handshake message before sending it again
+const HANDSHAKE_RESEND_DELAY: Duration = Duration::from_millis(250);
+/// How many of the newest inputs that the server hasn't used go in every packet,
+/// so that losing a packet doesn't lose the input in it
+const RESENT_INPUTS: usize = 4;
+
 /// Should be used as a global resource on the client
 #[derive(Debug)]
 struct Client {
@@ -25,16 +33,40 @@ struct Client {
     current_sequence: u64,
     /// Last sequence we received from the server
     last_received_sequence: u64,
+    /// Who we are, sent to the server when joining
+    identity: PlayerIdentity,
+    /// How far along we are in joining the server
+    handshake: Handshake,
+    /// When the last handshake message was sent, None to send the next one right away
+    last_handshake: Option<Instant>,
     /// Which bodies should be sent in the next outgoing packet
     bodies: Vec<ClientBodyElem>,
+    /// Acks, ordering and resending of bodies to and from the server
+    channel: Channel<ClientBodyElem, ServerBodyElem>,
     /// Debugging pause: drop all packets in and out, stop any processing
     debug_paused: bool,
     /// TODO: replace this with iyes_loopless fixedtimestep
     real_tick_count: u64,
+    /// Splits messages to the server, and puts messages from it back together
+    fragmenter: Fragmenter,
     /// Network buffer
     buffer: [u8; BUFFER_SIZE],
 }
 
+/// Steps of joining a server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Handshake {
+    /// Asking to connect
+    Connecting,
+    /// Sending back the token from the server's challenge
+    Challenged(u64),
+    /// In the game, sending and getting gameplay messages
+    Joined,
+}
+
+/// Why the client left the game, shown on the menu
+pub struct DisconnectReason(pub String);
+
 /// Global resource to contain messages, simplifies data path
 #[derive(Default)]
 struct Messages {
@@ -42,7 +74,11 @@ struct Messages {
 }
 
 impl Client {
-    fn new(server_address: SocketAddr, local_port: u16) -> Result<Self, std::io::Error> {
+    fn new(
+        server_address: SocketAddr,
+        local_port: u16,
+        identity: PlayerIdentity,
+    ) -> Result<Self, std::io::Error> {
         // port 0 means we let the OS decide
         let addr = SocketAddr::from(([0, 0, 0, 0], local_port));
         let sock = UdpSocket::bind(addr)?;
@@ -57,45 +93,91 @@ impl Client {
             server: server_address,
             last_received_sequence: 0,
             current_sequence: 0,
+            identity,
+            handshake: Handshake::Connecting,
+            last_handshake: None,
             bodies: Vec::with_capacity(DEFAULT_BODIES_VEC_CAPACITY),
+            channel: Channel::default(),
             debug_paused: false,
             real_tick_count: 0,
+            fragmenter: Fragmenter::default(),
             buffer: [0u8; BUFFER_SIZE],
         })
     }
 
     /// Send a message to the server
-    fn send_message(&mut self, message: ClientToServer) -> Result<(), SendError> {
-        send_message(&self.socket, self.server, message, &mut self.buffer)?;
+    fn send_message(&mut self, message: ClientMessage) -> Result<(), SendError> {
+        send_message(
+            &self.socket,
+            self.server,
+            message,
+            &mut self.fragmenter,
+            &mut self.buffer,
+        )?;
         Ok(())
     }
 
     /// Non-blocking way to get one message from the socket
-    fn get_one_message(&mut self) -> Result<ServerToClient, ReceiveError> {
+    fn get_one_message(&mut self) -> Result<ServerMessage, ReceiveError> {
         // read from socket
-        let (_size, sender_addr) = self.socket.recv_from(&mut self.buffer).map_err(|e| match e
-            .kind()
-        {
-            std::io::ErrorKind::WouldBlock => ReceiveError::NoMessage,
-            _ => ReceiveError::IoError(e),
-        })?;
+        let (size, sender_addr) =
+            self.socket
+                .recv_from(&mut self.buffer)
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::WouldBlock => ReceiveError::NoMessage,
+                    _ => ReceiveError::IoError(e),
+                })?;
 
         // check if it's actually from the server
         if sender_addr != self.server {
             return Err(ReceiveError::UnknownSender);
         }
 
-        // decode message
-        let (message, _size) = bincode::decode_from_slice(&self.buffer, BINCODE_CONFIG)
-            .map_err(ReceiveError::DecodeError)?;
-
-        Ok(message)
+        // decode message, once all of its fragments are here
+        self.fragmenter
+            .reassemble(sender_addr, &self.buffer[..size], Instant::now())
     }
 
     /// Push a body that will be sent to the server
     fn enqueue_body(&mut self, body: ClientBodyElem) {
         self.bodies.push(body);
     }
+
+    /// Send the next step of the handshake, again every so often in case it gets lost
+    fn send_handshake(&mut self) {
+        let now = Instant::now();
+        if self
+            .last_handshake
+            .is_some_and(|sent| now.duration_since(sent) < HANDSHAKE_RESEND_DELAY)
+        {
+            return;
+        }
+
+        let message = match self.handshake {
+            Handshake::Connecting => ClientMessage::Connect {
+                protocol_version: PROTOCOL_VERSION,
+            },
+            Handshake::Challenged(token) => ClientMessage::ChallengeResponse {
+                token,
+                identity: self.identity.clone(),
+            },
+            Handshake::Joined => return,
+        };
+        self.last_handshake = Some(now);
+        if let Err(e) = self.send_message(message) {
+            error!("failed to send message to server: {:?}", e);
+        }
+    }
+
+    /// Tell the server that we're leaving, a few times over since we won't be around to resend it
+    fn disconnect(&mut self, reason: LeaveReason) {
+        info!("disconnecting from server: {}", reason);
+        for _ in 0..DISCONNECT_COPIES {
+            if let Err(e) = self.send_message(ClientMessage::Disconnect(reason)) {
+                error!("failed to send message to server: {:?}", e);
+            }
+        }
+    }
 }
 
 pub struct ClientPlugin {
@@ -114,6 +196,9 @@ impl Plugin for ClientPlugin {
         // exit system
         app.add_exit_system(states::client::GameState::InGame, destroy_client);
 
+        // runs after everything else, so it sees the game closing on the same frame
+        app.add_system_to_stage(CoreStage::Last, disconnect_on_exit);
+
         // add timestep
         app.add_fixed_timestep(
             std::time::Duration::from_secs_f64(1. / NETWORK_TICK_HZ as f64),
@@ -151,17 +236,19 @@ impl Plugin for ClientPlugin {
         .add_fixed_timestep_system(
             NETWORK_TICK_LABEL,
             0,
-            queue_inputs
+            handle_messages
                 .run_in_state(states::client::GameState::InGame)
-                .label("queue_inputs")
+                .label("handle_messages")
                 .after("fetch_messages"),
         )
+        // after the server's position for our player, so the new input is predicted on top of it
         .add_fixed_timestep_system(
             NETWORK_TICK_LABEL,
             0,
-            handle_messages
+            queue_inputs
                 .run_in_state(states::client::GameState::InGame)
-                .label("handle_messages"),
+                .label("queue_inputs")
+                .after("handle_messages"),
         )
         .add_fixed_timestep_system(
             NETWORK_TICK_LABEL,
@@ -169,7 +256,7 @@ impl Plugin for ClientPlugin {
             send_bodies
                 .run_in_state(states::client::GameState::InGame)
                 .label("send_bodies")
-                .after("handle_messages"),
+                .after("queue_inputs"),
         )
         .add_fixed_timestep_system(
             NETWORK_TICK_LABEL,
@@ -183,22 +270,42 @@ impl Plugin for ClientPlugin {
 }
 
 fn create_client(mut commands: Commands, args: Res<ClientArgs>) {
+    let identity = save::load_or_create_identity(&args.identity_file, args.name.as_deref());
+    info!("playing as {}", identity.name);
+
     let client = match Client::new(
         SocketAddr::from((args.server_ip, args.server_port)),
         args.client_port,
+        identity,
     ) {
         Ok(s) => s,
         Err(e) => panic!("Unable to create client: {}", e),
     };
     info!("client created");
     commands.insert_resource(client);
+    commands.remove_resource::<DisconnectReason>();
 }
 
-fn destroy_client(mut commands: Commands) {
+fn destroy_client(mut commands: Commands, mut client: ResMut<Client>) {
     info!("destroying client");
+    if client.handshake == Handshake::Joined {
+        client.disconnect(LeaveReason::LeftGame);
+    }
     commands.remove_resource::<Client>();
 }
 
+/// Tell the server that we're leaving when the game closes
+fn disconnect_on_exit(exit: EventReader<AppExit>, client: Option<ResMut<Client>>) {
+    if exit.is_empty() {
+        return;
+    }
+    if let Some(mut client) = client {
+        if client.handshake == Handshake::Joined {
+            client.disconnect(LeaveReason::Quit);
+        }
+    }
+}
+
 fn increase_tick(mut client: ResMut<Client>) {
     // don't increment when paused
     if !client.debug_paused {
@@ -243,7 +350,10 @@ fn p_queues_ping(mut client: ResMut<Client>, input: Res<Input<KeyCode>>) {
 
     // only allow one ping per network cycle
     if num_ping_bodies == 0 {
-        info!("client queueing a ping");
+        info!(
+            "client queueing a ping, round trip time is {:?}",
+            client.channel.rtt()
+        );
         client.enqueue_body(ClientBodyElem::Ping);
     }
 }
@@ -254,7 +364,16 @@ fn queue_inputs(
     bevy_input: Res<Input<KeyCode>>,
     mouse: Res<Input<MouseButton>>,
     mut windows: ResMut<Windows>,
-    mut query: Query<(&mut PlayerPosition, &mut CameraBoundsBox), With<LocalPlayer>>,
+    mut query: Query<
+        (
+            &mut PlayerPosition,
+            &mut JumpState,
+            &mut PredictedInputs,
+            &CameraBoundsBox,
+        ),
+        With<LocalPlayer>,
+    >,
+    terrain: Res<Terrain>,
 ) {
     // TODO: remove
     if client.debug_paused {
@@ -263,8 +382,7 @@ fn queue_inputs(
 
     //Code to calculate the block x and y to mine based on the mouse x and y from bevy
 
-    let mut block_x_from_mouse = 0;
-    let mut block_y_from_mouse = 0;
+    let mut block_from_mouse = BlockPos::default();
 
     let window = windows.get_primary_mut();
 
@@ -273,7 +391,7 @@ fn queue_inputs(
     }
 
     let win = window.unwrap();
-    let (player_position, camera_box) = query.single();
+    let (mut player_position, mut jump, mut predicted, camera_box) = query.single_mut();
     let ms = win.cursor_position();
 
     if !ms.is_none() {
@@ -287,9 +405,8 @@ fn queue_inputs(
         let game_x = camera_box.center_coord.x + dist_x;
         let game_y = camera_box.center_coord.y + dist_y;
 
-        //calculate block coords from bevy coords
-        block_x_from_mouse = (game_x / PLAYER_AND_BLOCK_SIZE).round() as usize;
-        block_y_from_mouse = (-game_y / PLAYER_AND_BLOCK_SIZE).round() as usize;
+        //calculate block coords from bevy coords, which can be above the surface
+        block_from_mouse = WorldPos::from_render(Vec2::new(game_x, game_y)).block();
     }
 
     let mut input = PlayerInput {
@@ -297,23 +414,31 @@ fn queue_inputs(
         right: bevy_input.pressed(KeyCode::D),
         jump: bevy_input.pressed(KeyCode::Space),
         mine: mouse.pressed(MouseButton::Left),
-        block_x: block_x_from_mouse,
-        block_y: block_y_from_mouse,
+        block: block_from_mouse,
+        sequence: 0, // numbered when the player moves with it below
     };
 
     // TODO: remove
     // DEBUG: make G destroy the block below the player
     if bevy_input.pressed(KeyCode::G) {
         input.mine = true;
-        input.block_x = player_position.x as usize;
-        input.block_y = (-player_position.y) as usize + 1;
+        input.block = player_position.world_pos().block().offset(0, 1);
     }
 
-    client.enqueue_body(ClientBodyElem::Input(input));
+    // move right away instead of waiting for the server to say where we went
+    predicted.predict(&mut input, &mut player_position, &mut jump, &terrain);
+
+    for input in predicted.newest(RESENT_INPUTS) {
+        client.enqueue_body(ClientBodyElem::Input(input.clone()));
+    }
 }
 
 /// Get and handle all messages from server
-fn fetch_messages(mut client: ResMut<Client>, mut messages: ResMut<Messages>) {
+fn fetch_messages(
+    mut client: ResMut<Client>,
+    mut messages: ResMut<Messages>,
+    mut commands: Commands,
+) {
     if client.debug_paused {
         // eat all the messages
         let mut void = [0u8; 0];
@@ -323,21 +448,55 @@ fn fetch_messages(mut client: ResMut<Client>, mut messages: ResMut<Messages>) {
 
     loop {
         match client.get_one_message() {
-            Ok(message) => {
+            Ok(ServerMessage::Challenge(token)) => {
+                if client.handshake == Handshake::Connecting {
+                    // answer right away
+                    client.handshake = Handshake::Challenged(token);
+                    client.last_handshake = None;
+                }
+            }
+            Ok(ServerMessage::Accept) => {
+                if client.handshake != Handshake::Joined {
+                    info!("joined the server");
+                    client.handshake = Handshake::Joined;
+                }
+            }
+            Ok(ServerMessage::Reject(reason)) => {
+                error!("server turned us away: {}", reason);
+                commands.insert_resource(DisconnectReason(reason.to_string()));
+                commands.insert_resource(NextState(GameState::Menu));
+                break;
+            }
+            Ok(ServerMessage::Disconnect(reason)) => {
+                warn!("server disconnected us: {}", reason);
+                // the server already forgot about us, so there's no one to say goodbye to
+                client.handshake = Handshake::Connecting;
+                commands.insert_resource(DisconnectReason(format!("Disconnected: {}", reason)));
+                commands.insert_resource(NextState(GameState::Menu));
+                break;
+            }
+            Ok(ServerMessage::Game(message)) => {
                 // info!(
                 //     "client received message with {} bodies",
                 //     message.bodies.len()
                 // );
-                // only process newer messages, ignore old ones that arrive out of orders
-                if message.header.sequence > client.last_received_sequence {
-                    // wipe bodies from old packets, since the server is sending deltas anyway
-                    messages.messages.clear();
-
-                    // buffer all bodies sent from the server in this packet
-                    for body in message.bodies {
-                        messages.messages.push_back(body);
-                    }
+                // the server only sends these to clients that joined, even if the accept got lost
+                client.handshake = Handshake::Joined;
+
+                // the channel drops copies, and unreliable bodies from old packets,
+                // but reliable bodies are kept even when their packet arrives late
+                let bodies = client.channel.receive(message.bodies, Instant::now());
+                let newer = message.header.sequence > client.last_received_sequence;
+                if newer {
+                    // wipe unreliable bodies from old packets, since the server is sending deltas anyway
+                    messages
+                        .messages
+                        .retain(|body| body.reliability() != Reliability::Unreliable);
+                }
+                messages.messages.extend(bodies);
 
+                // only process newer messages, ignore old ones that arrive out of orders
+                if newer {
                     // if we are desync'd
                     if client.current_sequence != message.header.sequence {
                         let ticks_ahead =
@@ -366,6 +525,9 @@ fn fetch_messages(mut client: ResMut<Client>, mut messages: ResMut<Messages>) {
                 // no more messages at the moment
                 break;
             }
+            Err(ReceiveError::IncompleteMessage) => {
+                // wait for the rest of the fragments
+            }
             Err(e) => {
                 error!("client receive error: {:?}", e);
             }
@@ -383,9 +545,20 @@ fn handle_messages(
         (Entity, &mut PlayerPosition, &ClientAddress),
         (With<Player>, Without<LocalPlayer>),
     >,
-    mut local_player: Query<(&mut PlayerPosition, &mut Sprite, &mut Inventory), With<LocalPlayer>>,
-    old_blocks: Query<Entity, With<RenderedBlock>>,
+    mut local_player: Query<
+        (
+            &mut PlayerPosition,
+            &mut JumpState,
+            &mut PredictedInputs,
+            &mut Sprite,
+            &mut Inventory,
+        ),
+        With<LocalPlayer>,
+    >,
     assets: Res<AssetServer>,
+    mut chunk_meshes: ResMut<ChunkMeshes>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    atlas: Res<BlockAtlas>,
 ) {
     // new players after this frame, so we can delete old players
     let mut all_players = HashSet::new();
@@ -403,48 +576,62 @@ fn handle_messages(
                             info!(
                                 "got new completely new chunks!: {:?}",
                                 new_terrain
-                                    .chunks
-                                    .iter()
-                                    .map(|c| c.chunk_number)
+                                    .chunks()
+                                    .map(|c| (c.chunk_x, c.chunk_number))
                                     .collect::<Vec<_>>()
                             );
 
-                            // de-render and destroy old chunks
-                            for mut chunk in &mut terrain.chunks {
-                                derender_chunk(&mut commands, &mut chunk)
-                            }
-
-                            // overwrite the terrain
-                            *terrain = new_terrain;
+                            // add the new chunks to what we already know about the world,
+                            // chunks that aren't in them are kept for when they are on screen again
+                            for chunk in new_terrain.into_chunks() {
+                                let (chunk_x, chunk_number) = (chunk.chunk_x, chunk.chunk_number);
+                                if terrain.find_chunk(chunk_x, chunk_number) == Some(&chunk) {
+                                    continue;
+                                }
 
-                            // render new chunks
-                            for mut chunk in &mut terrain.chunks {
-                                render_chunk(&mut commands, &assets, &mut chunk);
+                                // chunks on screen get drawn again,
+                                // the rest get drawn when they come on screen
+                                if chunk_meshes.is_rendered(chunk_x, chunk_number) {
+                                    chunk_meshes.render(&mut commands, &mut meshes, &atlas, &chunk);
+                                }
+                                terrain.insert_chunk(chunk);
                             }
                         }
                         WorldDelta::BlockDelete(delete) => {
                             // info!("got block deletion: {:?}", delete);
 
-                            for chunk in &mut terrain.chunks {
-                                if chunk.chunk_number == delete.chunk_number {
-                                    let maybe_block = &mut chunk.blocks[delete.y][delete.x];
-                                    match maybe_block {
-                                        Some(block) => {
-                                            // un-render block entity if it exists
-                                            if let Some(e) = block.entity {
-                                                // info!("despawning mined block");
-                                                commands.entity(e).despawn();
-                                            }
-                                            // delete the block
-                                            *maybe_block = None;
-                                        }
-                                        None => {
-                                            // block already deleted
-                                            // warn!("client got BlockDelete but block already doesn't exist!");
-                                        }
-                                    }
-                                }
-                            }
+                            // a block that is already deleted doesn't change anything
+                            replace_block(
+                                &chunk_meshes,
+                                &mut meshes,
+                                &atlas,
+                                &mut terrain,
+                                (delete.chunk_x, delete.chunk_number),
+                                (delete.x, delete.y),
+                                None,
+                            );
+                        }
+                        WorldDelta::LiquidChange(change) => {
+                            replace_block(
+                                &chunk_meshes,
+                                &mut meshes,
+                                &atlas,
+                                &mut terrain,
+                                (change.chunk_x, change.chunk_number),
+                                (change.x, change.y),
+                                change.block_type.map(Block::new),
+                            );
+                        }
+                        WorldDelta::BlockPlace(place) => {
+                            replace_block(
+                                &chunk_meshes,
+                                &mut meshes,
+                                &atlas,
+                                &mut terrain,
+                                (place.chunk_x, place.chunk_number),
+                                (place.x, place.y),
+                                Some(place.block),
+                            );
                         }
                     }
 
@@ -460,10 +647,19 @@ fn handle_messages(
                     //     "new local player position is: ({}, {})",
                     //     info.position.x, info.position.y
                     // );
-                    let (mut local_pos, mut local_sprite, _) = local_player.single_mut();
+                    let (mut local_pos, mut jump, mut predicted, mut local_sprite, _) =
+                        local_player.single_mut();
 
                     // update local player game position, will be rendered in another system
-                    *local_pos = info.position.clone();
+                    // the server hasn't seen our newest inputs yet, so move with them again
+                    predicted.reconcile(
+                        info.last_input,
+                        &info.position,
+                        &info.jump,
+                        &mut local_pos,
+                        &mut jump,
+                        &terrain,
+                    );
 
                     // recolor local player sprite
                     local_sprite.color = info.addr.color();
@@ -502,7 +698,7 @@ fn handle_messages(
                 //     inv
                 // )
                 // overwrite our inventory with new one
-                let (_, _, mut our_inv) = local_player.single_mut();
+                let (_, _, _, _, mut our_inv) = local_player.single_mut();
                 *our_inv = new_inv;
             }
         }
@@ -536,19 +732,49 @@ fn handle_messages(
     }
 }
 
+/// Swap out a block in a chunk for a different one or a different state of it, or none,
+/// and patch it into the chunk's mesh
+fn replace_block(
+    chunk_meshes: &ChunkMeshes,
+    meshes: &mut Assets<Mesh>,
+    atlas: &BlockAtlas,
+    terrain: &mut Terrain,
+    (chunk_x, chunk_number): (i64, u64),
+    (x, y): (usize, usize),
+    block: Option<Block>,
+) {
+    if let Some(chunk) = terrain.find_chunk_mut(chunk_x, chunk_number) {
+        let maybe_block = &mut chunk.blocks[y][x];
+        if *maybe_block == block {
+            return;
+        }
+
+        *maybe_block = block;
+        chunk_meshes.set_block(meshes, atlas, (chunk_x, chunk_number), (x, y), block);
+    }
+}
+
 fn send_bodies(mut client: ResMut<Client>) {
     if client.debug_paused {
         client.bodies.clear();
         return;
     }
 
-    let message = ClientToServer {
+    // the server ignores gameplay bodies until it lets us in
+    if client.handshake != Handshake::Joined {
+        client.bodies.clear();
+        client.send_handshake();
+        return;
+    }
+
+    let bodies = std::mem::take(&mut client.bodies);
+    let message = ClientMessage::Game(ClientToServer {
         header: ClientHeader {
             current_sequence: client.current_sequence,
             last_received_sequence: client.last_received_sequence,
         },
-        bodies: client.bodies.clone(),
-    };
+        bodies: client.channel.send(bodies, Instant::now()),
+    });
     let success_str = format!("client sent message to server: {:?}", message);
     match client.send_message(message) {
         Ok(_) => {
@@ -556,9 +782,6 @@ fn send_bodies(mut client: ResMut<Client>) {
         }
         Err(e) => error!("failed to send message to server: {:?}", e),
     }
-
-    // client doesn't care if message arrives -- it never retransmits bodies
-    client.bodies.clear();
 }
 
 // TODO: client-side timeout!
@@ -578,6 +801,14 @@ fn client_timeout(client: ResMut<Client>, commands: Commands) {
 fn on_timeout(mut client: ResMut<Client>, mut commands: Commands) {
     info!("Clearing bodies");
     client.bodies.clear();
+    commands.insert_resource(DisconnectReason(
+        if client.handshake == Handshake::Joined {
+            "Lost connection to the server"
+        } else {
+            "Could not reach the server"
+        }
+        .to_string(),
+    ));
     // go back to menu
     commands.insert_resource(NextState(GameState::Menu));
 }
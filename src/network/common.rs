@@ -1,13 +1,14 @@
 use bevy::prelude::*;
 use bincode::{Decode, Encode};
 use std::{
-    collections::hash_map::DefaultHasher,
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet},
     hash::{Hash, Hasher},
     net::{SocketAddr, UdpSocket},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    player::{Inventory, PlayerInput, PlayerPosition},
+    player::{Inventory, JumpState, PlayerIdentity, PlayerInput, PlayerPosition},
     world::{BlockType, Terrain, WorldDelta},
 };
 
@@ -20,6 +21,8 @@ pub const BINCODE_CONFIG: bincode::config::Configuration = bincode::config::stan
 /// placeholders
 /// TODO: remove whenever command line arguments can be parsed
 pub const DEFAULT_SERVER_PORT: u16 = 8888u16;
+/// how many players can be connected to a server at once, unless told otherwise
+pub const DEFAULT_MAX_PLAYERS: usize = 8;
 /// the default server IP that the client looks for
 pub const DEFAULT_CLIENT_SERVER_IP: [u8; 4] = [127, 0, 0, 1];
 
@@ -27,12 +30,25 @@ pub const DEFAULT_CLIENT_SERVER_IP: [u8; 4] = [127, 0, 0, 1];
 /// max packet size in UDP is 2^16 bytes
 pub const BUFFER_SIZE: usize = (2 as usize).pow(16);
 
+/// How many bytes of a message go in each fragment, so that a fragment fits in a datagram
+/// that won't get split up on the way (1500 bytes is the usual MTU, minus the IP and UDP headers)
+pub const FRAGMENT_SIZE: usize = 1200;
+/// Messages can't be split into more fragments than this, so they can't be bigger than about 1.2 MB
+const MAX_FRAGMENTS: usize = 1024;
+/// How long to wait for the rest of a message's fragments before giving up on it
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(1);
+/// How many messages from one sender can be waiting for the rest of their fragments at once
+const MAX_INCOMPLETE_MESSAGES: usize = 16;
+
 /// Default size of allocated bodies vec, larger numbers may help reduce reallocation
 pub const DEFAULT_BODIES_VEC_CAPACITY: usize = 10;
 
 /// How many frames does a client have to not respond for before the server assumes it's dead
 pub const FRAME_DIFFERENCE_BEFORE_DISCONNECT: u64 = NETWORK_TICK_HZ * 2;
 
+/// How many copies of a disconnect message get sent, since there is no chance to send it again
+pub const DISCONNECT_COPIES: usize = 3;
+
 /// how many times per second will the network tick occur
 pub const NETWORK_TICK_HZ: u64 = 60;
 
@@ -48,11 +64,30 @@ pub const GAME_TICK_LABEL: &str = "GAME_TICK";
 /// Marker trait for network structs
 pub trait NetworkMessage: Encode + Decode {}
 
+/// Version of the messages that clients and servers send each other
+/// Bump this whenever any of them change, so that old clients get turned away instead of
+/// sending the server garbage
+pub const PROTOCOL_VERSION: u32 = 3;
+
+/// How many packets before the newest one received get acked in every packet, as bits
+const ACK_BITS: u64 = 32;
+/// How many sent packets to remember, so that their reliable bodies are known when they get acked
+/// Bodies in packets that are forgotten get sent again when they are due anyway
+const TRACKED_PACKETS: u64 = 256;
+/// Round trip time to assume until the first packet gets acked
+const DEFAULT_RTT: Duration = Duration::from_millis(100);
+/// How much of each new round trip time goes into the average
+const RTT_SMOOTHING: f32 = 0.1;
+/// How many round trip times to wait for an ack before sending a reliable body again
+const RESEND_RTT_FACTOR: f32 = 1.5;
+/// Reliable bodies don't get sent again more often than this, even on a fast connection
+const MIN_RESEND_DELAY: Duration = Duration::from_millis(50);
+
 /// Message from the server to a client
 #[derive(Encode, Decode, Debug)]
 pub struct ServerToClient {
     pub header: ServerHeader,
-    pub bodies: Vec<ServerBodyElem>,
+    pub bodies: ChannelPacket<ServerBodyElem>,
 }
 
 /// Header for ServerToClient message
@@ -77,20 +112,82 @@ pub enum ServerBodyElem {
     Inventory(Inventory),
 }
 
+impl ChannelBody for ServerBodyElem {
+    fn reliability(&self) -> Reliability {
+        match self {
+            ServerBodyElem::Pong(_) => Reliability::ReliableUnordered,
+            // world deltas are always from the last terrain the client acked, so only the newest matter
+            ServerBodyElem::WorldDeltas(_) | ServerBodyElem::PlayerInfo(_) => {
+                Reliability::Unreliable
+            }
+            // only sent when it changes, and an older one can't replace a newer one
+            ServerBodyElem::Inventory(_) => Reliability::ReliableOrdered,
+        }
+    }
+}
+
 /// Contains information about a single player
 #[derive(Encode, Decode, Debug, Clone)]
 pub struct SingleNetPlayerInfo {
     pub addr: ClientAddress,
-    pub position: PlayerPosition, // TODO: put inputs here if we want client-side prediction
+    pub position: PlayerPosition,
+    pub jump: JumpState,
+    /// Sequence number of the input the server last moved the player with, each one once in order,
+    /// so the client can move its own player again with the ones after it
+    pub last_input: u64,
+}
+
+/// Everything that the server sends to a client
+#[derive(Encode, Decode, Debug)]
+pub enum ServerMessage {
+    /// The client has to send this token back to join, to show that it really is at its address
+    Challenge(u64),
+    /// The client has joined the game
+    Accept,
+    /// The client can't join the game
+    Reject(RejectReason),
+    /// Gameplay, only sent to clients that have joined
+    Game(ServerToClient),
+    /// The server is done with the client, so it should stop sending
+    Disconnect(LeaveReason),
 }
 
-impl NetworkMessage for ServerToClient {}
+impl NetworkMessage for ServerMessage {}
+
+/// Why the server turned a client away
+#[derive(Encode, Decode, Debug, Clone, PartialEq, Eq)]
+pub enum RejectReason {
+    /// The client and server speak different versions of the protocol
+    WrongVersion { server: u32, client: u32 },
+    /// Too many players are already connected
+    ServerFull { max_players: usize },
+    /// Someone with the same identity is already playing
+    AlreadyConnected,
+}
+
+impl std::fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RejectReason::WrongVersion { server, client } => write!(
+                f,
+                "The server is on version {} of the game, but the client is on version {}",
+                server, client
+            ),
+            RejectReason::ServerFull { max_players } => {
+                write!(f, "The server is full ({} players)", max_players)
+            }
+            RejectReason::AlreadyConnected => {
+                write!(f, "Someone is already playing as you on the server")
+            }
+        }
+    }
+}
 
 /// Message from a client to the server
 #[derive(Encode, Decode, Debug)]
 pub struct ClientToServer {
     pub header: ClientHeader,
-    pub bodies: Vec<ClientBodyElem>,
+    pub bodies: ChannelPacket<ClientBodyElem>,
 }
 
 /// Header for ClientToServer message
@@ -113,12 +210,525 @@ pub enum ClientBodyElem {
     Input(PlayerInput),
 }
 
-impl NetworkMessage for ClientToServer {}
+impl ChannelBody for ClientBodyElem {
+    fn reliability(&self) -> Reliability {
+        match self {
+            ClientBodyElem::Ping => Reliability::ReliableUnordered,
+            // sent every tick, so only the newest matters
+            ClientBodyElem::Input(_) => Reliability::Unreliable,
+        }
+    }
+}
+
+/// Everything that a client sends to the server
+#[derive(Encode, Decode, Debug)]
+pub enum ClientMessage {
+    /// Asks to join the game
+    Connect { protocol_version: u32 },
+    /// Sends back the token from the server's challenge, and says who is joining
+    ChallengeResponse {
+        token: u64,
+        identity: PlayerIdentity,
+    },
+    /// Gameplay, ignored until the client has joined
+    Game(ClientToServer),
+    /// The client is leaving, so its player can go right away
+    Disconnect(LeaveReason),
+}
+
+/// Why one side is closing the connection
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LeaveReason {
+    /// The player closed the game
+    Quit,
+    /// The player went back to the menu
+    LeftGame,
+    /// The server is going down
+    ShuttingDown,
+}
+
+impl std::fmt::Display for LeaveReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LeaveReason::Quit => write!(f, "quit the game"),
+            LeaveReason::LeftGame => write!(f, "left the game"),
+            LeaveReason::ShuttingDown => write!(f, "the server is shutting down"),
+        }
+    }
+}
+
+impl NetworkMessage for ClientMessage {}
+
+/// How a body gets to the other side of a Channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reliability {
+    /// Sent once, and dropped if a newer packet has already arrived, for state that is sent every tick
+    Unreliable,
+    /// Sent again until it is acked, and handed over as soon as it arrives
+    ReliableUnordered,
+    /// Sent again until it is acked, and handed over in the order it was sent
+    ReliableOrdered,
+}
+
+/// A body that can be sent over a Channel
+pub trait ChannelBody: Clone {
+    fn reliability(&self) -> Reliability;
+}
+
+/// Which reliable body this is, counted separately for ordered and unordered bodies
+#[derive(Encode, Decode, Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MessageId {
+    pub ordered: bool,
+    pub id: u64,
+}
+
+/// A reliable body, with its id so that the other side can put it in order and drop copies of it
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ReliableBody<B> {
+    pub id: MessageId,
+    pub body: B,
+}
+
+/// The bodies of a packet, and what the channel needs to deliver them
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct ChannelPacket<B> {
+    /// Random number for the sending channel, so that the receiver can tell when it was replaced
+    pub session: u32,
+    /// Id of this packet, counting up from 1
+    pub packet: u64,
+    /// Id of the newest packet received from the other side, 0 for none
+    pub ack: u64,
+    /// Bit n is set if packet ack - 1 - n was received too
+    pub ack_bits: u32,
+    /// Every ordered body with a lower id has been acked, so the receiver doesn't wait for them
+    pub ordered_base: u64,
+    /// Every unordered body with a lower id has been acked, so the receiver can forget about them
+    pub unordered_base: u64,
+    /// Bodies that are only sent in this packet
+    pub unreliable: Vec<B>,
+    /// Bodies that are sent again until a packet with them is acked
+    pub reliable: Vec<ReliableBody<B>>,
+}
+
+/// A reliable body that was sent, but not acked yet
+#[derive(Debug)]
+struct Unacked<B> {
+    body: B,
+    /// When it was last sent, None if it hasn't been sent yet
+    sent: Option<Instant>,
+}
+
+/// A packet that was sent, but not acked yet
+#[derive(Debug)]
+struct SentPacket {
+    sent: Instant,
+    messages: Vec<MessageId>,
+}
+
+/// Reliability on top of UDP for one side of a connection: sends bodies of type S,
+/// and receives bodies of type R
+/// Every packet acks the newest packets received from the other side, and reliable bodies
+/// are sent again after a round trip time or so until a packet with them is acked
+#[derive(Debug)]
+pub struct Channel<S, R> {
+    session: u32,
+    /// The other side's session, None until it sends a packet
+    remote_session: Option<u32>,
+
+    /// Id of the last packet sent
+    last_packet: u64,
+    sent_packets: HashMap<u64, SentPacket>,
+    unacked: BTreeMap<MessageId, Unacked<S>>,
+    next_ordered: u64,
+    next_unordered: u64,
+    /// Average round trip time, None until the first packet gets acked
+    rtt: Option<Duration>,
+
+    /// Id of the newest packet received, 0 for none
+    latest_received: u64,
+    /// Bit n is set if packet latest_received - 1 - n was received too
+    received_bits: u32,
+    /// Id of the next ordered body to hand over
+    expected_ordered: u64,
+    /// Ordered bodies that arrived before the ones in front of them
+    held: BTreeMap<u64, R>,
+    /// Unordered bodies below this id have all been handed over
+    unordered_floor: u64,
+    /// Unordered bodies from unordered_floor up that have been handed over
+    unordered_received: HashSet<u64>,
+}
+
+impl<S, R> Default for Channel<S, R> {
+    fn default() -> Self {
+        Self {
+            session: rand::random(),
+            remote_session: None,
+            last_packet: 0,
+            sent_packets: HashMap::new(),
+            unacked: BTreeMap::new(),
+            next_ordered: 0,
+            next_unordered: 0,
+            rtt: None,
+            latest_received: 0,
+            received_bits: 0,
+            expected_ordered: 0,
+            held: BTreeMap::new(),
+            unordered_floor: 0,
+            unordered_received: HashSet::new(),
+        }
+    }
+}
+
+impl<S: ChannelBody, R: ChannelBody> Channel<S, R> {
+    /// Average round trip time of the connection
+    pub fn rtt(&self) -> Duration {
+        self.rtt.unwrap_or(DEFAULT_RTT)
+    }
+
+    /// Build the next packet to send, with the new bodies and any reliable bodies that are due
+    /// to be sent again
+    pub fn send(&mut self, bodies: Vec<S>, now: Instant) -> ChannelPacket<S> {
+        let mut unreliable = Vec::new();
+        for body in bodies {
+            let id = match body.reliability() {
+                Reliability::Unreliable => {
+                    unreliable.push(body);
+                    continue;
+                }
+                Reliability::ReliableOrdered => {
+                    self.next_ordered += 1;
+                    MessageId {
+                        ordered: true,
+                        id: self.next_ordered - 1,
+                    }
+                }
+                Reliability::ReliableUnordered => {
+                    self.next_unordered += 1;
+                    MessageId {
+                        ordered: false,
+                        id: self.next_unordered - 1,
+                    }
+                }
+            };
+            self.unacked.insert(id, Unacked { body, sent: None });
+        }
+
+        // new bodies, and old ones that have been waiting for an ack for too long
+        let resend_delay = self.rtt().mul_f32(RESEND_RTT_FACTOR).max(MIN_RESEND_DELAY);
+        let mut reliable = Vec::new();
+        for (id, message) in self.unacked.iter_mut() {
+            if message
+                .sent
+                .is_some_and(|sent| now.duration_since(sent) < resend_delay)
+            {
+                continue;
+            }
+            message.sent = Some(now);
+            reliable.push(ReliableBody {
+                id: *id,
+                body: message.body.clone(),
+            });
+        }
+
+        self.last_packet += 1;
+        self.sent_packets.insert(
+            self.last_packet,
+            SentPacket {
+                sent: now,
+                messages: reliable.iter().map(|message| message.id).collect(),
+            },
+        );
+        let last_packet = self.last_packet;
+        self.sent_packets
+            .retain(|packet, _| packet + TRACKED_PACKETS > last_packet);
+
+        let lowest_unacked = |ordered: bool| {
+            self.unacked
+                .keys()
+                .find(|id| id.ordered == ordered)
+                .map(|id| id.id)
+        };
+        ChannelPacket {
+            session: self.session,
+            packet: self.last_packet,
+            ack: self.latest_received,
+            ack_bits: self.received_bits,
+            ordered_base: lowest_unacked(true).unwrap_or(self.next_ordered),
+            unordered_base: lowest_unacked(false).unwrap_or(self.next_unordered),
+            unreliable,
+            reliable,
+        }
+    }
+
+    /// Take in a packet from the other side, and hand over the bodies that are ready:
+    /// reliable bodies that haven't been handed over before (ordered ones once all the ones
+    /// in front of them have been), then unreliable ones unless a newer packet already arrived
+    pub fn receive(&mut self, packet: ChannelPacket<R>, now: Instant) -> Vec<R> {
+        if self.remote_session != Some(packet.session) {
+            if self.remote_session.is_some() {
+                info!("other side of the channel restarted, starting over");
+            }
+            self.remote_session = Some(packet.session);
+            self.latest_received = 0;
+            self.received_bits = 0;
+            self.expected_ordered = 0;
+            self.held.clear();
+            self.unordered_floor = 0;
+            self.unordered_received.clear();
+        }
+
+        self.process_acks(packet.ack, packet.ack_bits, now);
+
+        let newest = match self.record_received(packet.packet) {
+            Some(newest) => newest,
+            // a copy of a packet that already arrived
+            None => return Vec::new(),
+        };
+
+        let mut bodies =
+            self.receive_reliable(packet.reliable, packet.ordered_base, packet.unordered_base);
+        if newest {
+            bodies.extend(packet.unreliable);
+        }
+        bodies
+    }
+
+    /// Forget the reliable bodies in packets that the other side acked
+    fn process_acks(&mut self, ack: u64, ack_bits: u32, now: Instant) {
+        if ack == 0 {
+            return;
+        }
+        let acked = std::iter::once(ack).chain(
+            (0..ACK_BITS)
+                .filter(|bit| ack_bits & (1 << bit) != 0)
+                .filter_map(|bit| ack.checked_sub(bit + 1)),
+        );
+
+        for packet in acked {
+            if let Some(sent) = self.sent_packets.remove(&packet) {
+                let sample = now.duration_since(sent.sent);
+                self.rtt = Some(match self.rtt {
+                    Some(rtt) => rtt.mul_f32(1. - RTT_SMOOTHING) + sample.mul_f32(RTT_SMOOTHING),
+                    None => sample,
+                });
+                for id in sent.messages {
+                    self.unacked.remove(&id);
+                }
+            }
+        }
+    }
+
+    /// Remember that a packet arrived, so it gets acked
+    /// Returns Some(true) if it is the newest packet so far, Some(false) if it is older,
+    /// and None if it already arrived
+    fn record_received(&mut self, packet: u64) -> Option<bool> {
+        if packet > self.latest_received {
+            let shift = packet - self.latest_received;
+            self.received_bits = if self.latest_received == 0 || shift > ACK_BITS {
+                0
+            } else {
+                // the previous newest packet is one of the older ones now
+                let bits = (self.received_bits as u64) << shift | 1 << (shift - 1);
+                bits as u32
+            };
+            self.latest_received = packet;
+            return Some(true);
+        }
+
+        let age = self.latest_received - packet;
+        if age == 0 {
+            return None;
+        }
+        if age <= ACK_BITS {
+            let bit = 1 << (age - 1);
+            if self.received_bits & bit != 0 {
+                return None;
+            }
+            self.received_bits |= bit;
+        }
+        Some(false)
+    }
+
+    /// The reliable bodies of a packet that are ready to hand over
+    fn receive_reliable(
+        &mut self,
+        messages: Vec<ReliableBody<R>>,
+        ordered_base: u64,
+        unordered_base: u64,
+    ) -> Vec<R> {
+        let mut ready = Vec::new();
+
+        // everything before the bases was acked, so the ones still held arrived here,
+        // and the rest aren't coming
+        if ordered_base > self.expected_ordered {
+            let mut waiting = self.held.split_off(&ordered_base);
+            std::mem::swap(&mut waiting, &mut self.held);
+            ready.extend(waiting.into_values());
+            self.expected_ordered = ordered_base;
+        }
+        if unordered_base > self.unordered_floor {
+            self.unordered_floor = unordered_base;
+            self.unordered_received.retain(|id| *id >= unordered_base);
+        }
+
+        for message in messages {
+            let id = message.id.id;
+            if message.id.ordered {
+                if id >= self.expected_ordered {
+                    self.held.insert(id, message.body);
+                }
+            } else if id >= self.unordered_floor && self.unordered_received.insert(id) {
+                ready.push(message.body);
+            }
+        }
+
+        while let Some(body) = self.held.remove(&self.expected_ordered) {
+            ready.push(body);
+            self.expected_ordered += 1;
+        }
+        ready
+    }
+}
+
+/// A piece of an encoded message, sent as one datagram
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct Fragment {
+    /// Which message this is a piece of, counting up for every message sent
+    pub message: u32,
+    /// Where this piece goes in the message
+    pub index: u16,
+    /// How many pieces the message was split into
+    pub count: u16,
+    pub data: Vec<u8>,
+}
+
+/// A message that some of the fragments have arrived for
+#[derive(Debug)]
+struct Reassembly {
+    /// When the first fragment arrived
+    started: Instant,
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+}
+
+/// Splits messages into fragments that each fit in a datagram,
+/// and puts the fragments that arrive back together into messages
+#[derive(Debug, Default)]
+pub struct Fragmenter {
+    /// Id of the next message sent
+    next_message: u32,
+    /// Messages that are still missing fragments, by sender and message id
+    incomplete: HashMap<(SocketAddr, u32), Reassembly>,
+}
+
+impl Fragmenter {
+    /// Encode a message and split it into fragments
+    /// Small messages still get sent as a single fragment, so that every datagram looks the same
+    pub fn split<M: NetworkMessage>(&mut self, message: M) -> Result<Vec<Fragment>, SendError> {
+        let bytes =
+            bincode::encode_to_vec(message, BINCODE_CONFIG).map_err(SendError::EncodeError)?;
+        let count = bytes.len().div_ceil(FRAGMENT_SIZE);
+        if count > MAX_FRAGMENTS {
+            return Err(SendError::MessageTooLarge);
+        }
+
+        let message = self.next_message;
+        self.next_message = self.next_message.wrapping_add(1);
+
+        Ok(bytes
+            .chunks(FRAGMENT_SIZE)
+            .enumerate()
+            .map(|(index, data)| Fragment {
+                message,
+                index: index as u16,
+                count: count as u16,
+                data: data.to_vec(),
+            })
+            .collect())
+    }
+
+    /// Take in a datagram, and decode the message once all of its fragments have arrived
+    /// Returns ReceiveError::IncompleteMessage while fragments are still missing
+    pub fn reassemble<M: NetworkMessage>(
+        &mut self,
+        sender: SocketAddr,
+        datagram: &[u8],
+        now: Instant,
+    ) -> Result<M, ReceiveError> {
+        let (fragment, _size): (Fragment, _) = bincode::decode_from_slice(datagram, BINCODE_CONFIG)
+            .map_err(ReceiveError::DecodeError)?;
+        let count = fragment.count as usize;
+        let index = fragment.index as usize;
+        if count > MAX_FRAGMENTS || index >= count {
+            return Err(ReceiveError::BadFragment);
+        }
+
+        let bytes = if count == 1 {
+            fragment.data
+        } else {
+            // forget about messages that are never going to be finished
+            self.incomplete.retain(|_, reassembly| {
+                now.duration_since(reassembly.started) < REASSEMBLY_TIMEOUT
+            });
+
+            // a sender with too many only pushes out its own, so it can't crowd out anyone else
+            let key = (sender, fragment.message);
+            let from_sender = || {
+                self.incomplete
+                    .iter()
+                    .filter(|((from, _), _)| *from == sender)
+            };
+            if !self.incomplete.contains_key(&key)
+                && from_sender().count() >= MAX_INCOMPLETE_MESSAGES
+            {
+                let oldest = from_sender()
+                    .min_by_key(|(_, reassembly)| reassembly.started)
+                    .map(|(key, _)| *key);
+                if let Some(oldest) = oldest {
+                    self.incomplete.remove(&oldest);
+                }
+            }
+
+            let reassembly = self.incomplete.entry(key).or_insert_with(|| Reassembly {
+                started: now,
+                fragments: vec![None; count],
+                received: 0,
+            });
+            if reassembly.fragments.len() != count {
+                return Err(ReceiveError::BadFragment);
+            }
+            // copies of fragments just replace the first one
+            if reassembly.fragments[index].is_none() {
+                reassembly.received += 1;
+            }
+            reassembly.fragments[index] = Some(fragment.data);
+            if reassembly.received < count {
+                return Err(ReceiveError::IncompleteMessage);
+            }
+
+            match self.incomplete.remove(&key) {
+                Some(reassembly) => reassembly
+                    .fragments
+                    .into_iter()
+                    .flatten()
+                    .flatten()
+                    .collect(),
+                None => return Err(ReceiveError::IncompleteMessage),
+            }
+        };
+
+        let (message, _size) = bincode::decode_from_slice(&bytes, BINCODE_CONFIG)
+            .map_err(ReceiveError::DecodeError)?;
+        Ok(message)
+    }
+}
 
 #[derive(Debug)]
 pub enum SendError {
     IoError(std::io::Error),
     EncodeError(bincode::error::EncodeError),
+    /// The message needs more than MAX_FRAGMENTS fragments
+    MessageTooLarge,
     //NoSuchPeer,
 }
 
@@ -128,26 +738,32 @@ pub enum ReceiveError {
     DecodeError(bincode::error::DecodeError),
     UnknownSender,
     NoMessage,
+    /// A fragment arrived, but the rest of its message hasn't yet
+    IncompleteMessage,
+    /// A fragment that doesn't fit the message it says it is part of
+    BadFragment,
 }
 
-/// Helper method for sending a message
+/// Helper method for sending a message, split into as many datagrams as it needs
 pub fn send_message<M: NetworkMessage>(
     socket: &UdpSocket,
     target: SocketAddr,
     message: M,
+    fragmenter: &mut Fragmenter,
     buffer: &mut [u8],
 ) -> Result<(), SendError> {
-    // TODO: use a buffer instead of allocating into vector
-    let size = bincode::encode_into_slice(message, buffer, BINCODE_CONFIG)
-        .map_err(|e| SendError::EncodeError(e))?;
-    // info!("message size: {} bytes", size);
-    socket
-        .send_to(&buffer[0..size], target)
-        .map_err(|e| SendError::IoError(e))?;
+    for fragment in fragmenter.split(message)? {
+        let size = bincode::encode_into_slice(fragment, buffer, BINCODE_CONFIG)
+            .map_err(SendError::EncodeError)?;
+        socket
+            .send_to(&buffer[0..size], target)
+            .map_err(SendError::IoError)?;
+    }
     Ok(())
 }
 
-/// A component on _all_ players, connected or not
+/// Where a player's client is
+/// On the server, only players that are connected have one
 #[derive(Component, Debug, Encode, Decode, Clone, PartialEq, Eq, Hash)]
 pub struct ClientAddress {
     pub addr: SocketAddr,
@@ -175,3 +791,373 @@ impl ClientAddress {
         return Color::rgb(r, b, g);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{generator::DefaultGenerator, ChunkGenerator, TEST_SEED};
+
+    /// Bodies with one of each reliability
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    enum TestBody {
+        State(u64),
+        Event(u64),
+        Command(u64),
+    }
+
+    impl ChannelBody for TestBody {
+        fn reliability(&self) -> Reliability {
+            match self {
+                TestBody::State(_) => Reliability::Unreliable,
+                TestBody::Event(_) => Reliability::ReliableUnordered,
+                TestBody::Command(_) => Reliability::ReliableOrdered,
+            }
+        }
+    }
+
+    type TestChannel = Channel<TestBody, TestBody>;
+
+    const TICK: Duration = Duration::from_millis(16);
+
+    #[test]
+    fn reliable_bodies_survive_a_bad_connection() {
+        let mut sender = TestChannel::default();
+        let mut receiver = TestChannel::default();
+        let start = Instant::now();
+        let mut received = Vec::new();
+        let mut in_flight = Vec::new();
+
+        for tick in 0..200u32 {
+            let now = start + TICK * tick;
+            let bodies = if tick < 50 {
+                vec![
+                    TestBody::State(tick as u64),
+                    TestBody::Event(tick as u64),
+                    TestBody::Command(tick as u64),
+                ]
+            } else {
+                Vec::new()
+            };
+            in_flight.push(sender.send(bodies, now));
+
+            // every third packet gets lost, and the others arrive two at a time, swapped
+            if tick % 2 == 1 {
+                let newer = in_flight.pop().unwrap();
+                let older = in_flight.pop().unwrap();
+                for packet in [newer, older] {
+                    if packet.packet % 3 != 0 {
+                        received.extend(receiver.receive(packet, now));
+                    }
+                }
+            }
+
+            // and so do a third of the acks
+            let ack = receiver.send(Vec::new(), now);
+            if tick % 3 != 0 {
+                sender.receive(ack, now);
+            }
+        }
+
+        let commands: Vec<_> = received
+            .iter()
+            .filter_map(|body| match body {
+                TestBody::Command(command) => Some(*command),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(commands, (0..50).collect::<Vec<_>>());
+
+        let mut events: Vec<_> = received
+            .iter()
+            .filter_map(|body| match body {
+                TestBody::Event(event) => Some(*event),
+                _ => None,
+            })
+            .collect();
+        events.sort_unstable();
+        assert_eq!(events, (0..50).collect::<Vec<_>>());
+
+        assert_eq!(sender.unacked.len(), 0);
+        assert!(sender.rtt() < DEFAULT_RTT);
+    }
+
+    #[test]
+    fn stale_unreliable_bodies_are_dropped() {
+        let mut sender = TestChannel::default();
+        let mut receiver = TestChannel::default();
+        let now = Instant::now();
+
+        let first = sender.send(vec![TestBody::State(1), TestBody::Event(1)], now);
+        let second = sender.send(vec![TestBody::State(2)], now);
+
+        assert_eq!(
+            receiver.receive(second.clone(), now),
+            vec![TestBody::State(2)]
+        );
+        // the event still gets through, but the old state doesn't
+        assert_eq!(receiver.receive(first, now), vec![TestBody::Event(1)]);
+        // and copies are ignored
+        assert_eq!(receiver.receive(second, now), Vec::new());
+    }
+
+    #[test]
+    fn reliable_bodies_are_resent_until_acked() {
+        let mut sender = TestChannel::default();
+        let mut receiver = TestChannel::default();
+        let start = Instant::now();
+
+        let first = sender.send(vec![TestBody::Event(1)], start);
+        assert_eq!(first.reliable.len(), 1);
+
+        // not again before it could have been acked
+        let soon = sender.send(Vec::new(), start + TICK);
+        assert!(soon.reliable.is_empty());
+
+        // but again once it should have been
+        let later = start + DEFAULT_RTT * 2;
+        let resent = sender.send(Vec::new(), later);
+        assert_eq!(resent.reliable.len(), 1);
+        assert_eq!(resent.reliable[0].id, first.reliable[0].id);
+
+        // the receiver only takes it once
+        assert_eq!(receiver.receive(first, later), vec![TestBody::Event(1)]);
+        assert_eq!(receiver.receive(resent, later), Vec::new());
+
+        // and once it's acked it's never sent again
+        sender.receive(receiver.send(Vec::new(), later), later);
+        assert_eq!(sender.unacked.len(), 0);
+        let after = sender.send(Vec::new(), later + DEFAULT_RTT * 4);
+        assert!(after.reliable.is_empty());
+    }
+
+    #[test]
+    fn restarted_channels_start_over() {
+        let mut sender = TestChannel::default();
+        let mut receiver = TestChannel::default();
+        let now = Instant::now();
+
+        for command in 0..5 {
+            let packet = sender.send(vec![TestBody::Command(command)], now);
+            assert_eq!(
+                receiver.receive(packet, now),
+                vec![TestBody::Command(command)]
+            );
+            sender.receive(receiver.send(Vec::new(), now), now);
+        }
+
+        // a new receiver doesn't wait for the commands that the old one already acked
+        let mut receiver = TestChannel::default();
+        let packet = sender.send(vec![TestBody::Command(5)], now);
+        assert_eq!(receiver.receive(packet, now), vec![TestBody::Command(5)]);
+
+        // and a new sender's first command isn't mistaken for a copy of an old one
+        let mut sender = TestChannel::default();
+        let packet = sender.send(vec![TestBody::Command(0)], now);
+        assert_eq!(receiver.receive(packet, now), vec![TestBody::Command(0)]);
+    }
+
+    /// A baseline with a width * depth area of chunks, and everything else a client gets
+    fn baseline(width: i64, depth: u64) -> ServerMessage {
+        let generator = DefaultGenerator::new().unwrap();
+        let chunks = (0..width)
+            .flat_map(|chunk_x| (0..depth).map(move |n| (chunk_x, n)))
+            .map(|(chunk_x, n)| generator.chunk(TEST_SEED, chunk_x, n))
+            .collect();
+        let players = (0..4)
+            .map(|port| SingleNetPlayerInfo {
+                addr: ClientAddress {
+                    addr: SocketAddr::from(([127, 0, 0, 1], 9000 + port)),
+                },
+                position: PlayerPosition::default(),
+                jump: JumpState::default(),
+                last_input: 0,
+            })
+            .collect();
+        ServerMessage::Game(ServerToClient {
+            header: ServerHeader { sequence: 7 },
+            bodies: ChannelPacket {
+                session: 1,
+                packet: 7,
+                ack: 5,
+                ack_bits: 0b11,
+                ordered_base: 0,
+                unordered_base: 0,
+                unreliable: vec![
+                    ServerBodyElem::WorldDeltas(vec![WorldDelta::NewChunks(chunks)]),
+                    ServerBodyElem::PlayerInfo(players),
+                ],
+                reliable: vec![ReliableBody {
+                    id: MessageId {
+                        ordered: true,
+                        id: 0,
+                    },
+                    // one kind of block, so that the encoding doesn't depend on the hash order
+                    body: ServerBodyElem::Inventory(Inventory {
+                        amounts: [(BlockType::Sand, 12)].into(),
+                    }),
+                }],
+            },
+        })
+    }
+
+    fn encode<E: Encode>(value: E) -> Vec<u8> {
+        bincode::encode_to_vec(value, BINCODE_CONFIG).unwrap()
+    }
+
+    #[test]
+    fn baselines_are_split_and_put_back_together() {
+        let server = SocketAddr::from(([127, 0, 0, 1], DEFAULT_SERVER_PORT));
+        let now = Instant::now();
+
+        for (width, depth) in [(3, 3), (5, 3)] {
+            let expected = encode(baseline(width, depth));
+            let mut sender = Fragmenter::default();
+            let mut receiver = Fragmenter::default();
+
+            let fragments = sender.split(baseline(width, depth)).unwrap();
+            assert_eq!(fragments.len(), expected.len().div_ceil(FRAGMENT_SIZE));
+            assert!(fragments.len() > 1);
+            let datagrams: Vec<_> = fragments.into_iter().map(encode).collect();
+            for datagram in &datagrams {
+                assert!(datagram.len() <= FRAGMENT_SIZE + 16);
+            }
+
+            // fragments can arrive in any order, and more than once
+            let (last, rest) = datagrams.split_last().unwrap();
+            for datagram in rest.iter().rev().chain(rest.iter().take(2)) {
+                assert!(matches!(
+                    receiver.reassemble::<ServerMessage>(server, datagram, now),
+                    Err(ReceiveError::IncompleteMessage)
+                ));
+            }
+            let message: ServerMessage = receiver.reassemble(server, last, now).unwrap();
+            assert_eq!(encode(message), expected);
+            assert!(receiver.incomplete.is_empty());
+        }
+    }
+
+    #[test]
+    fn fragments_of_different_messages_stay_apart() {
+        let first_sender = SocketAddr::from(([127, 0, 0, 1], 9001));
+        let second_sender = SocketAddr::from(([127, 0, 0, 1], 9002));
+        let now = Instant::now();
+        let mut sender = Fragmenter::default();
+        let mut receiver = Fragmenter::default();
+
+        let first: Vec<_> = sender.split(baseline(3, 1)).unwrap();
+        let second: Vec<_> = sender.split(baseline(1, 3)).unwrap();
+        assert_ne!(first[0].message, second[0].message);
+
+        // the same fragments from another sender are another message
+        let mut done = Vec::new();
+        for fragment in first.iter().chain(&second) {
+            for from in [first_sender, second_sender] {
+                if let Ok(message) =
+                    receiver.reassemble::<ServerMessage>(from, &encode(fragment.clone()), now)
+                {
+                    done.push(encode(message));
+                }
+            }
+        }
+        assert_eq!(
+            done,
+            vec![
+                encode(baseline(3, 1)),
+                encode(baseline(3, 1)),
+                encode(baseline(1, 3)),
+                encode(baseline(1, 3))
+            ]
+        );
+
+        // fragments that don't fit their message are turned away
+        let mut bad = first[0].clone();
+        bad.index = bad.count;
+        assert!(matches!(
+            receiver.reassemble::<ServerMessage>(first_sender, &encode(bad), now),
+            Err(ReceiveError::BadFragment)
+        ));
+    }
+
+    #[test]
+    fn incomplete_messages_time_out() {
+        let server = SocketAddr::from(([127, 0, 0, 1], DEFAULT_SERVER_PORT));
+        let start = Instant::now();
+        let mut sender = Fragmenter::default();
+        let mut receiver = Fragmenter::default();
+
+        let datagrams: Vec<_> = sender
+            .split(baseline(3, 3))
+            .unwrap()
+            .into_iter()
+            .map(encode)
+            .collect();
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest {
+            assert!(receiver
+                .reassemble::<ServerMessage>(server, datagram, start)
+                .is_err());
+        }
+
+        // the last fragment is too late, so the rest are gone
+        let late = start + REASSEMBLY_TIMEOUT * 2;
+        assert!(matches!(
+            receiver.reassemble::<ServerMessage>(server, last, late),
+            Err(ReceiveError::IncompleteMessage)
+        ));
+        assert_eq!(receiver.incomplete.len(), 1);
+
+        // small messages don't have to wait for anything
+        let small = sender.split(baseline(0, 0)).unwrap();
+        assert_eq!(small.len(), 1);
+        assert!(receiver
+            .reassemble::<ServerMessage>(server, &encode(small[0].clone()), late)
+            .is_ok());
+    }
+
+    #[test]
+    fn one_sender_cant_crowd_out_another() {
+        let honest = SocketAddr::from(([127, 0, 0, 1], 9001));
+        let flooder = SocketAddr::from(([127, 0, 0, 1], 9002));
+        let start = Instant::now();
+        let mut sender = Fragmenter::default();
+        let mut receiver = Fragmenter::default();
+
+        let datagrams: Vec<_> = sender
+            .split(baseline(3, 3))
+            .unwrap()
+            .into_iter()
+            .map(encode)
+            .collect();
+        let (last, rest) = datagrams.split_last().unwrap();
+        for datagram in rest {
+            assert!(receiver
+                .reassemble::<ServerMessage>(honest, datagram, start)
+                .is_err());
+        }
+
+        // the first half of a lot of messages that never get finished
+        for message in 0..MAX_INCOMPLETE_MESSAGES as u32 * 2 {
+            let fragment = Fragment {
+                message,
+                index: 0,
+                count: 2,
+                data: vec![0],
+            };
+            let now = start + Duration::from_millis(message as u64);
+            assert!(receiver
+                .reassemble::<ServerMessage>(flooder, &encode(fragment), now)
+                .is_err());
+        }
+        assert_eq!(receiver.incomplete.len(), MAX_INCOMPLETE_MESSAGES + 1);
+        // the flooder's newest ones are kept
+        assert!(receiver
+            .incomplete
+            .contains_key(&(flooder, MAX_INCOMPLETE_MESSAGES as u32 * 2 - 1)));
+        assert!(!receiver.incomplete.contains_key(&(flooder, 0)));
+
+        let message: ServerMessage = receiver
+            .reassemble(honest, last, start + Duration::from_millis(100))
+            .unwrap();
+        assert_eq!(encode(message), encode(baseline(3, 3)));
+    }
+}
@@ -2,31 +2,57 @@ use super::*;
 use crate::{
     args::ServerArgs,
     player::{
-        server::{handle_movement, JumpDuration, JumpState},
-        Inventory, PlayerInput, PlayerPosition,
+        server::{handle_movement, hurt_players_in_lava, PendingInputs},
+        Health, Inventory, JumpState, PlayerIdentity, PlayerInput, PlayerPosition,
     },
     states,
     world::{
-        self, server::check_generate_new_chunks, BlockDelete, Terrain, WorldDelta, CHUNK_HEIGHT,
-        CHUNK_WIDTH,
+        self,
+        falling::drop_falling_blocks,
+        liquids::flow_liquids,
+        server::{add_generated_chunks, check_generate_new_chunks},
+        BlockDelete, BlockPlace, ChunkPos, FallingBlocks, LiquidChange, Liquids, Terrain,
+        WorldDelta, CHUNK_HEIGHT, CHUNK_WIDTH,
     },
 };
-use bevy::prelude::*;
+use bevy::{app::AppExit, prelude::*};
 use iyes_loopless::prelude::*;
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
     f32::consts::E,
+    hash::{Hash, Hasher},
     net::{SocketAddr, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Instant,
 };
 
 pub const MESSAGE_QUEUE_SIZE: usize = 20;
 
+/// How many blocks to either side of a player their client gets chunks for
+const CLIENT_VIEW_RADIUS_X: usize = 32;
+/// How many blocks above and below a player their client gets chunks for
+const CLIENT_VIEW_RADIUS_Y: usize = 24;
+/// The most chunks in a baseline: every chunk that a client's view can overlap
+/// The whole baseline goes out again every tick until the client acks it, so only chunks in view
+/// are sent, and this many of them fit in one message even when none can be shrunk
+const MAX_BASELINE_CHUNKS: usize = ((2 * CLIENT_VIEW_RADIUS_X).div_ceil(CHUNK_WIDTH) + 1)
+    * ((2 * CLIENT_VIEW_RADIUS_Y).div_ceil(CHUNK_HEIGHT) + 1);
+
 /// Should be used as a global resource on the server
 pub struct Server {
     /// UDP socket that should be used for everything
     socket: UdpSocket,
     /// The current sequence/tick number
     sequence: u64,
+    /// How many players can be connected at once
+    max_players: usize,
+    /// Random number that challenge tokens are made from, so that clients can't guess them
+    secret: u64,
+    /// Splits messages to clients, and puts messages from them back together
+    fragmenter: Fragmenter,
     /// Incoming buffer
     buffer: [u8; BUFFER_SIZE],
 }
@@ -34,9 +60,13 @@ pub struct Server {
 /// Helper resource to decouple message reception and processing
 #[derive(Default)]
 struct Messages {
-    messages: VecDeque<(SocketAddr, ClientToServer)>,
+    messages: VecDeque<(SocketAddr, ClientMessage)>,
 }
 
+/// Set when the server gets Ctrl+C, so it can tell clients that it's going away before it exits
+#[derive(Default)]
+struct ShutdownSignal(Arc<AtomicBool>);
+
 /// Information about a client, stored as a component on players that are connected
 #[derive(Component, Debug)]
 pub struct ConnectedClientInfo {
@@ -44,6 +74,8 @@ pub struct ConnectedClientInfo {
     pub last_ack: u64,
     /// Body elements that we build up
     pub bodies: Vec<ServerBodyElem>,
+    /// Acks, ordering and resending of bodies to and from the client
+    pub channel: Channel<ServerBodyElem, ClientBodyElem>,
     /// How many frames until we drop it
     pub until_drop: u64,
     /// Last confirmed world state (only the chunks that it knows about)
@@ -57,6 +89,7 @@ impl Default for ConnectedClientInfo {
         ConnectedClientInfo {
             last_ack: 0, // must be set immediately after creation
             bodies: Vec::with_capacity(DEFAULT_BODIES_VEC_CAPACITY),
+            channel: Channel::default(),
             until_drop: FRAME_DIFFERENCE_BEFORE_DISCONNECT,
             last_confirmed_terrain: Terrain::empty(),
             deltas: HashMap::new(),
@@ -66,7 +99,7 @@ impl Default for ConnectedClientInfo {
 
 impl Server {
     /// Binds the socket
-    fn new(port: u16) -> Result<Self, std::io::Error> {
+    fn new(port: u16, max_players: usize) -> Result<Self, std::io::Error> {
         let addr = SocketAddr::from(([0, 0, 0, 0], port));
         let sock = UdpSocket::bind(addr)?;
 
@@ -78,6 +111,9 @@ impl Server {
         Ok(Server {
             socket: sock,
             sequence: 1u64,
+            max_players,
+            secret: rand::random(),
+            fragmenter: Fragmenter::default(),
             buffer: [0u8; BUFFER_SIZE],
         })
     }
@@ -86,27 +122,35 @@ impl Server {
     fn send_message(
         &mut self,
         client_addr: SocketAddr,
-        message: ServerToClient,
+        message: ServerMessage,
     ) -> Result<(), SendError> {
         // TODO: check if address is acually a connected client via a query?
-        send_message(&self.socket, client_addr, message, &mut self.buffer)?;
+        send_message(
+            &self.socket,
+            client_addr,
+            message,
+            &mut self.fragmenter,
+            &mut self.buffer,
+        )?;
         Ok(())
     }
 
     /// Non-blocking way to get one message from the socket
     /// Can receive messages from _any_ address, not just connected clients
-    fn get_one_message(&mut self) -> Result<(SocketAddr, ClientToServer), ReceiveError> {
+    fn get_one_message(&mut self) -> Result<(SocketAddr, ClientMessage), ReceiveError> {
         // read from socket
-        let (_size, sender_addr) = self.socket.recv_from(&mut self.buffer).map_err(|e| match e
-            .kind()
-        {
-            std::io::ErrorKind::WouldBlock => ReceiveError::NoMessage,
-            _ => ReceiveError::IoError(e),
-        })?;
-
-        // decode
-        let (message, _size) = bincode::decode_from_slice(&self.buffer, BINCODE_CONFIG)
-            .map_err(ReceiveError::DecodeError)?;
+        let (size, sender_addr) =
+            self.socket
+                .recv_from(&mut self.buffer)
+                .map_err(|e| match e.kind() {
+                    std::io::ErrorKind::WouldBlock => ReceiveError::NoMessage,
+                    _ => ReceiveError::IoError(e),
+                })?;
+
+        // decode, once all of its fragments are here
+        let message =
+            self.fragmenter
+                .reassemble(sender_addr, &self.buffer[..size], Instant::now())?;
 
         // unwrap OK because we just guaranteed the client is in our HashMap
         Ok((sender_addr, message))
@@ -165,15 +209,50 @@ impl Plugin for ServerPlugin {
                 .label("check_generate_new_chunks")
                 .after("handle_messages"),
         )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            add_generated_chunks
+                .run_in_state(states::server::GameState::Running)
+                .label("add_generated_chunks")
+                .after("check_generate_new_chunks"),
+        )
         .add_fixed_timestep_system(
             GAME_TICK_LABEL,
             0,
             handle_movement
                 .run_in_state(states::server::GameState::Running)
                 .label("handle_movement")
-                .after("check_generate_new_chunks"),
+                .after("add_generated_chunks"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            flow_liquids
+                .run_in_state(states::server::GameState::Running)
+                .label("flow_liquids")
+                .after("handle_movement"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            drop_falling_blocks
+                .run_in_state(states::server::GameState::Running)
+                .label("drop_falling_blocks")
+                .after("flow_liquids"),
+        )
+        .add_fixed_timestep_system(
+            GAME_TICK_LABEL,
+            0,
+            hurt_players_in_lava
+                .run_in_state(states::server::GameState::Running)
+                .label("hurt_players_in_lava")
+                .after("handle_movement"),
         );
 
+        // every frame, not on a tick, so Ctrl+C is handled right away
+        app.add_system(shutdown_on_signal.run_in_state(states::server::GameState::Running));
+
         // debug print player info
         // app.add_fixed_timestep_system(
         //     NETWORK_TICK_LABEL,
@@ -246,7 +325,7 @@ impl Plugin for ServerPlugin {
 
 fn create_server(mut commands: Commands, args: Res<ServerArgs>) {
     // TODO: use command line arguments for port and handle failure better
-    let server = match Server::new(args.port) {
+    let server = match Server::new(args.port, args.max_players) {
         Ok(s) => s,
         Err(e) => panic!("Unable to create server: {}", e),
     };
@@ -255,6 +334,18 @@ fn create_server(mut commands: Commands, args: Res<ServerArgs>) {
 
     commands.insert_resource(Messages::default());
 
+    // catch Ctrl+C, a second one exits right away in case shutting down gets stuck
+    let shutdown = ShutdownSignal::default();
+    let signal = shutdown.0.clone();
+    if let Err(e) = ctrlc::set_handler(move || {
+        if signal.swap(true, Ordering::Relaxed) {
+            std::process::exit(1);
+        }
+    }) {
+        error!("unable to catch Ctrl+C: {}", e);
+    }
+    commands.insert_resource(shutdown);
+
     info!("server created");
 }
 
@@ -262,6 +353,30 @@ fn destroy_server(mut commands: Commands) {
     commands.remove_resource::<Server>();
 }
 
+/// Tell every connected client that the server is going away, then exit
+fn shutdown_on_signal(
+    signal: Res<ShutdownSignal>,
+    mut server: ResMut<Server>,
+    clients: Query<&ClientAddress, With<ConnectedClientInfo>>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if !signal.0.load(Ordering::Relaxed) {
+        return;
+    }
+
+    info!("shutting down");
+    for client_addr in clients.iter() {
+        // a few times over, since we won't be around to resend it
+        for _ in 0..DISCONNECT_COPIES {
+            let message = ServerMessage::Disconnect(LeaveReason::ShuttingDown);
+            if let Err(e) = server.send_message(client_addr.addr, message) {
+                error!("server unable to send message: {:?}", e);
+            }
+        }
+    }
+    exit.send(AppExit);
+}
+
 /// Server increase tick count
 fn increase_network_tick(mut server: ResMut<Server>) {
     server.sequence += 1;
@@ -275,20 +390,24 @@ fn process_player_mining(
         &mut Inventory,
     )>,
     mut terrain: ResMut<Terrain>,
-    mut commands: Commands,
+    mut liquids: ResMut<Liquids>,
+    mut falling: ResMut<FallingBlocks>,
 ) {
     for (addr, inputs, mut client, mut inventory) in query.iter_mut() {
         if inputs.mine {
-            // destroy the block
-            let res = world::server::destroy_block(
-                inputs.block_x,
-                inputs.block_y,
-                &mut commands,
-                &mut terrain,
-            );
+            // damage the block, which breaks once it has been mined for long enough
+            let res = world::server::mine_block(inputs.block, &mut terrain, &mut falling);
             //we really care what happens because of inventory
             match res {
-                Ok(block) => {
+                Ok(None) => {
+                    // damaged, but not broken yet
+                }
+                Ok(Some(block)) => {
+                    // liquids next to the block can flow into the hole
+                    if let Some((x, y)) = inputs.block.terrain_coords() {
+                        liquids.wake_around(x, y);
+                    }
+
                     // modify inventory
                     match inventory.amounts.get_mut(&block.block_type) {
                         Some(amount) => {
@@ -300,14 +419,14 @@ fn process_player_mining(
                     }
 
                     // info!(
-                    //     "player {} destroyed block at ({}, {}): {:?}, new inv: {:?}",
-                    //     addr, inputs.block_x, inputs.block_y, block.block_type, inventory
+                    //     "player {} destroyed block at {:?}: {:?}, new inv: {:?}",
+                    //     addr, inputs.block, block.block_type, inventory
                     // );
                 }
                 Err(_err) => {
                     // error!(
-                    //     "player {} unable to destroy block at ({}, {}): {:?}",
-                    //     addr, inputs.block_x, inputs.block_y, err
+                    //     "player {} unable to destroy block at {:?}: {:?}",
+                    //     addr, inputs.block, err
                     // );
                 }
             }
@@ -342,8 +461,8 @@ fn retrieve_messages(mut server: ResMut<Server>, mut messages: ResMut<Messages>)
                 // break whenever we run out of messages
                 break;
             }
-            Err(ReceiveError::UnknownSender) => {
-                warn!("server recieve error: server is full!");
+            Err(ReceiveError::IncompleteMessage) => {
+                // wait for the rest of the fragments
             }
             #[cfg(target_os = "windows")]
             Err(ReceiveError::IoError(e)) if e.kind() == std::io::ErrorKind::ConnectionReset => {
@@ -360,127 +479,226 @@ fn retrieve_messages(mut server: ResMut<Server>, mut messages: ResMut<Messages>)
 
 /// System that handles all messages from the Messages resource
 fn handle_messages(
+    mut server: ResMut<Server>,
     mut messages: ResMut<Messages>,
     mut commands: Commands,
     mut query: Query<(
         Entity,
-        &ClientAddress,
+        Option<&ClientAddress>,
+        &PlayerIdentity,
         Option<&mut ConnectedClientInfo>,
-        &mut PlayerInput,
+        &PlayerInput,
+        Option<&mut PendingInputs>,
     )>,
 ) {
     /*
-    We have to handle several different cases and we need immediate access
-    to all components (spawn() has a 1-tick delay), so if needed, we create
-    the components inside this function, call process_client_message,
-    then add the components to the entity
+    Clients have to go through a handshake before they get a player:
+    they ask to connect, the server answers with a challenge token, and once
+    the client sends the token back with its identity the server accepts it,
+    and gives it back its player or makes a new one.
+    Gameplay messages from clients that haven't joined are ignored.
+
+    Components of players that join this frame aren't there until next frame
+    (spawn() has a 1-tick delay), so we remember who joined here, with their key
     */
-
-    // process all messages from new clients all together at the end of this function,
-    // since entities aren't spawned until next frame
-    let mut new_clients: HashMap<SocketAddr, Vec<ClientToServer>> = HashMap::new();
+    let mut new_clients: HashMap<SocketAddr, u64> = HashMap::new();
+    // clients that left this frame, whose components aren't gone until next frame
+    let mut left_clients: HashSet<SocketAddr> = HashSet::new();
+    let mut players = query
+        .iter()
+        .filter(|(_, _, _, connected, ..)| connected.is_some())
+        .count();
 
     // for each message
     while let Some((addr, message)) = messages.messages.pop_front() {
         let mut entity: Option<Entity> = None;
 
-        // check if we have a player at this address already
-        for (e, client_addr, _, _) in query.iter() {
-            if client_addr.addr == addr {
-                entity = Some(e)
+        // check if a player is connected from this address already
+        for (e, client_addr, ..) in query.iter() {
+            if client_addr.is_some_and(|client_addr| client_addr.addr == addr) {
+                entity = Some(e);
             }
         }
-
-        match entity {
-            Some(entity) => {
-                // client is either currently connected or has connected before
-                // unwrap OK since we iterated to find it above
-                let e = query.get_mut(entity).unwrap();
-
-                // unpack tuple here for readability
-                let maybe_connected = e.2;
-                let mut input = e.3;
-
-                match maybe_connected {
-                    Some(mut connected) => {
-                        // client is currently connected
-
-                        // process the client message
-                        process_client_message(&addr, &mut connected, message, &mut input);
+        let joined =
+            (entity.is_some() || new_clients.contains_key(&addr)) && !left_clients.contains(&addr);
+
+        let reply = match message {
+            ClientMessage::Connect { protocol_version } => {
+                if joined {
+                    // the client didn't get our accept
+                    ServerMessage::Accept
+                } else {
+                    match check_join(protocol_version, players, server.max_players) {
+                        Ok(()) => ServerMessage::Challenge(challenge_token(server.secret, &addr)),
+                        Err(reason) => {
+                            info!("turning away {}: {}", addr, reason);
+                            ServerMessage::Reject(reason)
+                        }
                     }
-                    None => {
-                        // client has connected before, but timed out
-                        info!("reconnection from {}", addr);
-                        let mut connected = ConnectedClientInfo::default();
-
-                        // process the client message
-                        process_client_message(&addr, &mut connected, message, &mut input);
+                }
+            }
+            ClientMessage::ChallengeResponse { token, identity } => {
+                if token != challenge_token(server.secret, &addr) {
+                    warn!("wrong challenge token from {}", addr);
+                    continue;
+                }
 
-                        // add connected to the entity
-                        commands.entity(entity).insert(connected);
+                if joined {
+                    // the client didn't get our accept
+                    ServerMessage::Accept
+                } else {
+                    // find the player this client played as before
+                    let mut player: Option<Entity> = None;
+                    let mut playing = new_clients.values().any(|key| *key == identity.key);
+                    for (e, _, player_identity, connected, ..) in query.iter() {
+                        if player_identity.key == identity.key {
+                            player = Some(e);
+                            playing |= connected.is_some();
+                        }
+                    }
 
-                        // add other connected-only components to entity
-                        commands
-                            .entity(entity)
-                            .insert(JumpDuration::default())
-                            .insert(JumpState::default());
+                    // the version was checked before the challenge was sent,
+                    // but others could have joined since
+                    let already_connected = match playing {
+                        true => Err(RejectReason::AlreadyConnected),
+                        false => Ok(()),
+                    };
+                    match check_join(PROTOCOL_VERSION, players, server.max_players)
+                        .and(already_connected)
+                    {
+                        Ok(()) => {
+                            new_clients.insert(addr, identity.key);
+                            add_player(&mut commands, addr, identity, player);
+                            players += 1;
+                            ServerMessage::Accept
+                        }
+                        Err(reason) => {
+                            info!("turning away {}: {}", addr, reason);
+                            ServerMessage::Reject(reason)
+                        }
                     }
-                };
+                }
             }
-            None => {
-                // if we already got a message from this new client this frame
-                if let Some(mut client_messages) = new_clients.get_mut(&addr) {
-                    client_messages.push(message);
-                } else {
-                    // else this is the first messages from this new client this frame
-                    new_clients.insert(addr.clone(), vec![message]);
+            ClientMessage::Disconnect(reason) => {
+                // the copies of the message after the first are ignored
+                if joined {
+                    info!("{} disconnected: {}", addr, reason);
+                    if let Some(entity) = entity {
+                        disconnect_player(&mut commands, entity);
+                    }
+                    new_clients.remove(&addr);
+                    left_clients.insert(addr);
+                    players -= 1;
                 }
+                continue;
             }
+            ClientMessage::Game(message) => {
+                if !joined {
+                    // clients that haven't joined don't get an answer, they still have to connect
+                    continue;
+                }
+                if let Some(Ok((_, _, _, Some(mut connected), input, Some(mut pending)))) =
+                    entity.map(|entity| query.get_mut(entity))
+                {
+                    // process the client message
+                    process_client_message(&addr, &mut connected, message, input, &mut pending);
+                }
+                continue;
+            }
+        };
+
+        if let Err(e) = server.send_message(addr, reply) {
+            error!("server unable to send message: {:?}", e);
         }
     }
+}
+
+/// Check whether a client can join the game
+fn check_join(
+    protocol_version: u32,
+    players: usize,
+    max_players: usize,
+) -> Result<(), RejectReason> {
+    if protocol_version != PROTOCOL_VERSION {
+        return Err(RejectReason::WrongVersion {
+            server: PROTOCOL_VERSION,
+            client: protocol_version,
+        });
+    }
+    if players >= max_players {
+        return Err(RejectReason::ServerFull { max_players });
+    }
+    Ok(())
+}
 
-    for (addr, c_messages) in new_clients {
-        // new connection
-        let client_addr = ClientAddress { addr };
-        let position = PlayerPosition::default();
-        let mut input = PlayerInput::default();
-        let jump_dur = JumpDuration::default();
-        let jump_state = JumpState::default();
-        let inventory = Inventory::default();
-        let mut connected = ConnectedClientInfo::default();
+/// The token that a client has to send back to join, always the same for the same address
+/// so that the server doesn't have to remember anything about clients that haven't joined
+fn challenge_token(secret: u64, addr: &SocketAddr) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    secret.hash(&mut hasher);
+    addr.hash(&mut hasher);
+    hasher.finish()
+}
 
-        info!("new connection from {}", client_addr);
+/// Give a client that finished the handshake a player,
+/// or give it back the one it had if it played here before
+fn add_player(
+    commands: &mut Commands,
+    addr: SocketAddr,
+    identity: PlayerIdentity,
+    entity: Option<Entity>,
+) {
+    let client_addr = ClientAddress { addr };
+    match entity {
+        Some(entity) => {
+            info!("{} is back, from {}", identity.name, client_addr);
 
-        for message in c_messages {
-            // process the message
-            process_client_message(&client_addr.addr, &mut connected, message, &mut input);
+            // add connected-only components to entity, and the name in case it changed,
+            // and forget its old inputs, since the client numbers them from the start again
+            commands
+                .entity(entity)
+                .insert(client_addr)
+                .insert(identity)
+                .insert(PlayerInput::default())
+                .insert(PendingInputs::default())
+                .insert(ConnectedClientInfo::default())
+                .insert(JumpState::default())
+                .insert(Health::default());
         }
+        None => {
+            info!("new player {}, from {}", identity.name, client_addr);
 
-        // create entity with components
-        // ONLY once per new client
-        commands
-            .spawn()
-            .insert(client_addr)
-            .insert(position)
-            .insert(input)
-            .insert(connected)
-            .insert(jump_dur)
-            .insert(jump_state)
-            .insert(inventory);
+            // create entity with components
+            // ONLY once per new client
+            commands
+                .spawn()
+                .insert(client_addr)
+                .insert(identity)
+                .insert(PlayerPosition::default())
+                .insert(PlayerInput::default())
+                .insert(PendingInputs::default())
+                .insert(ConnectedClientInfo::default())
+                .insert(JumpState::default())
+                .insert(Health::default())
+                .insert(Inventory::default());
+        }
     }
 }
 
 /// Process a client's message and push new bodies to the next packet sent to the client
-/// Uses client message info to overwrite player input components
+/// Queues up the inputs in it for the player to move with
 fn process_client_message(
     addr: &SocketAddr,
     client: &mut ConnectedClientInfo,
     message: ClientToServer,
-    input: &mut PlayerInput,
+    input: &PlayerInput,
+    pending: &mut PendingInputs,
 ) {
+    let bodies = client.channel.receive(message.bodies, Instant::now());
+
     // TODO: just impl Display or Debug instead
     let mut bodies_str = "".to_string();
-    for body in &message.bodies {
+    for body in &bodies {
         bodies_str.push_str(match body {
             ClientBodyElem::Ping => "ping,",
             ClientBodyElem::Input(_) => "input,",
@@ -489,18 +707,13 @@ fn process_client_message(
     // info!(
     //     "server got message from client @ {} with {} bodies: {}",
     //     addr,
-    //     message.bodies.len(),
+    //     bodies.len(),
     //     bodies_str
     // );
 
-    let mut in_order = false;
-
     // this message is in-order
-    // TODO: whenever the clients send inputs, ignore any that are out of order
-    // i.e. only use the most recent input
     if message.header.last_received_sequence > client.last_ack {
         client.last_ack = message.header.last_received_sequence;
-        client.bodies.clear(); // clear any pending pings
 
         // get the changes we need to apply to our baseline
         let changes = client.deltas.get(&client.last_ack);
@@ -518,11 +731,31 @@ fn process_client_message(
                             // delete single block
 
                             // find chunk
-                            for mut chunk in &mut client.last_confirmed_terrain.chunks {
-                                if chunk.chunk_number == delete.chunk_number {
-                                    // delete the block
-                                    chunk.blocks[delete.y][delete.x] = None;
-                                }
+                            if let Some(chunk) = client
+                                .last_confirmed_terrain
+                                .find_chunk_mut(delete.chunk_x, delete.chunk_number)
+                            {
+                                // delete the block
+                                chunk.blocks[delete.y][delete.x] = None;
+                            }
+                        }
+                        WorldDelta::LiquidChange(change) => {
+                            // replace single block
+                            if let Some(chunk) = client
+                                .last_confirmed_terrain
+                                .find_chunk_mut(change.chunk_x, change.chunk_number)
+                            {
+                                chunk.blocks[change.y][change.x] =
+                                    change.block_type.map(world::Block::new);
+                            }
+                        }
+                        WorldDelta::BlockPlace(place) => {
+                            // replace single block
+                            if let Some(chunk) = client
+                                .last_confirmed_terrain
+                                .find_chunk_mut(place.chunk_x, place.chunk_number)
+                            {
+                                chunk.blocks[place.y][place.x] = Some(place.block);
                             }
                         }
                     }
@@ -543,24 +776,18 @@ fn process_client_message(
 
         // reset client's drop timer
         client.until_drop = FRAME_DIFFERENCE_BEFORE_DISCONNECT;
-
-        // this message was in-order
-        in_order = true;
     }
 
     // compute our direct responses
-    let mut body_elems: Vec<ServerBodyElem> = message
-        .bodies
+    let mut body_elems: Vec<ServerBodyElem> = bodies
         .iter()
         // match client bodies to server bodies
         .filter_map(|elem| match elem {
             ClientBodyElem::Ping => Some(ServerBodyElem::Pong(message.header.current_sequence)),
             ClientBodyElem::Input(new_input) => {
-                if in_order {
-                    // info!("server got inputs for client {}", addr);
-                    // add inputs to player entity's input component
-                    *input = new_input.clone();
-                }
+                // inputs can arrive out of order or more than once, the queue sorts them out
+                // info!("server got inputs for client {}", addr);
+                pending.push(new_input, input);
 
                 // never respond directly to input bodies
                 None
@@ -579,12 +806,6 @@ fn process_client_message(
 
     // queue up our responses to be sent our in the next packet
     client.bodies.append(&mut body_elems);
-
-    // only keep pongs that are in response to a ping newer than or equals to the client's last_ack
-    client.bodies.retain(|elem| match elem {
-        ServerBodyElem::Pong(seq) => *seq >= client.last_ack,
-        _ => true, // keep everything else
-    });
 }
 
 fn send_all_messages(
@@ -592,13 +813,15 @@ fn send_all_messages(
     mut query: Query<(&ClientAddress, &mut ConnectedClientInfo)>,
 ) {
     // loop over clients
-    for (client_addr, client_info) in query.iter_mut() {
-        let message = ServerToClient {
+    for (client_addr, mut client_info) in query.iter_mut() {
+        // the channel keeps the reliable bodies around until the client acks them
+        let bodies = std::mem::take(&mut client_info.bodies);
+        let message = ServerMessage::Game(ServerToClient {
             header: ServerHeader {
                 sequence: server.sequence,
             },
-            bodies: client_info.bodies.clone(),
-        };
+            bodies: client_info.channel.send(bodies, Instant::now()),
+        });
 
         // form message via borrow before consuming it
         let success_msg = format!("server sent message to {:?}", client_addr);
@@ -609,14 +832,6 @@ fn send_all_messages(
             Err(e) => error!("server unable to send message: {:?}", e),
         }
     }
-
-    // filter out client bodies
-    for (_, mut client_info) in query.iter_mut() {
-        client_info.bodies.retain(|b| match b {
-            ServerBodyElem::Pong(_) => true, // keep pongs until we know they were received
-            _ => false,                      // never keep anything else
-        });
-    }
 }
 
 /// Add the terrain to the next packet sent
@@ -628,30 +843,32 @@ fn enqueue_terrain(
     mut clients: Query<(&ClientAddress, &mut ConnectedClientInfo, &PlayerPosition)>,
 ) {
     for (addr, mut client, player_position) in clients.iter_mut() {
-        // the number of the chunk that the player is in
-        let player_chunk = -(player_position.y) as usize / CHUNK_HEIGHT as usize;
-        let chunk_range = if player_chunk == 0 {
-            0..=1
-        } else {
-            (player_chunk - 1)..=(player_chunk + 1)
-        };
-
-        // info!("enqueuing partial terrain {:?} to {}", chunk_range, addr);
+        let player_block = player_position.world_pos().block();
+
+        // the closest chunks around the player that the server has generated
+        let mut chunk_positions: Vec<(i64, u64)> =
+            world::chunks_in_area(player_block, CLIENT_VIEW_RADIUS_X, CLIENT_VIEW_RADIUS_Y)
+                .into_iter()
+                .filter(|(chunk_x, chunk_number)| {
+                    terrain.find_chunk(*chunk_x, *chunk_number).is_some()
+                })
+                .collect();
+        chunk_positions.sort_by_key(|(chunk_x, chunk_number)| {
+            ChunkPos::new(*chunk_x, *chunk_number).distance_to(player_block)
+        });
+        chunk_positions.truncate(MAX_BASELINE_CHUNKS);
 
-        // chunks that the client has
-        let client_chunks: Vec<u64> = client
-            .last_confirmed_terrain
-            .chunks
-            .iter()
-            .map(|c| c.chunk_number)
-            .collect();
+        // info!("enqueuing partial terrain {:?} to {}", chunk_positions, addr);
 
         // check if the client doesn't have a chunk that it should
         let mut needs_baseline = false;
-        for chunk_num in chunk_range.clone() {
-            // check if client is missing this chunk number
-            let mut filter = client_chunks.iter().filter(|c| **c == chunk_num as u64);
-            if filter.next().is_none() {
+        for (chunk_x, chunk_number) in chunk_positions.iter() {
+            // check if client is missing this chunk
+            if client
+                .last_confirmed_terrain
+                .find_chunk(*chunk_x, *chunk_number)
+                .is_none()
+            {
                 // if it is missing a chunk, it needs a new baseline
                 needs_baseline = true;
             }
@@ -662,47 +879,88 @@ fn enqueue_terrain(
         if needs_baseline {
             // resend the entire baseline!
             // the terrain we will send them
-            let mut baseline = Terrain::empty();
             // clone in only specified chunks
-            for chunk_number in chunk_range {
-                baseline.chunks.push(terrain.chunks[chunk_number].clone())
-            }
+            let baseline: Terrain = chunk_positions
+                .into_iter()
+                .filter_map(|(chunk_x, chunk_number)| terrain.find_chunk(chunk_x, chunk_number))
+                .cloned()
+                .collect();
 
             // push it
             world_changes.push(WorldDelta::NewChunks(baseline));
         } else {
             // just calcluate the block deletions
-            for client_chunk in &mut client.last_confirmed_terrain.chunks {
+            for client_chunk in client.last_confirmed_terrain.chunks() {
+                let chunk_x = client_chunk.chunk_x;
                 let chunk_num = client_chunk.chunk_number;
 
-                // server chunks are always at their correct index
-                let server_chunk = terrain.chunks.get(chunk_num as usize);
+                let server_chunk = terrain.find_chunk(chunk_x, chunk_num);
                 match server_chunk {
                     Some(server_chunk) => {
                         // loop over blocks in chunk
                         for y in 0..CHUNK_HEIGHT {
                             for x in 0..CHUNK_WIDTH {
-                                // if the client chunk has a block here but server doesn't
-                                if client_chunk.blocks[y][x].is_some()
-                                    && server_chunk.blocks[y][x].is_none()
-                                {
-                                    // create delta (deletion)
-                                    let block_deletion = BlockDelete {
-                                        chunk_number: chunk_num,
-                                        x,
-                                        y,
-                                    };
-                                    // push it to the client
-                                    world_changes.push(WorldDelta::BlockDelete(block_deletion));
+                                let client_block = client_chunk.blocks[y][x];
+                                let server_block = server_chunk.blocks[y][x];
+                                if client_block == server_block {
+                                    continue;
+                                }
+
+                                match server_block {
+                                    // liquid that flowed in, or changed level
+                                    Some(block) if block.block_type.is_liquid() => {
+                                        world_changes.push(WorldDelta::LiquidChange(
+                                            LiquidChange {
+                                                chunk_x,
+                                                chunk_number: chunk_num,
+                                                x,
+                                                y,
+                                                block_type: Some(block.block_type),
+                                            },
+                                        ));
+                                    }
+                                    // a block where the client has none or a different one, like a falling block
+                                    // sinking through water, or the same one in a different state, like a block
+                                    // being mined
+                                    Some(block) => {
+                                        world_changes.push(WorldDelta::BlockPlace(BlockPlace {
+                                            chunk_x,
+                                            chunk_number: chunk_num,
+                                            x,
+                                            y,
+                                            block,
+                                        }));
+                                    }
+                                    // liquid that flowed away
+                                    None if client_block
+                                        .is_some_and(|b| b.block_type.is_liquid()) =>
+                                    {
+                                        world_changes.push(WorldDelta::LiquidChange(
+                                            LiquidChange {
+                                                chunk_x,
+                                                chunk_number: chunk_num,
+                                                x,
+                                                y,
+                                                block_type: None,
+                                            },
+                                        ));
+                                    }
+                                    // the client chunk has a block here but server doesn't
+                                    None => {
+                                        world_changes.push(WorldDelta::BlockDelete(BlockDelete {
+                                            chunk_x,
+                                            chunk_number: chunk_num,
+                                            x,
+                                            y,
+                                        }));
+                                    }
                                 }
                             }
                         }
                     }
                     None => {
-                        error!(
-                            "client somehow has chunk that server doesn't have: {}",
-                            chunk_num
-                        );
+                        // the server unloaded it since no player is near it,
+                        // and it can't change until it gets loaded again
                     }
                 }
             }
@@ -721,7 +979,10 @@ fn enqueue_terrain(
 /// Enqueues all player information to each client
 fn enqueue_player_info(
     // With<> for connected players only
-    info: Query<(&ClientAddress, &PlayerPosition), With<ConnectedClientInfo>>,
+    info: Query<
+        (&ClientAddress, &PlayerPosition, &JumpState, &PlayerInput),
+        With<ConnectedClientInfo>,
+    >,
     mut clients: Query<(&ClientAddress, &mut ConnectedClientInfo)>,
 ) {
     // for each connected client
@@ -730,10 +991,12 @@ fn enqueue_player_info(
         let mut players = Vec::new();
 
         // loop over every connected player info
-        for (addr, pos) in info.iter() {
+        for (addr, pos, jump, input) in info.iter() {
             let player_info = SingleNetPlayerInfo {
                 addr: addr.clone(),
                 position: pos.clone(),
+                jump: jump.clone(),
+                last_input: input.sequence,
             };
 
             if addr.addr == target_client_addr.addr {
@@ -754,8 +1017,13 @@ fn enqueue_player_info(
     }
 }
 
-/// Enqueue player inventory info to each client
-fn enqueue_inventory(mut clients: Query<(&mut ConnectedClientInfo, &Inventory)>) {
+/// Clients that need their inventory sent: it changed, or they just (re)connected
+type InventoryOutdated = Or<(Changed<Inventory>, Added<ConnectedClientInfo>)>;
+
+/// Enqueue player inventory info to each client when it changes, and when they connect
+fn enqueue_inventory(
+    mut clients: Query<(&mut ConnectedClientInfo, &Inventory), InventoryOutdated>,
+) {
     for (mut client, inv) in clients.iter_mut() {
         client.bodies.push(ServerBodyElem::Inventory(inv.clone()));
     }
@@ -770,12 +1038,7 @@ fn drop_disconnected_clients(
         // if we need to drop them
         if client.until_drop == 0 {
             warn!("dropping client {}", addr);
-            // remove all connected-only components
-            commands
-                .entity(entity)
-                .remove::<ConnectedClientInfo>()
-                .remove::<JumpState>()
-                .remove::<JumpDuration>();
+            disconnect_player(&mut commands, entity);
         } else {
             // in else so we never underflow
             client.until_drop -= 1;
@@ -783,6 +1046,17 @@ fn drop_disconnected_clients(
     }
 }
 
+/// Remove all connected-only components from a player
+/// The player itself stays, so the client can come back to it
+fn disconnect_player(commands: &mut Commands, entity: Entity) {
+    commands
+        .entity(entity)
+        .remove::<ClientAddress>()
+        .remove::<ConnectedClientInfo>()
+        .remove::<JumpState>()
+        .remove::<PendingInputs>();
+}
+
 /// debug print client info
 fn debug_print_players(query: Query<(Entity, &ClientAddress, Option<&ConnectedClientInfo>)>) {
     // print entity, address, and connected
@@ -795,3 +1069,234 @@ fn debug_print_players(query: Query<(Entity, &ClientAddress, Option<&ConnectedCl
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::player::client::PredictedInputs;
+    use crate::world::{Block, BlockType, Chunk};
+
+    /// A connected player standing on a floor, with everything the server moves them with
+    fn movement_world() -> (World, Entity) {
+        let mut world = World::new();
+        world.insert_resource(Terrain::with_floor(0));
+        let entity = world
+            .spawn()
+            .insert(PlayerPosition {
+                x: 2.,
+                y: -((CHUNK_HEIGHT - 2) as f32),
+            })
+            .insert(JumpState::default())
+            .insert(PlayerInput::default())
+            .insert(PendingInputs::default())
+            .insert(ConnectedClientInfo::default())
+            .id();
+        (world, entity)
+    }
+
+    /// Hand the server a packet of inputs from the client, the same way handle_messages does
+    fn deliver(
+        world: &mut World,
+        entity: Entity,
+        channel: &mut Channel<ClientBodyElem, ServerBodyElem>,
+        inputs: &[PlayerInput],
+    ) {
+        let message = ClientToServer {
+            header: ClientHeader {
+                current_sequence: 0,
+                last_received_sequence: 0,
+            },
+            bodies: channel.send(
+                inputs.iter().cloned().map(ClientBodyElem::Input).collect(),
+                Instant::now(),
+            ),
+        };
+        let mut query =
+            world.query::<(&mut ConnectedClientInfo, &PlayerInput, &mut PendingInputs)>();
+        let (mut connected, input, mut pending) = query.get_mut(world, entity).unwrap();
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9000));
+        process_client_message(&addr, &mut connected, message, input, &mut pending);
+    }
+
+    /// What the client sent, and where it predicted that would take it
+    struct Predicted {
+        predicted: PredictedInputs,
+        sent: Vec<PlayerInput>,
+        position: PlayerPosition,
+        jump: JumpState,
+    }
+
+    /// The client is ahead of the server, and has already moved with all of its inputs
+    fn predict_inputs(world: &World, entity: Entity, count: usize) -> Predicted {
+        let terrain = Terrain::with_floor(0);
+        let mut predicted = PredictedInputs::default();
+        let mut position = world.get::<PlayerPosition>(entity).unwrap().clone();
+        let mut jump = JumpState::default();
+        let mut sent = Vec::new();
+        for tick in 0..count {
+            let mut input = PlayerInput {
+                right: true,
+                jump: (10..15).contains(&tick),
+                ..default()
+            };
+            predicted.predict(&mut input, &mut position, &mut jump, &terrain);
+            sent.push(input);
+        }
+        Predicted {
+            predicted,
+            sent,
+            position,
+            jump,
+        }
+    }
+
+    /// Check that the server moved with exactly the inputs it says it did,
+    /// so playing the rest again ends up where the client already is
+    /// Returns the sequence number of the last input the server used
+    fn check_reconcile(world: &mut World, entity: Entity, client: &mut Predicted) -> u64 {
+        let end = (client.position.x, client.position.y);
+        let end_jump = client.jump.clone();
+        let mut query = world.query::<(&PlayerPosition, &JumpState, &PlayerInput)>();
+        let (server_position, server_jump, input) = query.get(world, entity).unwrap();
+        client.predicted.reconcile(
+            input.sequence,
+            server_position,
+            server_jump,
+            &mut client.position,
+            &mut client.jump,
+            &Terrain::with_floor(0),
+        );
+        assert_eq!((client.position.x, client.position.y), end);
+        assert_eq!(client.jump, end_jump);
+        input.sequence
+    }
+
+    #[test]
+    fn players_move_with_every_input_once() {
+        let (mut world, entity) = movement_world();
+        let mut movement = SystemStage::single(handle_movement);
+        let mut channel = Channel::default();
+        let mut client = predict_inputs(&world, entity, 40);
+        let sent = client.sent.clone();
+        let end = (client.position.x, client.position.y);
+
+        let mut last_used = 0;
+        for tick in 0..sent.len() {
+            // packets show up in bunches every few ticks, out of order, with copies of inputs
+            if tick % 3 == 0 {
+                let newer = &sent[(tick + 1).min(sent.len())..(tick + 3).min(sent.len())];
+                let older = &sent[tick..(tick + 2).min(sent.len())];
+                deliver(&mut world, entity, &mut channel, newer);
+                deliver(&mut world, entity, &mut channel, older);
+            }
+            movement.run(&mut world);
+
+            let used = check_reconcile(&mut world, entity, &mut client);
+            assert!(used >= last_used && used <= last_used + 2);
+            last_used = used;
+        }
+
+        // every input got used, and with nothing new the player waits for the next one
+        assert_eq!(last_used, 40);
+        movement.run(&mut world);
+        let server_position = world.get::<PlayerPosition>(entity).unwrap();
+        assert_eq!((server_position.x, server_position.y), end);
+        assert_eq!(world.get::<PlayerInput>(entity).unwrap().sequence, 40);
+
+        // and inputs that were already used don't come back
+        deliver(&mut world, entity, &mut channel, &sent[35..]);
+        movement.run(&mut world);
+        assert_eq!(world.get::<PlayerInput>(entity).unwrap().sequence, 40);
+    }
+
+    #[test]
+    fn late_inputs_get_caught_up_on() {
+        let (mut world, entity) = movement_world();
+        let mut movement = SystemStage::single(handle_movement);
+        let mut channel = Channel::default();
+        let mut client = predict_inputs(&world, entity, 20);
+        let sent = client.sent.clone();
+
+        let mut last_position = None;
+        for tick in 0..sent.len() {
+            match tick {
+                // one input is late, so nothing arrives for a tick
+                5 => {}
+                // then it arrives together with the next one
+                6 => deliver(&mut world, entity, &mut channel, &sent[5..7]),
+                _ => deliver(&mut world, entity, &mut channel, &sent[tick..tick + 1]),
+            }
+            movement.run(&mut world);
+
+            let used = check_reconcile(&mut world, entity, &mut client);
+            let position = world.get::<PlayerPosition>(entity).unwrap().clone();
+            if tick == 5 {
+                // the player waits instead of moving with an input the client didn't predict
+                assert_eq!(used, 5);
+                assert_eq!(last_position, Some((position.x, position.y)));
+            } else {
+                // and then uses both, so no input is left waiting behind the ones after it
+                assert_eq!(used, tick as u64 + 1);
+            }
+            last_position = Some((position.x, position.y));
+        }
+    }
+
+    #[test]
+    fn baselines_fit_in_a_message() {
+        // no two blocks the same, so every block is its own palette entry and run
+        let mut chunk = Chunk::empty(0, 0);
+        for y in 0..CHUNK_HEIGHT {
+            for x in 0..CHUNK_WIDTH {
+                let i = y * CHUNK_WIDTH + x;
+                let mut block = Block::new(BlockType::Granite);
+                block.state.damage = (i % 256) as u8;
+                block.state.variant = (i / 256) as u8;
+                chunk.blocks[y][x] = Some(block);
+            }
+        }
+        let baseline: Terrain = (0..MAX_BASELINE_CHUNKS as i64)
+            .map(|chunk_x| Chunk {
+                chunk_x,
+                ..chunk.clone()
+            })
+            .collect();
+        assert_eq!(baseline.len(), MAX_BASELINE_CHUNKS);
+
+        let mut channel: Channel<ServerBodyElem, ClientBodyElem> = Channel::default();
+        let bodies = vec![ServerBodyElem::WorldDeltas(vec![WorldDelta::NewChunks(
+            baseline,
+        )])];
+        let message = ServerMessage::Game(ServerToClient {
+            header: ServerHeader { sequence: 1 },
+            bodies: channel.send(bodies, Instant::now()),
+        });
+        assert!(Fragmenter::default().split(message).is_ok());
+    }
+
+    #[test]
+    fn clients_are_turned_away_with_a_reason() {
+        assert_eq!(check_join(PROTOCOL_VERSION, 0, 2), Ok(()));
+        assert_eq!(check_join(PROTOCOL_VERSION, 1, 2), Ok(()));
+        assert_eq!(
+            check_join(PROTOCOL_VERSION, 2, 2),
+            Err(RejectReason::ServerFull { max_players: 2 })
+        );
+        assert_eq!(
+            check_join(PROTOCOL_VERSION + 1, 0, 2),
+            Err(RejectReason::WrongVersion {
+                server: PROTOCOL_VERSION,
+                client: PROTOCOL_VERSION + 1,
+            })
+        );
+    }
+
+    #[test]
+    fn challenge_tokens_depend_on_the_address() {
+        let addr = SocketAddr::from(([127, 0, 0, 1], 9000));
+        let other = SocketAddr::from(([127, 0, 0, 1], 9001));
+        assert_eq!(challenge_token(1, &addr), challenge_token(1, &addr));
+        assert_ne!(challenge_token(1, &addr), challenge_token(1, &other));
+        assert_ne!(challenge_token(1, &addr), challenge_token(2, &addr));
+    }
+}
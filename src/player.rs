@@ -5,29 +5,40 @@ use bevy::{
     time::Stopwatch,
 };
 use iyes_loopless::prelude::*;
-use std::{cmp, collections::HashMap, time::Duration};
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    time::Duration,
+};
 use strum::IntoEnumIterator;
 
 use bincode::{Decode, Encode};
+use serde::{Deserialize, Serialize};
 
 use crate::network::ClientAddress;
 use crate::{
     states::client::GameState,
-    world::{
-        block_exists, derender_chunk, render_chunk, spawn_chunk, to_world_point_x,
-        to_world_point_y, Terrain, CHUNK_HEIGHT, CHUNK_WIDTH,
-    },
-    CharacterCamera, WIN_H, WIN_W,
+    world::{BlockPos, Terrain, WorldPos, BLOCK_SIZE},
+    CharacterCamera,
 };
 
 const PLAYER_ASSET: &str = "Ferris.png";
-pub const PLAYER_AND_BLOCK_SIZE: f32 = 32.;
 const PLAYER_START_POS: PlayerPosition = PlayerPosition { x: 0., y: 0. };
 const PLAYER_SPEED: f32 = 20.;
 const PLAYER_JUMP_DURATION: f32 = 0.3; //seconds
 const PLAYER_MINE_DURATION: f32 = 2.; //seconds
 const PLAYER_MINE_RADIUS: f32 = 3.; //number of blocks
 const GRAVITY: f32 = -10.0;
+/// How many inputs the client keeps to play again when the server says where it is,
+/// about two seconds of them; older ones are forgotten if the server stops answering
+const MAX_PREDICTED_INPUTS: usize = 120;
+/// How many inputs the server holds on to for a player before it skips the oldest ones,
+/// so a client whose clock runs fast doesn't end up further and further ahead of the server
+const MAX_PENDING_INPUTS: usize = 30;
+/// How much slower players move, fall and swim in water and lava
+const WATER_SLOWDOWN: f32 = 0.5;
+const LAVA_SLOWDOWN: f32 = 0.25;
+const PLAYER_MAX_HEALTH: f32 = 100.;
+const LAVA_DAMAGE_PER_SECOND: f32 = 50.;
 pub const CAMERA_BOUNDS_SIZE: [f32; 2] = [1000., 500.];
 const PLAYER_Z: f32 = 2.0;
 const INV_ICON_SIZE: f32 = 48.0;
@@ -38,15 +49,52 @@ pub struct PlayerPosition {
     pub y: f32,
 }
 
+impl PlayerPosition {
+    /// Where the middle of the player is in the world
+    pub fn world_pos(&self) -> WorldPos {
+        WorldPos::new(self.x, self.y)
+    }
+}
+
 /// Contains all inputs that the client needs to tell the server
 #[derive(Component, Encode, Decode, Clone, Debug, Default)]
 pub struct PlayerInput {
     pub left: bool,
     pub right: bool,
     pub jump: bool,
-    pub mine: bool, //true means the block at block_x, block_y was clicked on.
-    pub block_x: usize,
-    pub block_y: usize,
+    pub mine: bool, //true means the block at block was clicked on.
+    pub block: BlockPos,
+    /// Counts up for each input the client sends, so the server can skip ones that arrive late,
+    /// and tell the client which one it last moved the player with
+    pub sequence: u64,
+}
+
+/// How much more damage a player can take before they respawn
+#[derive(Component, Debug, Clone)]
+pub struct Health {
+    pub health: f32,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Self {
+            health: PLAYER_MAX_HEALTH,
+        }
+    }
+}
+
+impl Health {
+    /// Take damage, and respawn with full health if there is none left
+    /// Returns true if the player respawned
+    pub fn hurt(&mut self, damage: f32, position: &mut PlayerPosition) -> bool {
+        self.health -= damage;
+        if self.health > 0. {
+            return false;
+        }
+        *position = PLAYER_START_POS;
+        *self = Health::default();
+        true
+    }
 }
 
 /// Represents the entire inventory for a player
@@ -63,259 +111,321 @@ impl Default for Inventory {
     }
 }
 
-pub mod server {
-    use crate::network::server::ConnectedClientInfo;
+/// Who a player is, the same every time they join, no matter where from
+/// The key is a random number made the first time the game runs, which the server
+/// knows the player by; the name is just for people to read
+#[derive(Component, Debug, Encode, Decode, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PlayerIdentity {
+    pub name: String,
+    pub key: u64,
+}
 
-    use super::*;
+/// Whether a player is going up, coming down, or standing on something
+#[derive(Eq, PartialEq, Debug, Default, Clone, Copy, Encode, Decode)]
+enum PlayerJumpState {
+    Jumping,
+    Falling,
+    #[default]
+    NonJumping,
+}
 
-    #[derive(Component)]
-    pub struct JumpDuration {
-        timer: Timer,
-    }
+/// Where a player is in their jump, which movement carries over from one tick to the next
+#[derive(Component, Default, Debug, Encode, Decode, Clone, PartialEq)]
+pub struct JumpState {
+    state: PlayerJumpState,
+    /// How long the player has been jumping for, they fall after PLAYER_JUMP_DURATION
+    time: Duration,
+}
 
-    impl Default for JumpDuration {
-        fn default() -> Self {
-            Self {
-                timer: Timer::new(Duration::from_secs_f32(PLAYER_JUMP_DURATION), false),
-            }
-        }
+#[derive(Component, Debug, Default)]
+struct PlayerCollision {
+    any: bool,
+    top: Option<f32>,
+    right: Option<f32>,
+    bottom: Option<f32>,
+    left: Option<f32>,
+    inside: bool,
+}
+
+/// Moves a player along by one tick of their input: walking, jumping, swimming, gravity,
+/// and bumping into blocks
+/// The server moves every player with this, and the client moves its own player with it too,
+/// so it doesn't have to wait for the server to see itself move
+pub fn move_player(
+    player_position: &mut PlayerPosition,
+    player_jump_state: &mut JumpState,
+    input: &PlayerInput,
+    terrain: &Terrain,
+) {
+    const DEBUG_COLLISIONS: bool = false;
+
+    // timers don't work with iyes_loopless?
+    // TODO: maybe make this system run _not_ on a fixed timestep and user a timer
+    let time_delta = 1f32 / 60f32;
+
+    let jump_duration = Duration::from_secs_f32(PLAYER_JUMP_DURATION);
+    player_jump_state.time =
+        (player_jump_state.time + Duration::from_secs_f32(time_delta)).min(jump_duration);
+
+    let mut x_diff = 0.;
+    let mut y_diff = 0.;
+
+    let prev_x = player_position.x;
+    let prev_y = player_position.y;
+
+    // info!("movement calc, starting: ({}, {})", prev_x, prev_y);
+
+    //Player moves left
+    if input.left {
+        x_diff -= PLAYER_SPEED * time_delta;
     }
 
-    #[derive(Component)]
-    struct MineDuration {
-        timer: Stopwatch,
+    //Player moves right
+    if input.right {
+        x_diff += PLAYER_SPEED * time_delta;
     }
 
-    #[derive(Eq, PartialEq)]
-    enum PlayerJumpState {
-        Jumping,
-        Falling,
-        NonJumping,
+    // liquids slow everything down, and players can swim up through them
+    let liquid = liquid_at(player_position, terrain);
+    let slowdown = match liquid {
+        Some(BlockType::Water) => WATER_SLOWDOWN,
+        Some(BlockType::Lava) => LAVA_SLOWDOWN,
+        _ => 1.,
+    };
+    x_diff *= slowdown;
+    if liquid.is_some() && input.jump {
+        y_diff += PLAYER_SPEED * slowdown * time_delta;
     }
 
-    impl Default for PlayerJumpState {
-        fn default() -> Self {
-            PlayerJumpState::NonJumping
-        }
+    //When space pressed, set player to jumping and start timer
+    if input.jump && player_jump_state.state == PlayerJumpState::NonJumping {
+        player_jump_state.time = Duration::ZERO;
+        player_jump_state.state = PlayerJumpState::Jumping;
+        // info!("player starting jump");
     }
 
-    #[derive(Component, Default)]
-    pub struct JumpState {
-        state: PlayerJumpState,
-    }
-
-    #[derive(Component, Debug)]
-    struct PlayerCollision {
-        any: bool,
-        top: Option<f32>,
-        right: Option<f32>,
-        bottom: Option<f32>,
-        left: Option<f32>,
-        inside: bool,
-    }
-
-    impl Default for PlayerCollision {
-        fn default() -> PlayerCollision {
-            PlayerCollision {
-                any: false,
-                top: None,
-                right: None,
-                bottom: None,
-                left: None,
-                inside: false,
-            }
-        }
+    //Player jumps (increases in height) for PLAYER_JUMP_DURATION seconds
+    if player_jump_state.time < jump_duration && player_jump_state.state == PlayerJumpState::Jumping
+    {
+        y_diff += PLAYER_SPEED * time_delta;
+        // info!("player is jumping");
     }
 
-    //Handles player movement, gravity, jumpstate
-    pub fn handle_movement(
-        mut query: Query<
-            (
-                &mut PlayerPosition,
-                &mut JumpDuration,
-                &mut JumpState,
-                &PlayerInput,
-            ),
-            With<ConnectedClientInfo>,
-        >,
-        _time: Res<Time>,
-        terrain: Res<Terrain>,
-    ) {
-        const DEBUG_COLLISIONS: bool = false;
+    //sets jump state as player falling
+    if player_jump_state.state == PlayerJumpState::Jumping
+        && player_jump_state.time >= jump_duration
+    {
+        player_jump_state.state = PlayerJumpState::Falling;
+        // info!("player is falling");
+    }
 
-        // timers don't work with iyes_loopless?
-        // TODO: maybe make this system run _not_ on a fixed timestep and user a timer
-        let time_delta = 1f32 / 60f32;
+    // gravity already negative
+    y_diff += GRAVITY * slowdown * time_delta;
 
-        for (mut player_position, mut player_jump_timer, mut player_jump_state, input) in
-            query.iter_mut()
-        {
-            player_jump_timer
-                .timer
-                .tick(Duration::from_secs_f32(time_delta));
+    // info!(
+    //     "moving player, time_delta:{:.5} x_diff:{:.2}, y_diff:{:.2}",
+    //     time_delta, x_diff, y_diff
+    // );
 
-            let mut x_diff = 0.;
-            let mut y_diff = 0.;
+    player_position.x += x_diff;
+    player_position.y += y_diff;
 
-            let prev_x = player_position.x;
-            let prev_y = player_position.y;
+    loop {
+        let player_collision = get_collisions(player_position, terrain, DEBUG_COLLISIONS);
+        if !player_collision.any {
+            break;
+        }
 
-            // info!("movement calc, starting: ({}, {})", prev_x, prev_y);
+        // info!("There's a collision: {:?}", player_collision);
+        // Check for "inside" conditions that can occur and just reset in those scenarios
+        if (player_collision.left.is_some() && player_collision.right.is_some())
+            || (player_collision.top.is_some() && player_collision.bottom.is_some())
+            || player_collision.inside
+        {
+            player_position.x = prev_x;
+            player_position.y = prev_y;
+            // info!("Inside collision");
 
-            //Player moves left
-            if input.left {
-                x_diff -= PLAYER_SPEED * time_delta;
-            }
+            continue;
+        }
 
-            //Player moves right
-            if input.right {
-                x_diff += PLAYER_SPEED * time_delta;
-            }
+        if let Some(left) = player_collision.left {
+            player_position.x = left;
+            // info!("Left collision");
+            continue;
+        } else if let Some(right) = player_collision.right {
+            player_position.x = right;
+            // info!("Right collision");]
+            continue;
+        }
 
-            //When space pressed, set player to jumping and start timer
-            if input.jump && player_jump_state.state == PlayerJumpState::NonJumping {
-                player_jump_timer.timer.reset();
-                player_jump_state.state = PlayerJumpState::Jumping;
-                // info!("player starting jump");
-            }
+        if let Some(top) = player_collision.top {
+            player_position.y = top;
+            // info!("Top collision");
+            continue;
+        } else if let Some(bottom) = player_collision.bottom {
+            player_position.y = bottom;
+            // info!("Bottom collision");
+            player_jump_state.state = PlayerJumpState::NonJumping;
+            // info!("player hit ground");
+
+            continue;
+        }
+    }
+}
 
-            //Player jumps (increases in height) for PLAYER_JUMP_DURATION seconds
-            if !player_jump_timer.timer.finished()
-                && player_jump_state.state == PlayerJumpState::Jumping
-            {
-                y_diff += PLAYER_SPEED * time_delta;
-                // info!("player is jumping");
-            }
+/// The liquid that the middle of a player is in, if any
+fn liquid_at(player_position: &PlayerPosition, terrain: &Terrain) -> Option<BlockType> {
+    terrain
+        .get_block(player_position.world_pos().block())
+        .flatten()
+        .map(|block| block.block_type)
+        .filter(|block_type| block_type.is_liquid())
+}
 
-            //sets jump state as player falling
-            if player_jump_state.state == PlayerJumpState::Jumping
-                && player_jump_timer.timer.finished()
-            {
-                player_jump_state.state = PlayerJumpState::Falling;
-                // info!("player is falling");
+fn get_collisions(
+    player_position: &PlayerPosition,
+    terrain: &Terrain,
+    debug: bool,
+) -> PlayerCollision {
+    // Get block indices we need to check
+
+    // the block that the middle of the player is in, the player can only touch the ones around it
+    let player_block = player_position.world_pos().block();
+
+    // info!("player: {:?}", player_block);
+
+    let sizes = Vec2 { x: 1., y: 1. };
+
+    let mut collisions = PlayerCollision::default();
+
+    for x_index in (player_block.x - 1)..=(player_block.x + 1) {
+        for y_index in (player_block.y - 1)..=(player_block.y + 1) {
+            // chunks that haven't been generated yet (and the sky) have nothing to collide with
+            let block = terrain.get_block(BlockPos::new(x_index, y_index)).flatten();
+
+            // info!("checking x: {}, y: {}, block = {:?}", x_index, y_index, block);
+            // players move through liquids
+            if block.is_some_and(|block| !block.block_type.is_liquid()) {
+                let z = PLAYER_Z; // always collide on same z plane
+                let center = BlockPos::new(x_index, y_index).center();
+                let block_pos = Vec3::new(center.x, center.y, z);
+                let collision = collide(
+                    Vec3::new(player_position.x, player_position.y, z),
+                    sizes,
+                    block_pos,
+                    sizes,
+                );
+                if collision.is_some() {
+                    collisions.any = true;
+                    match collision {
+                        Some(Collision::Top) => collisions.bottom = Some(block_pos.y + sizes.y),
+                        Some(Collision::Left) => collisions.right = Some(block_pos.x - sizes.x),
+                        Some(Collision::Bottom) => collisions.top = Some(block_pos.y - sizes.y),
+                        Some(Collision::Right) => collisions.left = Some(block_pos.x + sizes.x),
+                        Some(Collision::Inside) => collisions.inside = true,
+                        None => (),
+                    }
+                }
+                if debug {
+                    info!(
+                        "Block x: {}, y: {}, collision: {:?}, playerxy: {:?}, blockxy: {},{}",
+                        x_index, y_index, collision, player_position, block_pos.x, block_pos.y
+                    );
+                }
             }
+        }
+    }
 
-            // gravity already negative
-            y_diff += GRAVITY * time_delta;
-
-            // info!(
-            //     "moving player, time_delta:{:.5} x_diff:{:.2}, y_diff:{:.2}",
-            //     time_delta, x_diff, y_diff
-            // );
-
-            player_position.x += x_diff as f32;
-            player_position.y += y_diff as f32;
+    collisions
+}
 
-            // prevent going past horizontal world boundaries
-            player_position.x =
-                f32::min(f32::max(player_position.x, 0.0), (CHUNK_WIDTH - 1) as f32);
+pub mod server {
+    use crate::network::server::ConnectedClientInfo;
 
-            loop {
-                let player_collision = get_collisions(&player_position, &terrain, DEBUG_COLLISIONS);
-                if !player_collision.any {
-                    break;
-                }
+    use super::*;
 
-                // info!("There's a collision: {:?}", player_collision);
-                // Check for "inside" conditions that can occur and just reset in those scenarios
-                if (player_collision.left.is_some() && player_collision.right.is_some())
-                    || (player_collision.top.is_some() && player_collision.bottom.is_some())
-                    || player_collision.inside
-                {
-                    player_position.x = prev_x;
-                    player_position.y = prev_y;
-                    // info!("Inside collision");
-
-                    continue;
-                }
+    #[derive(Component)]
+    struct MineDuration {
+        timer: Stopwatch,
+    }
 
-                if player_collision.left.is_some() {
-                    player_position.x = player_collision.left.unwrap();
-                    // info!("Left collision");
-                    continue;
-                } else if player_collision.right.is_some() {
-                    player_position.x = player_collision.right.unwrap();
-                    // info!("Right collision");]
-                    continue;
-                }
+    /// Inputs from a player's client that they haven't been moved with yet, by sequence number
+    /// Movement uses each of them once, the same as the client did when it predicted them
+    #[derive(Component, Default)]
+    pub struct PendingInputs {
+        inputs: BTreeMap<u64, PlayerInput>,
+    }
 
-                if player_collision.top.is_some() {
-                    player_position.y = player_collision.top.unwrap();
-                    // info!("Top collision");
-                    continue;
-                } else if player_collision.bottom.is_some() {
-                    player_position.y = player_collision.bottom.unwrap();
-                    // info!("Bottom collision");
-                    player_jump_state.state = PlayerJumpState::NonJumping;
-                    // info!("player hit ground");
-
-                    continue;
-                }
+    impl PendingInputs {
+        /// Queue up an input, unless it is a copy of one that's queued or already used
+        pub fn push(&mut self, input: &PlayerInput, current: &PlayerInput) {
+            if input.sequence <= current.sequence {
+                return;
+            }
+            self.inputs.insert(input.sequence, input.clone());
+            while self.inputs.len() > MAX_PENDING_INPUTS {
+                self.inputs.pop_first();
             }
         }
     }
 
-    fn get_collisions(
-        player_position: &Mut<PlayerPosition>,
-        terrain: &Terrain,
-        debug: bool,
-    ) -> PlayerCollision {
-        // Get block indices we need to check
-
-        // how many blocks to the right the player is
-        let player_x_block = (player_position.x) as usize;
-        // how many blocks down the player is
-        let player_y_block = -(player_position.y) as usize;
-
-        // info!("player: ({}, {})", player_x_block, player_y_block);
+    //Handles player movement, gravity, jumpstate
+    pub fn handle_movement(
+        mut query: Query<
+            (
+                &mut PlayerPosition,
+                &mut JumpState,
+                &mut PlayerInput,
+                &mut PendingInputs,
+            ),
+            With<ConnectedClientInfo>,
+        >,
+        terrain: Res<Terrain>,
+    ) {
+        for (mut player_position, mut player_jump_state, mut input, mut pending) in query.iter_mut()
+        {
+            // the next input in order, and one more to catch up if they arrived late and bunched up
+            // the player waits if the next hasn't gotten here yet, since the client only predicted
+            // moving once per input, and the last one's sequence number is what the client hears
+            // the position includes
+            let moves = if pending.inputs.len() > 1 { 2 } else { 1 };
+            for _ in 0..moves {
+                let next = match pending.inputs.pop_first() {
+                    Some((_, next)) => next,
+                    None => break,
+                };
+                *input = next;
+                move_player(
+                    &mut player_position,
+                    &mut player_jump_state,
+                    &input,
+                    &terrain,
+                );
+            }
+        }
+    }
 
-        let sizes = Vec2 { x: 1., y: 1. };
+    /// Hurt players that are in lava, and respawn the ones that run out of health
+    pub fn hurt_players_in_lava(
+        mut query: Query<
+            (&ClientAddress, &mut PlayerPosition, &mut Health),
+            With<ConnectedClientInfo>,
+        >,
+        terrain: Res<Terrain>,
+    ) {
+        // same fixed timestep as handle_movement
+        let time_delta = 1f32 / 60f32;
 
-        let mut collisions = PlayerCollision::default();
+        for (addr, mut player_position, mut health) in query.iter_mut() {
+            if liquid_at(&player_position, &terrain) != Some(BlockType::Lava) {
+                continue;
+            }
 
-        for x_index in
-            (cmp::max(1, player_x_block) - 1)..=(cmp::min(player_x_block + 1, CHUNK_WIDTH - 1))
-        {
-            for y_index in (cmp::max(1, player_y_block) - 1)..=player_y_block + 1 {
-                let chunk_number = y_index / CHUNK_HEIGHT;
-                // index inside the chunk
-                let chunk_y_index = y_index - (chunk_number * CHUNK_HEIGHT);
-
-                let block = terrain.chunks[chunk_number].blocks[chunk_y_index][x_index];
-
-                // info!("checking chunk: {}, x: {}, y: {}, block = {:?}", chunk_number, x_index, chunk_y_index, block);
-                if block.is_some() {
-                    let z = PLAYER_Z; // always collide on same z plane
-                    let block_pos = Vec3 {
-                        x: x_index as f32,
-                        y: -(chunk_y_index as f32 + (chunk_number * CHUNK_HEIGHT) as f32) as f32,
-                        z: z,
-                    };
-                    let collision = collide(
-                        Vec3::new(player_position.x as f32, player_position.y as f32, z),
-                        sizes,
-                        block_pos,
-                        sizes,
-                    );
-                    if collision.is_some() {
-                        collisions.any = true;
-                        match collision {
-                            Some(Collision::Top) => collisions.bottom = Some(block_pos.y + sizes.y),
-                            Some(Collision::Left) => collisions.right = Some(block_pos.x - sizes.x),
-                            Some(Collision::Bottom) => collisions.top = Some(block_pos.y - sizes.y),
-                            Some(Collision::Right) => collisions.left = Some(block_pos.x + sizes.x),
-                            Some(Collision::Inside) => collisions.inside = true,
-                            None => (),
-                        }
-                    }
-                    if debug {
-                        info!("Block x: {}, y: {}, chunk: {}, collision: {:?}, playerxy: {:?}, blockxy: {},{}", x_index, chunk_y_index, chunk_number, collision, player_position, block_pos.x, block_pos.y);
-                    }
-                }
+            if health.hurt(LAVA_DAMAGE_PER_SECOND * time_delta, &mut player_position) {
+                info!("player {} burned up in lava", addr);
             }
         }
-
-        return collisions;
     }
 }
 
@@ -355,6 +465,71 @@ pub mod client {
     #[derive(Component)]
     pub struct Player;
 
+    /// Inputs that the local player moved with that the server hasn't moved them with yet,
+    /// oldest first, so they can be played again on top of where the server says the player is
+    #[derive(Component, Default)]
+    pub struct PredictedInputs {
+        /// Sequence number of the newest input
+        last_sequence: u64,
+        /// Newest input that the server has moved the player with
+        last_acked: u64,
+        inputs: VecDeque<PlayerInput>,
+    }
+
+    impl PredictedInputs {
+        /// Number an input for sending, and move the player with it right away
+        pub fn predict(
+            &mut self,
+            input: &mut PlayerInput,
+            position: &mut PlayerPosition,
+            jump: &mut JumpState,
+            terrain: &Terrain,
+        ) {
+            self.last_sequence += 1;
+            input.sequence = self.last_sequence;
+
+            move_player(position, jump, input, terrain);
+
+            self.inputs.push_back(input.clone());
+            if self.inputs.len() > MAX_PREDICTED_INPUTS {
+                self.inputs.pop_front();
+            }
+        }
+
+        /// Up to the newest count inputs that the server hasn't moved the player with, oldest first
+        pub fn newest(&self, count: usize) -> impl Iterator<Item = &PlayerInput> {
+            self.inputs
+                .iter()
+                .skip(self.inputs.len().saturating_sub(count))
+        }
+
+        /// Put the player where the server says they are after it moved them with input
+        /// `last_input`, then move them again with every input after that one
+        pub fn reconcile(
+            &mut self,
+            last_input: u64,
+            server_position: &PlayerPosition,
+            server_jump: &JumpState,
+            position: &mut PlayerPosition,
+            jump: &mut JumpState,
+            terrain: &Terrain,
+        ) {
+            // the server can't take back inputs, so this is older than what we already know
+            if last_input < self.last_acked {
+                return;
+            }
+            self.last_acked = last_input;
+
+            self.inputs.retain(|input| input.sequence > last_input);
+
+            *position = server_position.clone();
+            *jump = server_jump.clone();
+            for input in &self.inputs {
+                move_player(position, jump, input, terrain);
+            }
+        }
+    }
+
     #[derive(Component)]
     pub struct CameraBoundsBox {
         pub center_coord: Vec3,
@@ -369,8 +544,10 @@ pub mod client {
         mut camera: Query<&mut Transform, With<CharacterCamera>>,
     ) {
         for (mut render_pos, game_pos, local) in query.iter_mut() {
-            let bevy_x = game_pos.x as f32 * PLAYER_AND_BLOCK_SIZE as f32;
-            let bevy_y = game_pos.y as f32 * PLAYER_AND_BLOCK_SIZE as f32;
+            let Vec2 {
+                x: bevy_x,
+                y: bevy_y,
+            } = game_pos.world_pos().to_render();
 
             if bevy_x != render_pos.translation.x {
                 render_pos.translation.x = bevy_x;
@@ -401,7 +578,7 @@ pub mod client {
                 },
                 texture: assets.load(PLAYER_ASSET),
                 sprite: Sprite {
-                    custom_size: Some(Vec2::splat(PLAYER_AND_BLOCK_SIZE)),
+                    custom_size: Some(Vec2::splat(BLOCK_SIZE)),
                     ..default()
                 },
                 ..default()
@@ -409,6 +586,8 @@ pub mod client {
             .insert(LocalPlayer)
             .insert(Player)
             .insert(game_position)
+            .insert(JumpState::default())
+            .insert(PredictedInputs::default())
             .insert(CameraBoundsBox {
                 center_coord: bevy_position.clone(),
             })
@@ -554,20 +733,19 @@ pub mod client {
         let color = addr.color();
 
         // game coords -> bevy rendering coords
-        let real_x = position.x * 32.;
-        let real_y = position.y * 32.;
+        let render = position.world_pos().to_render();
 
         commands
             .spawn()
             .insert_bundle(SpriteBundle {
                 transform: Transform {
                     // render in front of blocks
-                    translation: Vec3::new(real_x as f32, real_y as f32, PLAYER_Z),
+                    translation: render.extend(PLAYER_Z),
                     ..default()
                 },
                 texture: assets.load(PLAYER_ASSET),
                 sprite: Sprite {
-                    custom_size: Some(Vec2::splat(PLAYER_AND_BLOCK_SIZE)),
+                    custom_size: Some(Vec2::splat(BLOCK_SIZE)),
                     color: color, // tint
                     ..default()
                 },
@@ -652,3 +830,149 @@ pub mod client {
         camera_transform.translation.y = camera_bounds.center_coord[1];
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::client::PredictedInputs;
+    use super::*;
+    use crate::world::{Block, CHUNK_HEIGHT};
+
+    /// Standing on the floor
+    fn start() -> (PlayerPosition, JumpState) {
+        let position = PlayerPosition {
+            x: 2.,
+            y: -((CHUNK_HEIGHT - 2) as f32),
+        };
+        (position, JumpState::default())
+    }
+
+    /// Walking right, jumping part of the way along
+    fn walk_right(ticks: usize) -> Vec<PlayerInput> {
+        (0..ticks)
+            .map(|tick| PlayerInput {
+                right: true,
+                jump: (10..15).contains(&tick),
+                ..default()
+            })
+            .collect()
+    }
+
+    /// Predict every input, returning them numbered like they would be sent
+    fn predict_all(
+        predicted: &mut PredictedInputs,
+        inputs: &[PlayerInput],
+        position: &mut PlayerPosition,
+        jump: &mut JumpState,
+        terrain: &Terrain,
+    ) -> Vec<PlayerInput> {
+        let mut sent = Vec::new();
+        for input in inputs {
+            let mut input = input.clone();
+            predicted.predict(&mut input, position, jump, terrain);
+            sent.push(input);
+        }
+        sent
+    }
+
+    #[test]
+    fn replayed_inputs_end_up_where_they_were_predicted() {
+        let terrain = Terrain::with_floor(0);
+        let mut predicted = PredictedInputs::default();
+        let (mut position, mut jump) = start();
+        let sent = predict_all(
+            &mut predicted,
+            &walk_right(40),
+            &mut position,
+            &mut jump,
+            &terrain,
+        );
+        assert_eq!(sent[0].sequence, 1);
+        assert_eq!(sent[39].sequence, 40);
+        assert!(position.x > start().0.x);
+
+        // the server has only moved the player with some of the inputs so far
+        let (mut server_position, mut server_jump) = start();
+        for input in &sent[..25] {
+            move_player(&mut server_position, &mut server_jump, input, &terrain);
+        }
+        assert!(server_position.y > start().0.y, "should be mid-jump");
+
+        let (predicted_position, predicted_jump) = (position.clone(), jump.clone());
+        predicted.reconcile(
+            25,
+            &server_position,
+            &server_jump,
+            &mut position,
+            &mut jump,
+            &terrain,
+        );
+        assert_eq!(
+            (position.x, position.y),
+            (predicted_position.x, predicted_position.y)
+        );
+        assert_eq!(jump, predicted_jump);
+
+        // the server saying where the player was even longer ago changes nothing
+        let (old_position, old_jump) = start();
+        predicted.reconcile(
+            10,
+            &old_position,
+            &old_jump,
+            &mut position,
+            &mut jump,
+            &terrain,
+        );
+        assert_eq!(
+            (position.x, position.y),
+            (predicted_position.x, predicted_position.y)
+        );
+    }
+
+    #[test]
+    fn wrong_predictions_get_corrected() {
+        let client_terrain = Terrain::with_floor(0);
+        // the server knows about a wall that the client doesn't
+        let mut server_terrain = Terrain::with_floor(0);
+        let chunk = server_terrain.find_chunk_mut(0, 0).unwrap();
+        for y in CHUNK_HEIGHT - 5..CHUNK_HEIGHT - 1 {
+            chunk.blocks[y][6] = Some(Block::new(BlockType::Limestone));
+        }
+
+        let mut predicted = PredictedInputs::default();
+        let (mut position, mut jump) = start();
+        let sent = predict_all(
+            &mut predicted,
+            &walk_right(30),
+            &mut position,
+            &mut jump,
+            &client_terrain,
+        );
+        assert!(position.x > 6.);
+
+        let (mut server_position, mut server_jump) = start();
+        for input in &sent {
+            move_player(
+                &mut server_position,
+                &mut server_jump,
+                input,
+                &server_terrain,
+            );
+        }
+        assert!(server_position.x <= 5.);
+
+        // every input has been used, so the player goes right where the server says
+        predicted.reconcile(
+            30,
+            &server_position,
+            &server_jump,
+            &mut position,
+            &mut jump,
+            &client_terrain,
+        );
+        assert_eq!(
+            (position.x, position.y),
+            (server_position.x, server_position.y)
+        );
+        assert_eq!(jump, server_jump);
+    }
+}
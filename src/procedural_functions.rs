@@ -6,9 +6,14 @@ use std::{
 use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use rand_distr::{Binomial, Distribution};
 
-use crate::world::{BiomeType, BlockType, Cave, OreType, Vein, CHUNK_HEIGHT, CHUNK_WIDTH};
+use crate::world::{
+    biomes::BiomeChange, caves, ores, Biome, BiomeTable, BlockType, Cave, ChunkBiomes, Vein,
+    VeinShape, CHUNK_HEIGHT, CHUNK_WIDTH,
+};
 
 const FREQUENCY: f32 = 4.;
+/// How many layers of noise caves are made of
+const CAVE_OCTAVES: u64 = 3;
 
 pub fn generate_seed(base_seed: u64, additional_data: Vec<u64>) -> u64 {
     let mut s = DefaultHasher::new();
@@ -19,6 +24,13 @@ pub fn generate_seed(base_seed: u64, additional_data: Vec<u64>) -> u64 {
     s.finish()
 }
 
+/// Turns arbitrary text (e.g. from the command line) into a world seed
+pub fn seed_from_str(text: &str) -> u64 {
+    let mut s = DefaultHasher::new();
+    text.hash(&mut s);
+    s.finish()
+}
+
 //Generates vector of random values, with seed, with amount
 pub fn generate_random_values(seed: u64, amount: usize, low: usize, high: usize) -> Vec<i32> {
     let mut values: Vec<i32> = Vec::new();
@@ -31,20 +43,31 @@ pub fn generate_random_values(seed: u64, amount: usize, low: usize, high: usize)
     values
 }
 
-pub fn perlin_slice(seed: u64, density: usize, width: usize, height: usize) -> Vec<i32> {
-    let r = generate_random_values(seed, density, 0, height);
-    let mut slice = vec![0; width];
-    for x in 0..width {
-        slice[x] = slice_pos_x(x, &r) as i32;
-    }
-    return slice;
+/// The height of a smooth random curve at a position anywhere in the world
+/// Interpolates between random points every `spacing` blocks,
+/// each point is derived from the seed and its position so chunks line up with their neighbors
+pub fn world_slice_pos_x(seed: u64, spacing: usize, low: usize, high: usize, world_x: i64) -> f32 {
+    let spacing = spacing as i64;
+    let point = world_x.div_euclid(spacing);
+    let diff = world_x.rem_euclid(spacing) as f32 / spacing as f32;
+
+    let point_value = |point: i64| {
+        let mut rand = StdRng::seed_from_u64(generate_seed(seed, vec![point as u64]));
+        rand.gen_range(low as i32..high as i32) as f32
+    };
+
+    //Cubic curve
+    let u = diff * diff * (3.0 - 2.0 * diff);
+
+    //Interpolate + return
+    point_value(point) * (1.0f32 - u) + point_value(point + 1) * u
 }
 
 //Generates a random count of veins for a chunk using a normal distribution
-pub fn generate_random_vein_count(seed: u64, chunk_number: u64) -> u64 {
+pub fn generate_random_vein_count(seed: u64, chunk_x: i64, chunk_number: u64) -> u64 {
     let approx_veins_per_chunk = 16.0;
     // Treat it as if every block of a chunk has a % chance of originating an ore vein
-    let mut rand = StdRng::seed_from_u64(generate_seed(seed, vec![chunk_number]));
+    let mut rand = StdRng::seed_from_u64(generate_seed(seed, vec![chunk_x as u64, chunk_number]));
     let bindist = Binomial::new(
         (CHUNK_WIDTH * CHUNK_HEIGHT) as u64,
         approx_veins_per_chunk / (CHUNK_WIDTH * CHUNK_HEIGHT) as f64,
@@ -55,20 +78,78 @@ pub fn generate_random_vein_count(seed: u64, chunk_number: u64) -> u64 {
 }
 
 //Generates random vein with a random start coordinate, end coordinate, and thickness
-pub fn generate_random_vein(seed: u64, chunk_number: u64, vein_number: u64) -> Vein {
-    let mut rand = StdRng::seed_from_u64(generate_seed(seed, vec![chunk_number, vein_number]));
+//The ore comes from the rarity table of the biome the vein starts in, and picks the shape
+pub fn generate_random_vein(
+    seed: u64,
+    chunk_x: i64,
+    chunk_number: u64,
+    vein_number: u64,
+    biomes: &ChunkBiomes,
+) -> Vein {
+    let mut rand = StdRng::seed_from_u64(generate_seed(
+        seed,
+        vec![chunk_x as u64, chunk_number, vein_number],
+    ));
 
     // Generate random start coordinate
     let start_x = rand.gen_range(0..CHUNK_WIDTH);
     let start_y = rand.gen_range(0..CHUNK_HEIGHT);
 
+    let ore_type = ores::choose_ore(&biomes.at(start_y).ores, chunk_number, rand.gen());
+    let shape = *ore_type.vein_shapes().choose(&mut rand).unwrap();
+
     // End x can be left or right of start
     let end_x = (start_x as i16)
         + (rand.gen_range(10 as i16..32 as i16) * (if rand.gen_bool(0.5) { 1 } else { -1 }));
     // End y can only be below start (so you don't have a new vein that's supposed to go up to the previous chunk)
     let end_y = (start_y as i16) + rand.gen_range(5 as i16..16 as i16);
 
-    let thickness_sq: f32 = rand.gen_range(1.0..3.0);
+    let (thickness_sq, points) = match shape {
+        VeinShape::Line => (rand.gen_range(1.0..3.0), Vec::new()),
+        // a few overlapping circles, at most a few blocks from the start
+        VeinShape::Blob => {
+            let circles = rand.gen_range(2..5);
+            let points = (0..circles)
+                .map(|_| {
+                    (
+                        start_x as i16 + rand.gen_range(-3..=3),
+                        start_y as i16 + rand.gen_range(-2..=2),
+                    )
+                })
+                .collect();
+            (rand.gen_range(2.0..5.0), points)
+        }
+        // branches head off to either side, and down like the main line
+        VeinShape::Branching => {
+            let branches = rand.gen_range(1..4);
+            let points = (1..=branches)
+                .map(|branch| {
+                    let along = branch as f32 / (branches + 1) as f32;
+                    let from_x = start_x as f32 + (end_x as f32 - start_x as f32) * along;
+                    let from_y = start_y as f32 + (end_y as f32 - start_y as f32) * along;
+                    let side = if rand.gen_bool(0.5) { 1 } else { -1 };
+                    (
+                        from_x as i16 + rand.gen_range(4..10) * side,
+                        from_y as i16 + rand.gen_range(2..8),
+                    )
+                })
+                .collect();
+            (rand.gen_range(1.5..3.0), points)
+        }
+        // single blocks scattered around the start
+        VeinShape::Gems => {
+            let gems = rand.gen_range(3..8);
+            let points = (0..gems)
+                .map(|_| {
+                    (
+                        start_x as i16 + rand.gen_range(-6..=6),
+                        start_y as i16 + rand.gen_range(-2..=8),
+                    )
+                })
+                .collect();
+            (0., points)
+        }
+    };
 
     /* info!(
         "Generated vein from {},{} to {},{} in chunk {} with thickness_sq {}",
@@ -81,43 +162,29 @@ pub fn generate_random_vein(seed: u64, chunk_number: u64, vein_number: u64) -> V
     ); */
 
     Vein {
-        ore_type: OreType::Primary,
+        ore_type,
+        shape,
+        chunk_x,
         chunk_number,
         start_x,
         start_y,
         end_x,
         end_y,
         thickness_sq,
+        points,
     }
 }
 
-//Get the value (float) of a position X
-pub fn slice_pos_x(x: usize, r: &Vec<i32>) -> f32 {
-    //Do this so we can generate spaces between points
-    let x_float = (x as f32) / ((CHUNK_WIDTH / r.len()) + 1) as f32;
-
-    let x_int = x_float as u32;
-    let diff = x_float - (x_int as f32);
-
-    //Cubic curve
-    let u = diff * diff * (3.0 - 2.0 * diff);
-
-    //Interpolate + return
-    return (r[x_int as usize]) as f32 * (1.0f32 - u) + ((r[(x_int + 1) as usize]) as f32 * u);
-}
-
-fn dist_sq(x1: f32, y1: f32, x2: f32, y2: f32) -> f32 {
+pub fn dist_sq(x1: f32, y1: f32, x2: f32, y2: f32) -> f32 {
     ((x1 - x2).powf(2.0) + (y1 - y2).powf(2.0)).into()
 }
 
-pub fn dist_to_vein(vein: &Vein, x: f32, y: f32) -> f32 {
+/// Squared distance from a point to the line segment from start to end
+pub fn dist_to_segment(start: (f32, f32), end: (f32, f32), x: f32, y: f32) -> f32 {
     // Get distance from point to line segment
     // Adapted from https://stackoverflow.com/a/1501725/1474787
-    // Do all necessary casting first for readability's sake
-    let vx1 = vein.start_x as f32;
-    let vx2 = vein.end_x as f32;
-    let vy1 = vein.start_y as f32;
-    let vy2 = vein.end_y as f32;
+    let (vx1, vy1) = start;
+    let (vx2, vy2) = end;
 
     let len_sq = dist_sq(vx1, vy1, vx2, vy2);
     if len_sq == 0.0 {
@@ -131,96 +198,172 @@ pub fn dist_to_vein(vein: &Vein, x: f32, y: f32) -> f32 {
     dist_sq(x, y, vx1 + (proj * (vx2 - vx1)), vy1 + (proj * (vy2 - vy1)))
 }
 
-pub fn generate_chunk_biome_change(seed: u64, chunk_number: u64) -> Option<BiomeType> {
+/// Find the biome continuing down from the chunks above and the biome this row of chunks changes to
+pub fn generate_chunk_biomes(biomes: &BiomeTable, seed: u64, chunk_number: u64) -> ChunkBiomes<'_> {
+    // the surface chunk has its own biomes, the lower one continues down
+    if chunk_number == 0 {
+        return ChunkBiomes {
+            upper: biomes.biome(&biomes.surface.upper),
+            lower: biomes.biome(&biomes.surface.lower),
+            change_depth: biomes.surface.change_depth,
+        };
+    }
+
+    // get prev biome, the surface chunk always has one so the search stops there
+    let mut prev_biome_search: Option<&Biome> = None;
+    let mut curr_search_depth = chunk_number - 1;
+
+    while prev_biome_search.is_none() {
+        prev_biome_search = generate_chunk_biome_change(biomes, seed, curr_search_depth);
+        if curr_search_depth == 0 {
+            break; // can't put >= 0 in the while condititon since it's unsigned and that'll always be true
+        }
+        curr_search_depth -= 1;
+    }
+
+    let prev_biome = prev_biome_search.unwrap_or_else(|| biomes.biome(&biomes.surface.lower));
+
+    // Determine biome of chunk and whether there will be a biome change
+    let biome_change =
+        generate_chunk_biome_change(biomes, seed, chunk_number).unwrap_or(prev_biome);
+
+    let average_biome_change_depth =
+        generate_random_values(generate_seed(seed, vec![chunk_number, 432]), 1, 3, 10)[0] as usize;
+
+    ChunkBiomes {
+        upper: prev_biome,
+        lower: biome_change,
+        change_depth: average_biome_change_depth,
+    }
+}
+
+/// The biome a row of chunks changes to, or None if the one from above continues
+/// Picked with the weights of the transition for this depth
+pub fn generate_chunk_biome_change(
+    biomes: &BiomeTable,
+    seed: u64,
+    chunk_number: u64,
+) -> Option<&Biome> {
+    if chunk_number == 0 {
+        return Some(biomes.biome(&biomes.surface.lower));
+    }
+
     // 81043 is magic number to make biome-specific rand
     let mut rand = StdRng::seed_from_u64(generate_seed(seed, vec![chunk_number, 81043]));
 
-    let rnum: f32 = rand.gen();
+    let changes = &biomes.transition(chunk_number).changes;
+    let total: f32 = changes.iter().map(|(_, weight)| weight).sum();
+    let mut target = rand.gen::<f32>() * total;
 
-    // rules depend on depth
-    return if chunk_number == 0 {
-        Some(BiomeType::Sedimentary)
-    } else if chunk_number <= 3 {
-        if rnum < 0.7 {
-            None
-        } else {
-            Some(BiomeType::Basalt)
-        }
-    } else if chunk_number <= 5 {
-        if rnum < 0.8 {
-            Some(BiomeType::Basalt)
-        } else {
-            Some(BiomeType::Felsic)
-        }
-    } else if chunk_number <= 8 {
-        if rnum < 0.7 {
-            Some(BiomeType::Ultramafic)
-        } else if rnum < 0.8 {
-            None
-        } else if rnum < 0.9 {
-            Some(BiomeType::Basalt)
-        } else {
-            Some(BiomeType::Felsic)
+    // only falls through to the last change through float rounding
+    let mut picked = &changes.last().unwrap().0;
+    for (change, weight) in changes {
+        if target < *weight {
+            picked = change;
+            break;
         }
-    } else if chunk_number <= 10 {
-        if rnum < 0.4 {
-            Some(BiomeType::Ultramafic)
-        } else if rnum < 0.6 {
-            None
-        } else if rnum < 0.8 {
-            Some(BiomeType::Mafic)
-        } else if rnum < 0.9 {
-            Some(BiomeType::Basalt)
-        } else {
-            Some(BiomeType::Felsic)
-        }
-    } else {
-        if rnum < 0.7 {
-            Some(BiomeType::Ultramafic)
-        } else if rnum < 0.8 {
-            Some(BiomeType::Mafic)
-        } else if rnum < 0.9 {
-            Some(BiomeType::Felsic)
-        } else {
-            None
-        }
-    };
-}
+        target -= weight;
+    }
 
-pub fn generate_random_cave(seed: u64, chunk_number: u64) -> Cave {
-    let cave_map = generate_perlin_noise(chunk_number, seed);
+    match picked {
+        BiomeChange::Keep => None,
+        BiomeChange::To(name) => Some(biomes.biome(name)),
+    }
+}
 
-    return Cave {
+pub fn generate_random_cave(
+    biomes: &BiomeTable,
+    seed: u64,
+    chunk_x: i64,
+    chunk_number: u64,
+) -> Cave {
+    let mut cave = Cave {
         block_type: BlockType::CaveVoid,
+        chunk_x,
         chunk_number,
-        cave_map,
+        cave_map: [[false; CHUNK_WIDTH]; CHUNK_HEIGHT],
     };
-}
 
-pub fn generate_perlin_noise(chunk_number: u64, seed: u64) -> [[f32; CHUNK_WIDTH]; CHUNK_HEIGHT] {
-    let mut noise_map = [[0. as f32; CHUNK_WIDTH]; CHUNK_HEIGHT];
+    // no caves in the surface chunk
+    if chunk_number == 0 {
+        return cave;
+    }
+
+    // caverns: layered noise for the chunk and a margin around it,
+    // so that smoothing comes out the same on both sides of a chunk border
+    let margin = caves::CAVE_SMOOTHING_STEPS;
+    let left = chunk_x * CHUNK_WIDTH as i64 - margin as i64;
+    let top = (chunk_number as usize * CHUNK_HEIGHT - margin) as f32;
+
+    let tables: Vec<[usize; 512]> = (0..CAVE_OCTAVES)
+        .map(|octave| generate_perlin_hash_table(generate_seed(seed, vec![octave, 7431])))
+        .collect();
+
+    // the margin reaches into the rows of chunks above and below, which can have other biomes
+    let row_biomes: Vec<ChunkBiomes> = (chunk_number - 1..=chunk_number + 1)
+        .map(|n| ChunkBiomes::new(biomes, seed, n))
+        .collect();
+
+    let mut open = vec![vec![false; CHUNK_WIDTH + 2 * margin]; CHUNK_HEIGHT + 2 * margin];
+    for (grid_y, row) in open.iter_mut().enumerate() {
+        let world_y = top + grid_y as f32;
+        let biome = row_biomes[world_y as usize / CHUNK_HEIGHT + 1 - chunk_number as usize]
+            .at(world_y as usize % CHUNK_HEIGHT);
+        // denser biomes need less noise for a cavern
+        let threshold = caves::cave_threshold(world_y) / biome.cave_density;
+
+        for (grid_x, cell) in row.iter_mut().enumerate() {
+            let world_x = (left + grid_x as i64) as f32;
+            let n = generate_octave_noise(
+                &tables,
+                world_x / CHUNK_WIDTH as f32 * FREQUENCY,
+                world_y / CHUNK_HEIGHT as f32 * FREQUENCY,
+            );
+
+            // never open up the surface chunk from below
+            *cell = n > threshold && world_y >= CHUNK_HEIGHT as f32;
+        }
+    }
 
-    let p = generate_perlin_hash_table(seed);
+    caves::smooth_caves(&mut open);
 
-    for chunk_x in 0..CHUNK_WIDTH {
-        for chunk_y in 0..CHUNK_HEIGHT {
-            let phys_y = (chunk_number as usize * CHUNK_HEIGHT) + chunk_y;
+    for y in 0..CHUNK_HEIGHT {
+        for x in 0..CHUNK_WIDTH {
+            cave.cave_map[y][x] = open[y + margin][x + margin];
+        }
+    }
 
-            let x = chunk_x as f32 / CHUNK_WIDTH as f32;
-            let y = phys_y as f32 / CHUNK_HEIGHT as f32;
+    // tunnels between the caverns, which can come from the neighboring chunks
+    for tunnel in caves::Tunnel::near(seed, chunk_x, chunk_number) {
+        tunnel.carve(&mut cave);
+    }
 
-            let n = noise((x * FREQUENCY) as f32, (y * FREQUENCY) as f32, p);
+    cave
+}
 
-            noise_map[chunk_y][chunk_x] = n;
-        }
+/// Perlin noise with a few octaves layered on top of each other, one for each hash table
+/// Each octave has double the frequency and half the strength of the one before
+/// Stays roughly within [-1, 1]
+pub fn generate_octave_noise(tables: &[[usize; 512]], x: f32, y: f32) -> f32 {
+    let mut total = 0.;
+    let mut total_amplitude = 0.;
+    let mut amplitude = 1.;
+    let mut frequency = 1.;
+
+    for p in tables {
+        total += noise(x * frequency, y * frequency, *p) * amplitude;
+        total_amplitude += amplitude;
+        amplitude /= 2.;
+        frequency *= 2.;
     }
 
-    return noise_map;
+    total / total_amplitude
 }
 
 pub fn noise(x: f32, y: f32, p: [usize; 512]) -> f32 {
-    let xi = x.floor() as usize & 255;
-    let yi = y.floor() as usize & 255;
+    // go through i64 so that negative coordinates wrap around instead of clamping to 0
+    let xi = (x.floor() as i64 & 255) as usize;
+    let yi = (y.floor() as i64 & 255) as usize;
 
     let g1 = p[p[xi] + yi];
     let g2 = p[p[xi + 1] + yi];
@@ -2,22 +2,27 @@ use bevy::prelude::*;
 use bincode::{Decode, Encode};
 use iyes_loopless::prelude::*;
 use std::{
-    fs::{create_dir_all, read, File},
+    fs::{create_dir_all, read, read_to_string, rename, File},
     io::Write,
-    net::SocketAddr,
     path::{Path, PathBuf},
 };
 
 use crate::{
     args::ServerArgs,
-    network::{ClientAddress, BINCODE_CONFIG},
-    player::{Inventory, PlayerInput, PlayerPosition},
+    network::BINCODE_CONFIG,
+    player::{Health, Inventory, PlayerIdentity, PlayerInput, PlayerPosition},
     states,
-    world::Terrain,
+    world::{
+        server::unload_distant_chunks, ChunkStore, FallingBlocks, GeneratorType, Liquids,
+        PendingChunks, Terrain, WorldGenerator, WorldSeed, SPAWN_CHUNKS,
+    },
 };
 
 pub const DEFAULT_SAVE_DIR: &str = "savedata";
 pub const DEFAULT_SAVE_FILE_SERVER: &str = "server.sav";
+pub const DEFAULT_IDENTITY_FILE_CLIENT: &str = "identity.ron";
+/// Name for new players that didn't pick one
+pub const DEFAULT_PLAYER_NAME: &str = "Krab";
 
 pub fn default_save_path_server() -> PathBuf {
     Path::new(".")
@@ -25,6 +30,68 @@ pub fn default_save_path_server() -> PathBuf {
         .join(DEFAULT_SAVE_FILE_SERVER)
 }
 
+pub fn default_identity_path_client() -> PathBuf {
+    Path::new(".")
+        .join(DEFAULT_SAVE_DIR)
+        .join(DEFAULT_IDENTITY_FILE_CLIENT)
+}
+
+/// Load the player's identity, or make a new one the first time, so that servers
+/// give back the same player every time
+/// A name that is given replaces the saved one
+/// If the file can't be written the identity still works, but only until the game closes
+pub fn load_or_create_identity(path: &Path, name: Option<&str>) -> PlayerIdentity {
+    let saved = read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| ron::from_str::<PlayerIdentity>(&text).map_err(|e| e.to_string()));
+    let mut identity = match saved {
+        Ok(identity) => identity,
+        Err(e) => {
+            if path.exists() {
+                // don't write over it, maybe it can be fixed by hand
+                error!(
+                    "unable to read identity file, playing as someone new: {}",
+                    e
+                );
+                return PlayerIdentity {
+                    name: name.unwrap_or(DEFAULT_PLAYER_NAME).to_string(),
+                    key: rand::random(),
+                };
+            }
+            info!("making a new identity");
+            PlayerIdentity {
+                name: DEFAULT_PLAYER_NAME.to_string(),
+                key: rand::random(),
+            }
+        }
+    };
+    if let Some(name) = name {
+        identity.name = name.to_string();
+    }
+
+    let text = match ron::to_string(&identity) {
+        Ok(text) => text,
+        Err(e) => {
+            error!("unable to encode identity, {}", e);
+            return identity;
+        }
+    };
+    let written = path
+        .parent()
+        .map_or(Ok(()), create_dir_all)
+        .and_then(|()| File::create(path))
+        .and_then(|mut file| file.write_all(text.as_bytes()));
+    if let Err(e) = written {
+        error!("could not write identity file, {}", e);
+    }
+    identity
+}
+
+/// Where the chunks of a save file's world are kept, a folder of region files next to it
+pub fn chunk_store_path(save_file: &Path) -> PathBuf {
+    save_file.with_extension("regions")
+}
+
 pub mod server {
     use super::*;
 
@@ -41,6 +108,15 @@ pub mod server {
                     .run_in_state(states::server::GameState::Running)
                     .label("save_server"),
             );
+            // chunks are unloaded right after a save, which already wrote them to the chunk store
+            app.add_fixed_timestep_system(
+                "SAVE_INTERVAL",
+                0,
+                unload_distant_chunks
+                    .run_in_state(states::server::GameState::Running)
+                    .label("unload_distant_chunks")
+                    .after("save_server"),
+            );
 
             // load on start
             app.add_enter_system(
@@ -54,36 +130,79 @@ pub mod server {
 /// Helper struct to save and load players
 #[derive(Debug, Encode, Decode)]
 struct PlayerInFile {
-    addr: SocketAddr,
+    identity: PlayerIdentity,
     position: PlayerPosition,
     inventory: Inventory,
 }
 
 /// Struct that get serialized to save the world
+/// The chunks aren't in it, they are in the chunk store where they can be loaded one at a time
 #[derive(Debug, Encode)]
-pub struct SaveFile<'a> {
+pub struct SaveFile {
+    /// seed used to generate the terrain, so new chunks keep matching old ones
+    seed: WorldSeed,
+    /// generator used for the terrain, for the same reason
+    generator: GeneratorType,
     players: Vec<PlayerInFile>,
-    /// reference to the terrain resource
-    terrain: &'a Terrain,
 }
 
 /// Struct that gets created whenever we deserialize the save file
 #[derive(Debug, Decode)]
 pub struct LoadFile {
+    pub seed: WorldSeed,
+    pub generator: GeneratorType,
     players: Vec<PlayerInFile>,
-    /// owns a terrain that gets created from the file
-    terrain: Terrain,
+}
+
+/// Read and decode a save file, without loading it into the game
+pub fn read_save_file(path: &Path) -> Result<LoadFile, String> {
+    let encoded_vec = read(path).map_err(|e| format!("could not read save file, {}", e))?;
+    match bincode::decode_from_slice(&encoded_vec, BINCODE_CONFIG) {
+        Ok((load, _size)) => Ok(load),
+        Err(e) => Err(format!("unable to decode save file: {}", e)),
+    }
+}
+
+/// Write a save file next to the old one, then replace it
+/// so a save that fails partway through leaves the last one intact
+fn write_save_file(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    let mut temp_path = path.as_os_str().to_owned();
+    temp_path.push(".tmp");
+    let temp_path = PathBuf::from(temp_path);
+
+    let mut file =
+        File::create(&temp_path).map_err(|e| format!("could not create save file, {}", e))?;
+    file.write_all(bytes)
+        .map_err(|e| format!("could not write to save file, {}", e))?;
+    drop(file);
+    rename(&temp_path, path).map_err(|e| format!("could not replace save file, {}", e))
 }
 
 fn save_server(
-    terrain: Res<Terrain>,
-    query: Query<(&PlayerPosition, &ClientAddress, &Inventory)>,
+    mut terrain: ResMut<Terrain>,
+    store: Res<ChunkStore>,
+    seed: Res<WorldSeed>,
+    generator: Res<WorldGenerator>,
+    query: Query<(&PlayerPosition, &PlayerIdentity, &Inventory)>,
     args: Res<ServerArgs>,
 ) {
+    // only chunks that changed since the last save get written
+    // one that can't be written gets tried again next time, the rest still get saved
+    for (chunk_x, chunk_number) in terrain.dirty_chunks() {
+        let chunk = match terrain.find_chunk(chunk_x, chunk_number) {
+            Some(chunk) => chunk,
+            None => continue,
+        };
+        match store.save_chunk(chunk) {
+            Ok(()) => terrain.mark_saved(chunk_x, chunk_number),
+            Err(e) => error!("{}", e),
+        }
+    }
+
     let mut players_in_file = Vec::<PlayerInFile>::new();
-    for (position, addr, inv) in query.iter() {
+    for (position, identity, inv) in query.iter() {
         let player = PlayerInFile {
-            addr: addr.addr,
+            identity: identity.clone(),
             position: position.clone(),
             inventory: inv.clone(),
         };
@@ -91,8 +210,9 @@ fn save_server(
     }
 
     let save_file = SaveFile {
+        seed: *seed,
+        generator: generator.generator_type,
         players: players_in_file,
-        terrain: terrain.as_ref(),
     };
     // try to encode, allocating a vec
     // in a real packet, we should use a pre-allocated array and encode into its slice
@@ -105,24 +225,12 @@ fn save_server(
             }
             // else it was successful
 
-            // open file in write-mode
-            match File::create(&args.save_file) {
-                Ok(mut file) => {
-                    // write the bytes to file
-                    match file.write_all(&encoded_vec) {
-                        Ok(_) => {
-                            // info!("saved to file!"),
-                        }
-                        Err(e) => error!("could not write to save file, {}", e),
-                    }
-                }
-                Err(e) => {
-                    error!("could not create save file, {}", e);
-                }
+            if let Err(e) = write_save_file(&args.save_file, &encoded_vec) {
+                error!("{}", e);
             }
         }
         Err(e) => {
-            error!("unable to encode terrain, {}", e);
+            error!("unable to encode save file, {}", e);
         }
     }
 }
@@ -130,25 +238,67 @@ fn save_server(
 /// Load the file
 fn load_server(
     mut commands: Commands,
-    players: Query<Entity, With<ClientAddress>>,
+    players: Query<Entity, With<PlayerIdentity>>,
     args: Res<ServerArgs>,
 ) {
-    match read(&args.save_file) {
-        Ok(encoded_vec) => {
-            // try to load the world and player
-            let decoded: LoadFile = match bincode::decode_from_slice(&encoded_vec, BINCODE_CONFIG) {
-                Ok((load, _size)) => load,
+    let store = ChunkStore::new(chunk_store_path(&args.save_file));
+
+    // try to load the world and player
+    match read_save_file(&args.save_file) {
+        Ok(decoded) => {
+            // the saved world keeps its own seed
+            if let Some(seed) = args.seed {
+                if seed != decoded.seed.seed {
+                    warn!(
+                        "ignoring seed {} from arguments, save file uses seed {}",
+                        seed, decoded.seed.seed
+                    );
+                }
+            }
+            info!("world seed is {}", decoded.seed.seed);
+            commands.insert_resource(decoded.seed);
+
+            // same for the generator
+            if args.generator != decoded.generator {
+                warn!(
+                    "ignoring generator {:?} from arguments, save file uses generator {:?}",
+                    args.generator, decoded.generator
+                );
+            }
+            info!("world generator is {:?}", decoded.generator);
+            let generator = match WorldGenerator::new(decoded.generator) {
+                Ok(generator) => generator,
                 Err(e) => {
-                    error!("unable to decode save file: {}", e);
-                    return;
+                    error!("unable to create world generator: {}", e);
+                    std::process::exit(1);
                 }
             };
 
+            // chunks that were being generated for the old world aren't needed anymore
+            commands.insert_resource(PendingChunks::default());
+
             // delete old terrain
             commands.remove_resource::<Terrain>();
 
-            // insert new terrain
-            commands.insert_resource(decoded.terrain);
+            // insert new terrain with only the spawn chunks in it, which new players need right away
+            // the rest get loaded from the chunk store as players get near them
+            let terrain: Terrain = SPAWN_CHUNKS
+                .into_iter()
+                .map(|(chunk_x, chunk_number)| {
+                    store.load_or_generate(
+                        generator.generator.as_ref(),
+                        decoded.seed.seed,
+                        chunk_x,
+                        chunk_number,
+                    )
+                })
+                .collect();
+            commands.insert_resource(terrain);
+            commands.insert_resource(generator);
+
+            // search the new terrain for liquids that were still flowing
+            commands.insert_resource(Liquids::default());
+            commands.insert_resource(FallingBlocks::default());
 
             // delete all player entities
             for entity in players.iter() {
@@ -163,17 +313,67 @@ fn load_server(
             warn!("loaded from file!");
         }
         Err(e) => {
-            error!("could not read save file, {}", e);
+            error!("{}", e);
+
+            // starting over would mix a new world into the old world's chunks, or overwrite
+            // the save file and lose its players, so leave both for someone to look at
+            if args.save_file.exists() {
+                error!(
+                    "save file {:?} exists but can't be loaded, move it and {:?} aside to start a new world",
+                    args.save_file,
+                    chunk_store_path(&args.save_file)
+                );
+                std::process::exit(1);
+            }
+
+            // chunks unloaded from an old world that had its save file deleted don't belong
+            // in the new one
+            if !store.positions().is_empty() {
+                warn!("removing chunks left over from a deleted save file");
+                if let Err(e) = store.clear() {
+                    error!("{}", e);
+                }
+            }
         }
     }
 }
 
 /// Spawn in a previously-connected player (from a file)
+/// It gets a ClientAddress once its client joins again
 fn spawn_player(commands: &mut Commands, player: &PlayerInFile) {
     commands
         .spawn()
-        .insert(ClientAddress { addr: player.addr })
+        .insert(player.identity.clone())
         .insert(player.position.clone())
         .insert(PlayerInput::default())
+        .insert(Health::default())
         .insert(player.inventory.clone());
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identities_are_kept_between_runs() {
+        let dir = std::env::temp_dir().join(format!("krabs-identity-{}", rand::random::<u64>()));
+        let path = dir.join(DEFAULT_IDENTITY_FILE_CLIENT);
+
+        let first = load_or_create_identity(&path, None);
+        assert_eq!(first.name, DEFAULT_PLAYER_NAME);
+        assert_eq!(load_or_create_identity(&path, None), first);
+
+        // a new name keeps the key, and is remembered
+        let renamed = load_or_create_identity(&path, Some("Ferris"));
+        assert_eq!(renamed.key, first.key);
+        assert_eq!(renamed.name, "Ferris");
+        assert_eq!(load_or_create_identity(&path, None), renamed);
+
+        // a broken file isn't thrown away
+        std::fs::write(&path, "not an identity").unwrap();
+        assert_ne!(load_or_create_identity(&path, None).key, first.key);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not an identity");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
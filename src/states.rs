@@ -1,4 +1,4 @@
-use bevy::prelude::*;
+use bevy::{app::AppExit, prelude::*};
 use iyes_loopless::prelude::*;
 
 pub mod server {
@@ -81,10 +81,10 @@ pub mod client {
     }
 }
 
-/// Immediately end the process
-fn ctrl_q_quit(input: Res<Input<KeyCode>>) {
+/// Close the game at the end of this frame
+fn ctrl_q_quit(input: Res<Input<KeyCode>>, mut exit: EventWriter<AppExit>) {
     if input.pressed(KeyCode::Q) && input.pressed(KeyCode::LControl) {
         warn!("ctrl-Q detected -- exiting!");
-        std::process::exit(0);
+        exit.send(AppExit);
     }
 }
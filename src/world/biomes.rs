@@ -0,0 +1,287 @@
+use bevy::prelude::*;
+use serde::Deserialize;
+use std::{collections::HashSet, fs, path::Path};
+
+use super::*;
+use crate::procedural_functions::generate_chunk_biomes;
+
+/// Where the biome definitions are loaded from
+pub const BIOMES_FILE: &str = "assets/biomes.ron";
+
+/// A layer of rock that the world is made of
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Biome {
+    pub name: String,
+    /// The block that everything other than ores is made of
+    pub primary_block: BlockType,
+    /// How much of the biome is hollowed out into caverns, 1 is normal
+    #[serde(default = "normal_cave_density")]
+    pub cave_density: f32,
+    /// Which ores veins in this biome can be made of, and how common each is
+    pub ores: Vec<OreRarity>,
+}
+
+fn normal_cave_density() -> f32 {
+    1.
+}
+
+/// What happens to the biome going down into a new row of chunks
+#[derive(Deserialize, Debug, Clone, PartialEq, Eq)]
+pub enum BiomeChange {
+    /// The biome from the chunks above continues
+    Keep,
+    /// Change to the biome with this name
+    To(String),
+}
+
+/// How likely each biome change is, for a range of chunk numbers
+#[derive(Deserialize, Debug, Clone)]
+pub struct Transition {
+    pub min_depth: u64,
+    /// Deepest chunk number, no limit if not given
+    #[serde(default)]
+    pub max_depth: Option<u64>,
+    /// Changes with their weights, in the order they get picked from
+    pub changes: Vec<(BiomeChange, f32)>,
+}
+
+impl Transition {
+    fn covers(&self, chunk_number: u64) -> bool {
+        chunk_number >= self.min_depth && chunk_number <= self.max_depth.unwrap_or(u64::MAX)
+    }
+}
+
+/// The biomes of the surface chunks, which don't get picked randomly
+#[derive(Deserialize, Debug, Clone)]
+pub struct SurfaceBiomes {
+    pub upper: String,
+    pub lower: String,
+    pub change_depth: usize,
+}
+
+/// Every biome, and the rules for which biome goes where
+#[derive(Deserialize, Debug, Clone)]
+pub struct BiomeTable {
+    pub surface: SurfaceBiomes,
+    pub biomes: Vec<Biome>,
+    pub transitions: Vec<Transition>,
+}
+
+impl BiomeTable {
+    /// Parse biome definitions and make sure they make sense
+    pub fn from_ron(text: &str) -> Result<Self, String> {
+        let mut table: BiomeTable = ron::from_str(text).map_err(|e| e.to_string())?;
+        table.transitions.sort_by_key(|t| t.min_depth);
+        table.validate()?;
+        Ok(table)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.biomes.is_empty() {
+            return Err("there are no biomes".to_string());
+        }
+
+        let mut names = HashSet::new();
+        for biome in &self.biomes {
+            if !names.insert(biome.name.as_str()) {
+                return Err(format!("biome {} is defined twice", biome.name));
+            }
+            if !biome.primary_block.is_real_block() {
+                return Err(format!(
+                    "biome {} can't be made of {:?}",
+                    biome.name, biome.primary_block
+                ));
+            }
+            if biome.cave_density <= 0. {
+                return Err(format!("biome {} needs a cave_density above 0", biome.name));
+            }
+
+            // the weights are positive at every depth if they are at both ends
+            if biome
+                .ores
+                .iter()
+                .any(|r| r.shallow_weight < 0. || r.deep_weight < 0.)
+            {
+                return Err(format!("biome {} has a negative ore weight", biome.name));
+            }
+            let shallow: f32 = biome.ores.iter().map(|r| r.shallow_weight).sum();
+            let deep: f32 = biome.ores.iter().map(|r| r.deep_weight).sum();
+            if shallow <= 0. || deep <= 0. {
+                return Err(format!(
+                    "biome {} needs ores at every depth, shallow and deep",
+                    biome.name
+                ));
+            }
+        }
+
+        let known = |name: &str| -> Result<(), String> {
+            match names.contains(name) {
+                true => Ok(()),
+                false => Err(format!("there is no biome called {}", name)),
+            }
+        };
+        known(&self.surface.upper)?;
+        known(&self.surface.lower)?;
+        if self.surface.change_depth >= CHUNK_HEIGHT {
+            return Err("surface change_depth is below the surface chunk".to_string());
+        }
+
+        // transitions are sorted, so they must follow each other with no gaps from chunk 1 down
+        let mut next_depth = Some(1);
+        for transition in &self.transitions {
+            if Some(transition.min_depth) != next_depth {
+                return Err(format!(
+                    "transitions don't cover chunk {} exactly once",
+                    next_depth.unwrap_or(transition.min_depth)
+                ));
+            }
+            if transition.max_depth.unwrap_or(u64::MAX) < transition.min_depth {
+                return Err(format!(
+                    "transition from chunk {} ends above where it starts",
+                    transition.min_depth
+                ));
+            }
+            next_depth = transition.max_depth.map(|depth| depth + 1);
+
+            if transition.changes.iter().any(|(_, weight)| *weight < 0.)
+                || transition
+                    .changes
+                    .iter()
+                    .map(|(_, weight)| weight)
+                    .sum::<f32>()
+                    <= 0.
+            {
+                return Err(format!(
+                    "transition from chunk {} needs positive weights",
+                    transition.min_depth
+                ));
+            }
+            for (change, _) in &transition.changes {
+                if let BiomeChange::To(name) = change {
+                    known(name)?;
+                }
+            }
+        }
+        if let Some(depth) = next_depth {
+            return Err(format!("no transition covers chunk {}", depth));
+        }
+
+        Ok(())
+    }
+
+    /// The biome with a name, which has to exist
+    pub fn biome(&self, name: &str) -> &Biome {
+        self.biomes
+            .iter()
+            .find(|biome| biome.name == name)
+            .unwrap_or_else(|| panic!("there is no biome called {}", name))
+    }
+
+    /// The biome change rules for a row of chunks below the surface
+    pub fn transition(&self, chunk_number: u64) -> &Transition {
+        self.transitions
+            .iter()
+            .find(|t| t.covers(chunk_number))
+            .expect("transitions cover every chunk below the surface")
+    }
+}
+
+/// Load the biome definitions, or say what is wrong with them
+pub fn load_biomes(path: &Path) -> Result<BiomeTable, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{:?}: {}", path, e))?;
+    let table = BiomeTable::from_ron(&text).map_err(|e| format!("{:?}: {}", path, e))?;
+    info!("loaded {} biome(s)", table.biomes.len());
+    Ok(table)
+}
+
+/// The biomes from the asset file, for tests that need a real table
+#[cfg(test)]
+pub fn test_table() -> BiomeTable {
+    load_biomes(Path::new(BIOMES_FILE)).unwrap()
+}
+
+/// The biomes in a row of chunks
+/// The biome from the chunk above continues down to around change_depth, then the new one starts
+#[derive(Debug, Clone, Copy)]
+pub struct ChunkBiomes<'a> {
+    pub upper: &'a Biome,
+    pub lower: &'a Biome,
+    pub change_depth: usize,
+}
+
+impl<'a> ChunkBiomes<'a> {
+    pub fn new(biomes: &'a BiomeTable, seed: u64, chunk_number: u64) -> Self {
+        generate_chunk_biomes(biomes, seed, chunk_number)
+    }
+
+    /// The biome around a row of a chunk
+    pub fn at(&self, y: usize) -> &'a Biome {
+        if y >= self.change_depth {
+            self.lower
+        } else {
+            self.upper
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_SEED: u64 = 77144010;
+
+    fn biomes_text() -> String {
+        fs::read_to_string(BIOMES_FILE).unwrap()
+    }
+
+    #[test]
+    fn biome_asset_loads() {
+        let table = load_biomes(Path::new(BIOMES_FILE)).unwrap();
+        assert_eq!(
+            table.biome(&table.surface.upper).primary_block,
+            BlockType::Sand
+        );
+    }
+
+    #[test]
+    fn bad_biomes_are_rejected() {
+        let text = biomes_text();
+
+        let unknown_biome = text.replace(r#"To("mafic")"#, r#"To("marble")"#);
+        assert!(BiomeTable::from_ron(&unknown_biome).is_err());
+
+        let gap = text.replace("min_depth: 4,", "min_depth: 5,");
+        assert!(BiomeTable::from_ron(&gap).is_err());
+
+        let no_ores = text.replace(
+            "(ore: Clay, shallow_weight: 10.0, deep_weight: 10.0),",
+            "(ore: Clay, shallow_weight: 0.0, deep_weight: 10.0),",
+        );
+        let no_ores = no_ores.replace(
+            "(ore: Coal, shallow_weight: 3.0, deep_weight: 3.0),",
+            "(ore: Coal, shallow_weight: 0.0, deep_weight: 3.0),",
+        );
+        assert!(BiomeTable::from_ron(&no_ores).is_err());
+
+        let void = text.replace("primary_block: Sand,", "primary_block: CaveVoid,");
+        assert!(BiomeTable::from_ron(&void).is_err());
+
+        assert!(BiomeTable::from_ron(&text).is_ok());
+    }
+
+    #[test]
+    fn biomes_continue_down() {
+        let table = load_biomes(Path::new(BIOMES_FILE)).unwrap();
+
+        let surface = ChunkBiomes::new(&table, TEST_SEED, 0);
+        assert_eq!(surface.upper.name, table.surface.upper);
+        assert_eq!(surface.lower.name, table.surface.lower);
+
+        // the top of every row of chunks is the bottom of the one above
+        for chunk_number in 1..20 {
+            let above = ChunkBiomes::new(&table, TEST_SEED, chunk_number - 1);
+            let biomes = ChunkBiomes::new(&table, TEST_SEED, chunk_number);
+            assert_eq!(biomes.upper, above.lower, "chunk {}", chunk_number);
+        }
+    }
+}
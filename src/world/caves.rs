@@ -0,0 +1,358 @@
+use bincode::{Decode, Encode};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::f32::consts::PI;
+
+use super::*;
+use crate::procedural_functions::{dist_sq, generate_random_cave, generate_seed};
+
+/// How many chunks down caves reach their deep density
+pub const DEEP_CAVE_DEPTH: u64 = 8;
+
+/// Noise needs to be above this for a cavern, near the surface and at DEEP_CAVE_DEPTH
+/// Increase for smaller caves
+/// Decrease for bigger caves
+const SHALLOW_CAVE_THRESHOLD: f32 = 0.32;
+const DEEP_CAVE_THRESHOLD: f32 = 0.18;
+
+/// How many times caverns get smoothed, each step needs one more block of noise around the chunk
+pub const CAVE_SMOOTHING_STEPS: usize = 3;
+
+/// Chance that a tunnel joins a cave node to the next one to the right, near the surface and deep
+const SHALLOW_SIDE_TUNNEL_CHANCE: f32 = 0.5;
+const DEEP_SIDE_TUNNEL_CHANCE: f32 = 0.9;
+/// Chance that a tunnel joins a cave node to the next one below, near the surface and deep
+const SHALLOW_DOWN_TUNNEL_CHANCE: f32 = 0.2;
+const DEEP_DOWN_TUNNEL_CHANCE: f32 = 0.5;
+
+/// How much tunnels wind around on their way, in radians
+const TUNNEL_WOBBLE: f32 = 0.8;
+/// Size of the room carved around every cave node that has tunnels
+const CAVE_NODE_RADIUS: f32 = 3.;
+
+/// The open spaces in a chunk
+/// Caverns come from layered noise smoothed out with cellular automata,
+/// tunnels wind between cave nodes and cross into the neighboring chunks
+#[derive(Encode, Decode, Debug, PartialEq, Clone)]
+pub struct Cave {
+    pub block_type: BlockType,
+    pub chunk_x: i64,
+    pub chunk_number: u64,
+    /// true where there is no block
+    pub cave_map: [[bool; CHUNK_WIDTH]; CHUNK_HEIGHT],
+}
+
+impl Cave {
+    pub fn new(biomes: &BiomeTable, seed: u64, chunk_x: i64, chunk_number: u64) -> Self {
+        generate_random_cave(biomes, seed, chunk_x, chunk_number)
+    }
+
+    pub fn is_open(&self, x: usize, y: usize) -> bool {
+        self.cave_map[y][x]
+    }
+}
+
+/// How far between shallow (0) and deep (1) a row of blocks is, for cave density
+/// The surface chunk has no caves, so depth starts counting below it
+fn deepness(world_y: f32) -> f32 {
+    let depth = world_y / CHUNK_HEIGHT as f32 - 1.;
+    (depth / DEEP_CAVE_DEPTH as f32).clamp(0., 1.)
+}
+
+/// What the layered noise needs to be above for a cavern at some row of blocks
+pub fn cave_threshold(world_y: f32) -> f32 {
+    let deepness = deepness(world_y);
+    SHALLOW_CAVE_THRESHOLD * (1. - deepness) + DEEP_CAVE_THRESHOLD * deepness
+}
+
+/// Smooth out caverns with cellular automata, so they have round walls and few single-block holes
+/// Cells on the edge of the map only see part of their neighbors,
+/// so each step leaves one more row of cells around the edge unreliable
+pub fn smooth_caves(open: &mut [Vec<bool>]) {
+    let height = open.len();
+    let width = open[0].len();
+
+    for _step in 0..CAVE_SMOOTHING_STEPS {
+        let before = open.to_vec();
+        for y in 0..height {
+            for x in 0..width {
+                // the 3x3 square around the cell, minus the cell itself
+                let open_neighbors = before[y.saturating_sub(1)..=(y + 1).min(height - 1)]
+                    .iter()
+                    .flat_map(|row| &row[x.saturating_sub(1)..=(x + 1).min(width - 1)])
+                    .filter(|open| **open)
+                    .count()
+                    - before[y][x] as usize;
+
+                // open cells stay open with half of their neighbors open, walls need more
+                open[y][x] = open_neighbors > 4 || (open_neighbors == 4 && before[y][x]);
+            }
+        }
+    }
+}
+
+/// The middle of the cave system of a chunk, in world block coordinates
+/// Tunnels run from node to node, so chunks with tunnels are joined to their neighbors
+pub fn cave_node(seed: u64, chunk_x: i64, chunk_number: u64) -> (f32, f32) {
+    let mut rand = StdRng::seed_from_u64(generate_seed(
+        seed,
+        vec![chunk_x as u64, chunk_number, 5741],
+    ));
+
+    let x = rand.gen_range(16..CHUNK_WIDTH - 16) as i64 + chunk_x * CHUNK_WIDTH as i64;
+    let y = rand.gen_range(12..CHUNK_HEIGHT - 12) + chunk_number as usize * CHUNK_HEIGHT;
+    (x as f32, y as f32)
+}
+
+/// A winding tunnel between two cave nodes, stored as the circles that carve it out
+#[derive(Debug, PartialEq, Clone)]
+pub struct Tunnel {
+    /// (x, y, radius) in world block coordinates
+    pub circles: Vec<(f32, f32, f32)>,
+}
+
+impl Tunnel {
+    /// Tunnel from the cave node of a chunk to the one to the right (or below), if there is one
+    /// There are no tunnels in or to the surface chunk
+    pub fn new(seed: u64, chunk_x: i64, chunk_number: u64, down: bool) -> Option<Tunnel> {
+        if chunk_number == 0 {
+            return None;
+        }
+
+        let mut rand = StdRng::seed_from_u64(generate_seed(
+            seed,
+            vec![chunk_x as u64, chunk_number, down as u64, 5742],
+        ));
+
+        let start = cave_node(seed, chunk_x, chunk_number);
+        let deepness = deepness(start.1);
+        let (end, chance) = if down {
+            (
+                cave_node(seed, chunk_x, chunk_number + 1),
+                SHALLOW_DOWN_TUNNEL_CHANCE * (1. - deepness) + DEEP_DOWN_TUNNEL_CHANCE * deepness,
+            )
+        } else {
+            (
+                cave_node(seed, chunk_x + 1, chunk_number),
+                SHALLOW_SIDE_TUNNEL_CHANCE * (1. - deepness) + DEEP_SIDE_TUNNEL_CHANCE * deepness,
+            )
+        };
+
+        if rand.gen::<f32>() >= chance {
+            return None;
+        }
+
+        // the worm heads for the end, turning back and forth on the way
+        let wobble_speed = rand.gen_range(0.05..0.2);
+        let wobble_phase = rand.gen_range(0.0..2. * PI);
+        let width_phase = rand.gen_range(0.0..2. * PI);
+
+        let mut circles = vec![(start.0, start.1, CAVE_NODE_RADIUS)];
+        let (mut x, mut y) = start;
+        let max_steps = (dist_sq(start.0, start.1, end.0, end.1).sqrt() * 3.) as usize;
+        for step in 0..max_steps {
+            if dist_sq(x, y, end.0, end.1) < 1. {
+                break;
+            }
+
+            let angle = (end.1 - y).atan2(end.0 - x)
+                + TUNNEL_WOBBLE * (step as f32 * wobble_speed + wobble_phase).sin();
+            x += angle.cos();
+            y += angle.sin();
+
+            // wide enough for a player everywhere
+            let radius = 1.8 + 0.5 * (step as f32 * 0.1 + width_phase).sin();
+            circles.push((x, y, radius));
+        }
+        circles.push((end.0, end.1, CAVE_NODE_RADIUS));
+
+        Some(Tunnel { circles })
+    }
+
+    /// All tunnels that could reach into a chunk
+    /// Tunnels never wander more than a chunk away from where they start,
+    /// so only tunnels starting in the surrounding chunks need to be checked
+    pub fn near(seed: u64, chunk_x: i64, chunk_number: u64) -> Vec<Tunnel> {
+        let mut tunnels = Vec::new();
+        for tunnel_chunk_x in (chunk_x - 1)..=(chunk_x + 1) {
+            for tunnel_chunk_number in chunk_number.saturating_sub(1)..=(chunk_number + 1) {
+                for down in [false, true] {
+                    if let Some(tunnel) =
+                        Tunnel::new(seed, tunnel_chunk_x, tunnel_chunk_number, down)
+                    {
+                        tunnels.push(tunnel);
+                    }
+                }
+            }
+        }
+        tunnels
+    }
+
+    /// Open up the blocks of a cave map that this tunnel goes through
+    pub fn carve(&self, cave: &mut Cave) {
+        let left = cave.chunk_x * CHUNK_WIDTH as i64;
+        let top = (cave.chunk_number as usize * CHUNK_HEIGHT) as i64;
+
+        for (circle_x, circle_y, radius) in &self.circles {
+            let reach = radius.ceil() as i64;
+            let (center_x, center_y) = (circle_x.round() as i64, circle_y.round() as i64);
+
+            for world_y in (center_y - reach)..=(center_y + reach) {
+                for world_x in (center_x - reach)..=(center_x + reach) {
+                    let (x, y) = (world_x - left, world_y - top);
+                    if x < 0 || y < 0 || x >= CHUNK_WIDTH as i64 || y >= CHUNK_HEIGHT as i64 {
+                        continue;
+                    }
+                    if dist_sq(world_x as f32, world_y as f32, *circle_x, *circle_y)
+                        <= radius * radius
+                    {
+                        cave.cave_map[y as usize][x as usize] = true;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{
+        biomes::{test_table, BIOMES_FILE},
+        TEST_SEED,
+    };
+    use std::fs;
+
+    /// How much of a chunk is cave
+    fn open_fraction(cave: &Cave) -> f32 {
+        let open = cave.cave_map.iter().flatten().filter(|open| **open).count();
+        open as f32 / (CHUNK_WIDTH * CHUNK_HEIGHT) as f32
+    }
+
+    #[test]
+    fn caves_depend_only_on_seed_and_position() {
+        let biomes = test_table();
+        assert_eq!(
+            Cave::new(&biomes, TEST_SEED, -1, 3),
+            Cave::new(&biomes, TEST_SEED, -1, 3)
+        );
+        assert_ne!(
+            Cave::new(&biomes, TEST_SEED, -1, 3),
+            Cave::new(&biomes, TEST_SEED + 1, -1, 3)
+        );
+        assert_eq!(Tunnel::near(TEST_SEED, 4, 2), Tunnel::near(TEST_SEED, 4, 2));
+    }
+
+    #[test]
+    fn surface_chunk_has_no_caves() {
+        let biomes = test_table();
+        assert_eq!(open_fraction(&Cave::new(&biomes, TEST_SEED, 0, 0)), 0.);
+    }
+
+    #[test]
+    fn deeper_chunks_have_more_caves() {
+        let biomes = test_table();
+        let average_open = |chunk_number| {
+            (-4..4)
+                .map(|chunk_x| open_fraction(&Cave::new(&biomes, TEST_SEED, chunk_x, chunk_number)))
+                .sum::<f32>()
+                / 8.
+        };
+        let shallow = average_open(1);
+        let deep = average_open(DEEP_CAVE_DEPTH + 1);
+        assert!(shallow > 0.01, "hardly any caves: {}", shallow);
+        assert!(deep > shallow, "deep {} vs shallow {}", deep, shallow);
+        assert!(deep < 0.6, "deep chunks are mostly cave: {}", deep);
+    }
+
+    #[test]
+    fn denser_biomes_have_more_caves() {
+        let normal = test_table();
+        let dense = BiomeTable::from_ron(
+            &fs::read_to_string(BIOMES_FILE)
+                .unwrap()
+                .replace("cave_density: 1.0", "cave_density: 2.0"),
+        )
+        .unwrap();
+
+        // the first chunk below the surface is always the surface's lower biome
+        let open = |biomes| open_fraction(&Cave::new(biomes, TEST_SEED, 2, 1));
+        assert!(open(&dense) > open(&normal));
+    }
+
+    #[test]
+    fn smoothing_removes_lone_blocks() {
+        let mut open = vec![vec![true; 9]; 9];
+        open[4][4] = false;
+        open[0][0] = false;
+        smooth_caves(&mut open);
+        assert!(open[4][4]);
+
+        let mut closed = vec![vec![false; 9]; 9];
+        closed[4][4] = true;
+        smooth_caves(&mut closed);
+        assert!(!closed[4][4]);
+    }
+
+    #[test]
+    fn tunnels_connect_across_chunk_borders() {
+        // find a tunnel between two chunks next to each other
+        let chunk_number = DEEP_CAVE_DEPTH;
+        let chunk_x = (0..64)
+            .find(|x| Tunnel::new(TEST_SEED, *x, chunk_number, false).is_some())
+            .expect("no side tunnels at all");
+
+        // the two chunks and the ones around them, as one big grid
+        let columns = 4;
+        let rows = 3;
+        let biomes = test_table();
+        let mut open = vec![vec![false; CHUNK_WIDTH * columns]; CHUNK_HEIGHT * rows];
+        for column in 0..columns {
+            for row in 0..rows {
+                let cave = Cave::new(
+                    &biomes,
+                    TEST_SEED,
+                    chunk_x - 1 + column as i64,
+                    chunk_number - 1 + row as u64,
+                );
+                for y in 0..CHUNK_HEIGHT {
+                    for x in 0..CHUNK_WIDTH {
+                        open[row * CHUNK_HEIGHT + y][column * CHUNK_WIDTH + x] = cave.is_open(x, y);
+                    }
+                }
+            }
+        }
+
+        // grid position of a cave node
+        let to_grid = |(x, y): (f32, f32)| {
+            (
+                (x as i64 - (chunk_x - 1) * CHUNK_WIDTH as i64) as usize,
+                (y as usize - (chunk_number as usize - 1) * CHUNK_HEIGHT),
+            )
+        };
+        let start = to_grid(cave_node(TEST_SEED, chunk_x, chunk_number));
+        let end = to_grid(cave_node(TEST_SEED, chunk_x + 1, chunk_number));
+
+        // flood fill from one node and make sure we get to the other one
+        let mut reached = vec![vec![false; CHUNK_WIDTH * columns]; CHUNK_HEIGHT * rows];
+        let mut to_visit = vec![start];
+        while let Some((x, y)) = to_visit.pop() {
+            if reached[y][x] || !open[y][x] {
+                continue;
+            }
+            reached[y][x] = true;
+            if x > 0 {
+                to_visit.push((x - 1, y));
+            }
+            if y > 0 {
+                to_visit.push((x, y - 1));
+            }
+            if x + 1 < CHUNK_WIDTH * columns {
+                to_visit.push((x + 1, y));
+            }
+            if y + 1 < CHUNK_HEIGHT * rows {
+                to_visit.push((x, y + 1));
+            }
+        }
+        assert!(reached[end.1][end.0]);
+    }
+}
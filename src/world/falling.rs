@@ -0,0 +1,309 @@
+use std::collections::HashSet;
+
+use super::{
+    liquids::{block_at, set_block_at},
+    *,
+};
+use crate::{network::server::ConnectedClientInfo, player::Health};
+
+/// How many game ticks a falling block takes to fall one block
+const FALL_TICKS: u64 = 3;
+/// Damage a player takes each time a falling block can't push them out of the way
+const CRUSH_DAMAGE: f32 = 10.;
+/// How far apart a player and a block have to be to not touch, a little under a block
+/// so that players standing right next to a block aren't in its way
+const TOUCH_DISTANCE: f32 = 0.99;
+
+/// Resource on the server that keeps track of blocks that might fall
+/// Only blocks that lost what was under them get checked, so a block can hang in the air
+/// until something next to it changes
+#[derive(Default)]
+pub struct FallingBlocks {
+    /// World positions (x, y) of blocks to check, they don't have to fall anymore
+    active: HashSet<(i64, usize)>,
+    /// Game ticks since blocks started falling
+    tick: u64,
+}
+
+impl FallingBlocks {
+    /// Check the block above a block that was removed, so that it can fall into the hole
+    pub fn wake_above(&mut self, x: i64, y: usize) {
+        if y > 0 {
+            self.active.insert((x, y - 1));
+        }
+    }
+
+    /// Advance the falling blocks by one game tick, moving them down a block if they are due to
+    /// Players in the way get pushed down, or crushed if there is nowhere to push them to
+    pub fn step(
+        &mut self,
+        terrain: &mut Terrain,
+        liquids: &mut Liquids,
+        players: &mut [(&mut PlayerPosition, &mut Health)],
+    ) {
+        self.tick += 1;
+        if !self.tick.is_multiple_of(FALL_TICKS) {
+            return;
+        }
+
+        // bottom rows first, so that the blocks under a block are out of the way
+        let mut positions: Vec<(i64, usize)> = self.active.drain().collect();
+        positions.sort_by_key(|(x, y)| (std::cmp::Reverse(*y), *x));
+
+        // blocks that fell into this position this tick, which shouldn't move again until the next one
+        let mut arrived = HashSet::new();
+
+        for (x, y) in positions {
+            if arrived.contains(&(x, y)) || !self.fall(terrain, liquids, players, x, y) {
+                continue;
+            }
+            arrived.insert((x, y + 1));
+
+            // the whole column on top of it comes down together
+            let mut top = y;
+            while top > 0 && self.fall(terrain, liquids, players, x, top - 1) {
+                top -= 1;
+                arrived.insert((x, top + 1));
+            }
+        }
+    }
+
+    /// Move the block at (x, y) down one block if it falls and nothing holds it up
+    /// Returns true if it moved
+    fn fall(
+        &mut self,
+        terrain: &mut Terrain,
+        liquids: &mut Liquids,
+        players: &mut [(&mut PlayerPosition, &mut Health)],
+        x: i64,
+        y: usize,
+    ) -> bool {
+        match block_at(terrain, x, y) {
+            Some(Some(block_type)) if block_type.falls() => {}
+            _ => return false,
+        }
+
+        // liquid under it swaps places with it, anything else holds it up
+        // chunks that aren't loaded hold it up too
+        let below = match block_at(terrain, x, y + 1) {
+            Some(below) if below.is_none_or(|b| b.is_liquid()) => below,
+            _ => return false,
+        };
+
+        if !make_room(terrain, players, x, y + 1) {
+            // try again next time
+            self.active.insert((x, y));
+            return false;
+        }
+
+        // the whole block moves, so it keeps its state (like any damage from mining)
+        let block = terrain.get_block((x, y).into()).flatten();
+        set_block_at(terrain, x, y, below);
+        terrain.set_block((x, y + 1).into(), block);
+        liquids.wake_around(x, y);
+        liquids.wake_around(x, y + 1);
+        self.active.insert((x, y + 1));
+        true
+    }
+}
+
+/// Push players out of the way of a block falling into (x, y)
+/// Players that can't be pushed down get crushed instead
+/// Returns true if there are no players in the way anymore
+fn make_room(
+    terrain: &Terrain,
+    players: &mut [(&mut PlayerPosition, &mut Health)],
+    x: i64,
+    y: usize,
+) -> bool {
+    let mut room = true;
+    for (position, health) in players.iter_mut() {
+        if !touches(position, x, y) {
+            continue;
+        }
+
+        // just under the falling block
+        let pushed = PlayerPosition {
+            x: position.x,
+            y: -(y as f32 + 1.),
+        };
+        if !touches_terrain(terrain, &pushed) {
+            **position = pushed;
+            continue;
+        }
+
+        if health.hurt(CRUSH_DAMAGE, position) {
+            info!("a player was crushed by a falling block");
+        } else {
+            room = false;
+        }
+    }
+    room
+}
+
+/// Whether a player overlaps the block at (x, y)
+fn touches(position: &PlayerPosition, x: i64, y: usize) -> bool {
+    (position.x - x as f32).abs() < TOUCH_DISTANCE
+        && (-position.y - y as f32).abs() < TOUCH_DISTANCE
+}
+
+/// Whether a player overlaps any block that they can't move through
+fn touches_terrain(terrain: &Terrain, position: &PlayerPosition) -> bool {
+    let player_block = position.world_pos().block();
+    // blocks above the surface are never solid
+    let (player_x, player_y) = match player_block.terrain_coords() {
+        Some(coords) => coords,
+        None => (player_block.x, 0),
+    };
+
+    for x in (player_x - 1)..=(player_x + 1) {
+        for y in player_y.saturating_sub(1)..=(player_y + 1) {
+            let solid = block_at(terrain, x, y)
+                .flatten()
+                .is_some_and(|b| !b.is_liquid());
+            if solid && touches(position, x, y) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+/// Server system that drops falling blocks, on the game tick
+pub fn drop_falling_blocks(
+    mut terrain: ResMut<Terrain>,
+    mut falling: ResMut<FallingBlocks>,
+    mut liquids: ResMut<Liquids>,
+    mut query: Query<(&mut PlayerPosition, &mut Health), With<ConnectedClientInfo>>,
+) {
+    let mut players: Vec<_> = query
+        .iter_mut()
+        .map(|(position, health)| (position.into_inner(), health.into_inner()))
+        .collect();
+    falling.step(&mut terrain, &mut liquids, &mut players);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(
+        falling: &mut FallingBlocks,
+        terrain: &mut Terrain,
+        players: &mut [(&mut PlayerPosition, &mut Health)],
+        ticks: u64,
+    ) {
+        let mut liquids = Liquids::default();
+        for _ in 0..ticks {
+            falling.step(terrain, &mut liquids, players);
+        }
+    }
+
+    #[test]
+    fn sand_column_falls_together() {
+        let mut terrain = Terrain::with_floor(0);
+        let floor = CHUNK_HEIGHT - 1;
+        // a column of sand held up by a block of limestone
+        set_block_at(&mut terrain, 5, 10, Some(BlockType::Limestone));
+        for y in 6..10 {
+            set_block_at(&mut terrain, 5, y, Some(BlockType::Sand));
+        }
+
+        let mut falling = FallingBlocks::default();
+        run(&mut falling, &mut terrain, &mut [], 100);
+        assert_eq!(block_at(&terrain, 5, 9), Some(Some(BlockType::Sand)));
+
+        // mine out the limestone
+        set_block_at(&mut terrain, 5, 10, None);
+        falling.wake_above(5, 10);
+        run(
+            &mut falling,
+            &mut terrain,
+            &mut [],
+            FALL_TICKS * CHUNK_HEIGHT as u64,
+        );
+
+        // it all ends up in a pile on the floor
+        for y in (floor - 4)..floor {
+            assert_eq!(block_at(&terrain, 5, y), Some(Some(BlockType::Sand)));
+        }
+        assert_eq!(block_at(&terrain, 5, floor - 5), Some(None));
+        assert!(falling.active.is_empty());
+    }
+
+    #[test]
+    fn sand_sinks_through_water() {
+        let mut terrain = Terrain::with_floor(0);
+        let floor = CHUNK_HEIGHT - 1;
+        set_block_at(&mut terrain, 8, floor - 1, Some(BlockType::Water));
+        set_block_at(&mut terrain, 8, floor - 2, Some(BlockType::Sand));
+
+        let mut falling = FallingBlocks::default();
+        falling.active.insert((8, floor - 2));
+        run(&mut falling, &mut terrain, &mut [], 10);
+
+        assert_eq!(
+            block_at(&terrain, 8, floor - 1),
+            Some(Some(BlockType::Sand))
+        );
+        assert_eq!(
+            block_at(&terrain, 8, floor - 2),
+            Some(Some(BlockType::Water))
+        );
+    }
+
+    #[test]
+    fn players_get_pushed_or_crushed() {
+        let mut terrain = Terrain::with_floor(0);
+        let floor = CHUNK_HEIGHT - 1;
+        set_block_at(&mut terrain, 3, 10, Some(BlockType::Sand));
+
+        // a player in the air right under the sand gets pushed down
+        let mut position = PlayerPosition { x: 3., y: -11.5 };
+        let mut health = Health::default();
+        let mut falling = FallingBlocks::default();
+        falling.active.insert((3, 10));
+        run(
+            &mut falling,
+            &mut terrain,
+            &mut [(&mut position, &mut health)],
+            FALL_TICKS,
+        );
+        assert_eq!(block_at(&terrain, 3, 11), Some(Some(BlockType::Sand)));
+        assert_eq!(position.y, -12.);
+        assert_eq!(health.health, Health::default().health);
+
+        // a player standing on the floor has nowhere to go
+        set_block_at(&mut terrain, 3, 11, None);
+        set_block_at(&mut terrain, 3, floor - 2, Some(BlockType::Sand));
+        let mut position = PlayerPosition {
+            x: 3.,
+            y: -(floor as f32 - 1.),
+        };
+        falling.active.insert((3, floor - 2));
+        run(
+            &mut falling,
+            &mut terrain,
+            &mut [(&mut position, &mut health)],
+            FALL_TICKS,
+        );
+        assert_eq!(
+            block_at(&terrain, 3, floor - 2),
+            Some(Some(BlockType::Sand))
+        );
+        assert!(health.health < Health::default().health);
+
+        // until they get crushed and respawn, then the sand lands
+        run(
+            &mut falling,
+            &mut terrain,
+            &mut [(&mut position, &mut health)],
+            FALL_TICKS * 20,
+        );
+        assert_eq!(
+            block_at(&terrain, 3, floor - 1),
+            Some(Some(BlockType::Sand))
+        );
+        assert_eq!(health.health, Health::default().health);
+    }
+}
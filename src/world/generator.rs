@@ -0,0 +1,547 @@
+use bevy::{
+    prelude::*,
+    tasks::{AsyncComputeTaskPool, Task},
+};
+use bincode::{Decode, Encode};
+use clap::ValueEnum;
+use futures_lite::future;
+use std::{collections::HashMap, ops::RangeInclusive, path::Path, sync::Arc};
+use strum::IntoEnumIterator;
+
+use super::{structures::StructureTemplate, *};
+use crate::procedural_functions::{self, generate_random_vein_count};
+
+/// How many empty rows sit above the ground in the flat world
+const FLAT_GROUND_Y: usize = 16;
+/// How many rows of sand cover the flat world
+const FLAT_SAND_DEPTH: usize = 4;
+
+/// The row that the void world's spawn platform is on
+const VOID_PLATFORM_Y: usize = 8;
+
+/// Something that can fill in the blocks of a chunk
+/// Must always return the same chunk for the same seed and chunk number
+pub trait ChunkGenerator: Send + Sync {
+    /// Generate a surface chunk (chunk number 0)
+    fn surface_chunk(&self, seed: u64, chunk_x: i64) -> Chunk;
+
+    /// Generate a chunk below the surface (chunk number > 0)
+    fn depth_chunk(&self, seed: u64, chunk_x: i64, depth: u64) -> Chunk;
+
+    /// Generate any chunk, picking surface or depth generation as needed
+    fn chunk(&self, seed: u64, chunk_x: i64, chunk_number: u64) -> Chunk {
+        if chunk_number == 0 {
+            self.surface_chunk(seed, chunk_x)
+        } else {
+            self.depth_chunk(seed, chunk_x, chunk_number)
+        }
+    }
+}
+
+/// Which chunk generator the server should use
+#[derive(ValueEnum, Encode, Decode, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GeneratorType {
+    /// Regular world generation with biomes, ores, caves and trees
+    Default,
+    /// Flat layers of sand and limestone
+    Flat,
+    /// Empty world with a few test structures at the surface
+    Void,
+}
+
+impl GeneratorType {
+    /// Create the generator for this type, or say why its files can't be loaded
+    pub fn create(&self) -> Result<Box<dyn ChunkGenerator>, String> {
+        Ok(match self {
+            GeneratorType::Default => Box::new(DefaultGenerator::new()?),
+            GeneratorType::Flat => Box::new(FlatGenerator),
+            GeneratorType::Void => Box::new(VoidGenerator),
+        })
+    }
+}
+
+/// Resource on the server that all chunks get generated through
+pub struct WorldGenerator {
+    /// Which type of generator this is, so that it can be saved
+    pub generator_type: GeneratorType,
+    /// Shared with the tasks that generate chunks in the background
+    pub generator: Arc<dyn ChunkGenerator>,
+}
+
+impl WorldGenerator {
+    pub fn new(generator_type: GeneratorType) -> Result<Self, String> {
+        Ok(Self {
+            generator_type,
+            generator: generator_type.create()?.into(),
+        })
+    }
+}
+
+/// Resource on the server for chunks that are being generated in the background,
+/// on the AsyncComputeTaskPool so that the game tick doesn't have to wait for them
+/// Chunks that were unloaded to the chunk store get read back in the same way
+#[derive(Default)]
+pub struct PendingChunks {
+    /// Generation tasks by chunk position, at most one per chunk
+    tasks: HashMap<(i64, u64), Task<Chunk>>,
+}
+
+impl PendingChunks {
+    /// Start generating a chunk, unless the terrain already has it or it is already pending
+    /// If the chunk store has the chunk, it gets loaded from there instead
+    pub fn request(
+        &mut self,
+        generator: &WorldGenerator,
+        seed: u64,
+        store: &ChunkStore,
+        terrain: &Terrain,
+        chunk_x: i64,
+        chunk_number: u64,
+    ) {
+        if terrain.find_chunk(chunk_x, chunk_number).is_some()
+            || self.is_pending(chunk_x, chunk_number)
+        {
+            return;
+        }
+
+        let generator = generator.generator.clone();
+        let store = store.clone();
+        let task = AsyncComputeTaskPool::get().spawn(async move {
+            store.load_or_generate(generator.as_ref(), seed, chunk_x, chunk_number)
+        });
+        self.tasks.insert((chunk_x, chunk_number), task);
+    }
+
+    /// Whether a chunk is being generated
+    pub fn is_pending(&self, chunk_x: i64, chunk_number: u64) -> bool {
+        self.tasks.contains_key(&(chunk_x, chunk_number))
+    }
+
+    /// How many chunks are being generated
+    pub fn len(&self) -> usize {
+        self.tasks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tasks.is_empty()
+    }
+
+    /// Add the chunks that are done to the terrain, without waiting for the rest
+    /// They get added in order of position, so the result doesn't depend on which finished first,
+    /// and chunks that the terrain got some other way in the meantime are kept instead
+    /// Returns the positions of the chunks that were added
+    pub fn merge_finished(&mut self, terrain: &mut Terrain) -> Vec<(i64, u64)> {
+        let mut finished: Vec<((i64, u64), Chunk)> = Vec::new();
+        self.tasks.retain(
+            |position, task| match future::block_on(future::poll_once(task)) {
+                Some(chunk) => {
+                    finished.push((*position, chunk));
+                    false
+                }
+                None => true,
+            },
+        );
+        finished.sort_by_key(|(position, _)| *position);
+
+        let mut added = Vec::new();
+        for (position, chunk) in finished {
+            if terrain.find_chunk(position.0, position.1).is_none() {
+                terrain.insert_chunk(chunk);
+                added.push(position);
+            }
+        }
+        added
+    }
+}
+
+/// The regular world generation algorithm
+pub struct DefaultGenerator {
+    /// Biomes and where they go, loaded from BIOMES_FILE
+    biomes: BiomeTable,
+    /// Structures placed on top of the terrain, loaded from STRUCTURES_DIR
+    structures: Vec<StructureTemplate>,
+}
+
+impl DefaultGenerator {
+    /// Load the biomes and structures, or say what is wrong with the biomes
+    pub fn new() -> Result<Self, String> {
+        Ok(Self {
+            biomes: biomes::load_biomes(Path::new(biomes::BIOMES_FILE))?,
+            structures: structures::load_structures(Path::new(structures::STRUCTURES_DIR)),
+        })
+    }
+
+    /// Generate a chunk without any structures
+    fn base_chunk(&self, seed: u64, chunk_x: i64, chunk_number: u64) -> Chunk {
+        if chunk_number == 0 {
+            self.base_surface_chunk(seed, chunk_x)
+        } else {
+            self.base_depth_chunk(seed, chunk_x, chunk_number)
+        }
+    }
+
+    fn base_depth_chunk(&self, seed: u64, chunk_x: i64, depth: u64) -> Chunk {
+        // start with empty chunk
+        let mut c = Chunk::empty(chunk_x, depth);
+
+        let veins = self.veins(seed, chunk_x, depth);
+
+        let biomes = ChunkBiomes::new(&self.biomes, seed, depth);
+        let prev_biome = biomes.upper;
+        let biome_change = biomes.lower;
+        let average_biome_change_depth = biomes.change_depth;
+
+        debug!(
+            "Chunk {} has biome change from {:?} to {:?} between {} and {}",
+            depth,
+            prev_biome,
+            biome_change,
+            average_biome_change_depth + 2,
+            average_biome_change_depth - 2,
+        );
+
+        let cave = Cave::new(&self.biomes, seed, chunk_x, depth);
+
+        // Loop through chunk, filling in where blocks should be
+        for x in 0..CHUNK_WIDTH {
+            let world_x = chunk_x * CHUNK_WIDTH as i64 + x as i64;
+
+            let biome_change_ypos = self.biome_change_y(seed, &biomes, depth, world_x);
+
+            for y in 0..CHUNK_HEIGHT {
+                let mut block_type = if y >= biome_change_ypos {
+                    biome_change.primary_block
+                } else {
+                    prev_biome.primary_block
+                };
+
+                // Check if this is within the bounds of an ore vein
+                for vein in &veins {
+                    let (vein_x, vein_y) = vein.local_position(chunk_x, depth, x, y);
+
+                    if vein.contains(vein_x, vein_y) {
+                        /* info!(
+                            "Block at chunk {} {},{} in {:?} vein from {},{} to {},{}",
+                            depth,
+                            x,
+                            y,
+                            vein.shape,
+                            vein.start_x,
+                            vein.start_y,
+                            vein.end_x,
+                            vein.end_y
+                        ); */
+                        block_type = vein.ore_type.block();
+                    }
+                }
+
+                //Add Cave Functionality
+                if cave.is_open(x, y) {
+                    block_type = BlockType::CaveVoid;
+                }
+
+                if block_type != BlockType::CaveVoid {
+                    c.blocks[y][x] = Some(Block::new(block_type));
+                }
+            }
+        }
+
+        c
+    }
+
+    fn base_surface_chunk(&self, seed: u64, chunk_x: i64) -> Chunk {
+        // Create surface chunk with perlin slice functions
+
+        let mut c = Chunk::empty(chunk_x, 0);
+
+        // the magic numbers make separate rands for each of these
+        let hill_seed = procedural_functions::generate_seed(seed, vec![16]);
+        let octave2_seed = procedural_functions::generate_seed(seed, vec![25]);
+
+        // the surface biomes are always the same
+        let surface = ChunkBiomes::new(&self.biomes, seed, 0);
+
+        let veins = self.veins(seed, chunk_x, 0);
+
+        // Loop through chunk, filling in where blocks should be
+        for x in 0..CHUNK_WIDTH {
+            let world_x = chunk_x * CHUNK_WIDTH as i64 + x as i64;
+
+            // points every 9 blocks, peaks as high as 16 blocks
+            let hills = procedural_functions::world_slice_pos_x(hill_seed, 9, 3, 16, world_x);
+            // smaller bumps on top of the hills, points every 5 blocks
+            let octave2 = procedural_functions::world_slice_pos_x(octave2_seed, 5, 0, 8, world_x);
+            let hill_top = (hills.round() as i32 + octave2 as i32) as usize - 1;
+
+            let sand_bottom = self.biome_change_y(seed, &surface, 0, world_x);
+
+            for y in hill_top..CHUNK_HEIGHT {
+                let mut block_type = if y < sand_bottom {
+                    surface.upper.primary_block
+                } else {
+                    surface.lower.primary_block
+                };
+
+                // Check if this is within the bounds of an ore vein
+                for vein in &veins {
+                    let (vein_x, vein_y) = vein.local_position(chunk_x, 0, x, y);
+
+                    if vein.contains(vein_x, vein_y) {
+                        // info!(
+                        //     "Block at chunk 0 {},{} in {:?} vein from {},{} to {},{}",
+                        //     x, y, vein.shape, vein.start_x, vein.start_y, vein.end_x, vein.end_y
+                        // );
+                        block_type = vein.ore_type.block();
+                    }
+                }
+
+                c.blocks[y][x] = Some(Block::new(block_type));
+            }
+        }
+
+        c
+    }
+
+    /// The biomes and the rules for where they go
+    pub fn biomes(&self) -> &BiomeTable {
+        &self.biomes
+    }
+
+    /// The first row of a column in a chunk that belongs to the chunk's lower biome
+    /// Below the surface the boundary wobbles around biomes.change_depth,
+    /// in the surface chunk it is the bottom of the sand
+    pub fn biome_change_y(
+        &self,
+        seed: u64,
+        biomes: &ChunkBiomes,
+        chunk_number: u64,
+        world_x: i64,
+    ) -> usize {
+        if chunk_number == 0 {
+            // points every 5 blocks
+            let sand_seed = procedural_functions::generate_seed(seed, vec![32]);
+            return procedural_functions::world_slice_pos_x(sand_seed, 5, 16, 31, world_x).round()
+                as usize;
+        }
+
+        // interpolate between points every 3 blocks, continuing into the neighboring chunks
+        procedural_functions::world_slice_pos_x(
+            procedural_functions::generate_seed(seed, vec![chunk_number, 234]),
+            3,
+            biomes.change_depth - 2,
+            biomes.change_depth + 2, // 5 block range
+            world_x,
+        )
+        .round() as usize
+            - 1
+    }
+
+    /// All veins that reach into a chunk, from the chunks above, below and to either side
+    pub fn veins(&self, seed: u64, chunk_x: i64, chunk_number: u64) -> Vec<Vein> {
+        nearby_veins(
+            &self.biomes,
+            seed,
+            chunk_x,
+            chunk_number,
+            chunk_number.saturating_sub(1)..=chunk_number + 1,
+        )
+    }
+
+    /// Add the structures that reach into a chunk on top of its terrain
+    fn with_structures(&self, seed: u64, mut c: Chunk) -> Chunk {
+        structures::place_structures(&self.structures, seed, &mut c, |x, n| {
+            self.base_chunk(seed, x, n)
+        });
+        c
+    }
+}
+
+impl ChunkGenerator for DefaultGenerator {
+    fn depth_chunk(&self, seed: u64, chunk_x: i64, depth: u64) -> Chunk {
+        self.with_structures(seed, self.base_depth_chunk(seed, chunk_x, depth))
+    }
+
+    fn surface_chunk(&self, seed: u64, chunk_x: i64) -> Chunk {
+        self.with_structures(seed, self.base_surface_chunk(seed, chunk_x))
+    }
+}
+
+/// All veins that reach into a chunk,
+/// out of the ones that originate in the given chunk rows, in this chunk and the chunks on either side
+fn nearby_veins(
+    biomes: &BiomeTable,
+    seed: u64,
+    chunk_x: i64,
+    chunk_number: u64,
+    chunk_numbers: RangeInclusive<u64>,
+) -> Vec<Vein> {
+    let mut veins = Vec::new();
+    for vein_chunk_number in chunk_numbers {
+        // every chunk in a row has the same biomes
+        let biomes = ChunkBiomes::new(biomes, seed, vein_chunk_number);
+
+        for vein_chunk_x in (chunk_x - 1)..=(chunk_x + 1) {
+            for vein_number in 0..generate_random_vein_count(seed, vein_chunk_x, vein_chunk_number)
+            {
+                let vein = Vein::new(seed, vein_chunk_x, vein_chunk_number, vein_number, &biomes);
+                if vein.reaches(chunk_x, chunk_number) {
+                    veins.push(vein);
+                }
+            }
+        }
+    }
+    veins
+}
+
+/// Superflat world: a few rows of sand on top of endless limestone
+pub struct FlatGenerator;
+
+impl ChunkGenerator for FlatGenerator {
+    fn surface_chunk(&self, _seed: u64, chunk_x: i64) -> Chunk {
+        let mut c = Chunk::empty(chunk_x, 0);
+
+        for y in FLAT_GROUND_Y..CHUNK_HEIGHT {
+            let block_type = if y < FLAT_GROUND_Y + FLAT_SAND_DEPTH {
+                BlockType::Sand
+            } else {
+                BlockType::Limestone
+            };
+            for x in 0..CHUNK_WIDTH {
+                c.blocks[y][x] = Some(Block::new(block_type));
+            }
+        }
+
+        c
+    }
+
+    fn depth_chunk(&self, _seed: u64, chunk_x: i64, depth: u64) -> Chunk {
+        Chunk {
+            blocks: [[Some(Block::new(BlockType::Limestone)); CHUNK_WIDTH]; CHUNK_HEIGHT],
+            chunk_x,
+            chunk_number: depth,
+        }
+    }
+}
+
+/// Empty world, except for a spawn platform with some test structures in the spawn chunk
+pub struct VoidGenerator;
+
+impl ChunkGenerator for VoidGenerator {
+    fn surface_chunk(&self, _seed: u64, chunk_x: i64) -> Chunk {
+        let mut c = Chunk::empty(chunk_x, 0);
+
+        // everything outside of the spawn chunk is void
+        if chunk_x != 0 {
+            return c;
+        }
+
+        // spawn platform across the whole chunk, which players can't mine through
+        let mut platform = Block::new(BlockType::Limestone);
+        platform.state.flags.insert(BlockFlags::PROTECTED);
+        for x in 0..CHUNK_WIDTH {
+            c.blocks[VOID_PLATFORM_Y][x] = Some(platform);
+        }
+
+        // one pillar of every block type, for checking textures and mining
+        let mut x = 4;
+        for block_type in BlockType::iter() {
+            if block_type == BlockType::CaveVoid {
+                continue;
+            }
+            c.blocks[VOID_PLATFORM_Y - 1][x] = Some(Block::new(block_type));
+            x += 2;
+        }
+
+        // staircase up and back down
+        let stairs_x = x + 4;
+        for step in 0..4 {
+            for height in 0..=step {
+                c.blocks[VOID_PLATFORM_Y - 1 - height][stairs_x + step] =
+                    Some(Block::new(BlockType::Basalt));
+                c.blocks[VOID_PLATFORM_Y - 1 - height][stairs_x + 7 - step] =
+                    Some(Block::new(BlockType::Basalt));
+            }
+        }
+
+        // tunnel that is exactly one block tall
+        let tunnel_x = stairs_x + 12;
+        for x in tunnel_x..tunnel_x + 8 {
+            c.blocks[VOID_PLATFORM_Y - 2][x] = Some(Block::new(BlockType::Granite));
+        }
+
+        // hole in the platform that drops into the void
+        let hole_x = tunnel_x + 12;
+        for x in hole_x..hole_x + 3 {
+            c.blocks[VOID_PLATFORM_Y][x] = None;
+        }
+
+        c
+    }
+
+    fn depth_chunk(&self, _seed: u64, chunk_x: i64, depth: u64) -> Chunk {
+        Chunk::empty(chunk_x, depth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::tasks::TaskPool;
+
+    const TEST_SEED: u64 = 5092718;
+
+    #[test]
+    fn pending_chunks_are_generated_once() {
+        AsyncComputeTaskPool::init(TaskPool::new);
+        let generator = WorldGenerator::new(GeneratorType::Default).unwrap();
+        let mut terrain = Terrain::empty();
+        terrain.insert_chunk(Chunk::empty(0, 0));
+
+        // a chunk that was changed and then unloaded
+        let dir = std::env::temp_dir().join(format!("krabs-pending-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let store = ChunkStore::new(dir);
+        let mut unloaded = generator.generator.chunk(TEST_SEED, 1, 1);
+        unloaded.blocks[0][0] = Some(Block::new(BlockType::Peridot));
+        store.save_chunk(&unloaded).unwrap();
+
+        let mut pending = PendingChunks::default();
+        // two players that need the same chunks
+        for _ in 0..2 {
+            for chunk_x in -1..=1 {
+                for chunk_number in 0..3 {
+                    pending.request(
+                        &generator,
+                        TEST_SEED,
+                        &store,
+                        &terrain,
+                        chunk_x,
+                        chunk_number,
+                    );
+                }
+            }
+        }
+        // the terrain already has (0, 0)
+        assert_eq!(pending.len(), 8);
+        assert!(!pending.is_pending(0, 0));
+        assert!(pending.is_pending(-1, 2));
+
+        let mut added = Vec::new();
+        while !pending.is_empty() {
+            added.extend(pending.merge_finished(&mut terrain));
+            std::thread::yield_now();
+        }
+        assert_eq!(added.len(), 8);
+        assert_eq!(terrain.len(), 9);
+
+        // the same chunks as generating them right away, and the existing chunk is kept
+        assert_eq!(terrain.find_chunk(0, 0), Some(&Chunk::empty(0, 0)));
+        assert_eq!(
+            terrain.find_chunk(-1, 2),
+            Some(&generator.generator.chunk(TEST_SEED, -1, 2))
+        );
+        // the unloaded chunk comes back the way it was left
+        assert_eq!(terrain.find_chunk(1, 1), Some(&unloaded));
+        store.clear().unwrap();
+    }
+}
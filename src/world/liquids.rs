@@ -0,0 +1,276 @@
+use std::collections::HashSet;
+
+use super::*;
+
+/// How many game ticks water takes to flow one block
+const WATER_FLOW_TICKS: u64 = 4;
+/// Lava is thicker, so it flows slower
+const LAVA_FLOW_TICKS: u64 = 12;
+/// How far to either side a liquid looks for a lower spot to flow towards
+const FLOW_DISTANCE: i64 = 4;
+
+/// Resource on the server that keeps track of the liquids that might still flow
+/// Liquids that can't go anywhere settle, and only get checked again when a block next to them changes
+#[derive(Default)]
+pub struct Liquids {
+    /// World positions (x, y) of blocks to check, they don't have to be liquids anymore
+    active: HashSet<(i64, usize)>,
+    /// Chunks that have already been searched for liquids
+    scanned_chunks: HashSet<(i64, u64)>,
+    /// Game ticks since the liquids started flowing
+    tick: u64,
+}
+
+impl Liquids {
+    /// Check the liquids around a block that changed, so that they can flow into it
+    pub fn wake_around(&mut self, x: i64, y: usize) {
+        for wake_x in (x - 1)..=(x + 1) {
+            for wake_y in y.saturating_sub(1)..=(y + 1) {
+                self.active.insert((wake_x, wake_y));
+            }
+        }
+    }
+
+    /// Forget that a chunk was searched for liquids, so that it gets searched again
+    /// if it gets loaded back in after being unloaded
+    pub fn forget_chunk(&mut self, chunk_x: i64, chunk_number: u64) {
+        self.scanned_chunks.remove(&(chunk_x, chunk_number));
+    }
+
+    /// Advance the liquids by one game tick, moving the ones that are due to flow by a block
+    pub fn step(&mut self, terrain: &mut Terrain) {
+        self.tick += 1;
+
+        // liquids in chunks that were just generated or loaded
+        for chunk in terrain.chunks() {
+            if !self
+                .scanned_chunks
+                .insert((chunk.chunk_x, chunk.chunk_number))
+            {
+                continue;
+            }
+            for (y, row) in chunk.blocks.iter().enumerate() {
+                for (x, block) in row.iter().enumerate() {
+                    if block.is_some_and(|b| b.block_type.is_liquid()) {
+                        self.active.insert((
+                            chunk.chunk_x * CHUNK_WIDTH as i64 + x as i64,
+                            chunk.chunk_number as usize * CHUNK_HEIGHT + y,
+                        ));
+                    }
+                }
+            }
+        }
+
+        // bottom rows first, so that columns of liquid fall together
+        let mut positions: Vec<(i64, usize)> = self.active.drain().collect();
+        positions.sort_by_key(|(x, y)| (std::cmp::Reverse(*y), *x));
+
+        // blocks that liquid flowed into this tick, which shouldn't move again until the next one
+        let mut arrived = HashSet::new();
+
+        for (x, y) in positions {
+            if arrived.contains(&(x, y)) {
+                continue;
+            }
+            let liquid = match block_at(terrain, x, y) {
+                Some(Some(block_type)) if block_type.is_liquid() => block_type,
+                _ => continue,
+            };
+
+            if self.harden(terrain, x, y, liquid) {
+                continue;
+            }
+
+            let flow_ticks = match liquid {
+                BlockType::Lava => LAVA_FLOW_TICKS,
+                _ => WATER_FLOW_TICKS,
+            };
+            if !self.tick.is_multiple_of(flow_ticks) {
+                // not this tick, but it could still flow
+                self.active.insert((x, y));
+                continue;
+            }
+
+            if let Some((to_x, to_y)) = flow_target(terrain, x, y, liquid) {
+                set_block_at(terrain, x, y, None);
+                set_block_at(terrain, to_x, to_y, Some(liquid));
+                self.wake_around(x, y);
+                self.active.insert((to_x, to_y));
+                arrived.insert((to_x, to_y));
+            }
+            // else the liquid has settled
+        }
+    }
+
+    /// Lava touching water cools into basalt
+    /// Returns true if the liquid at (x, y) was lava that hardened
+    fn harden(&mut self, terrain: &mut Terrain, x: i64, y: usize, liquid: BlockType) -> bool {
+        let other = match liquid {
+            BlockType::Lava => BlockType::Water,
+            _ => BlockType::Lava,
+        };
+
+        let mut hardened = false;
+        for (side_x, side_y) in sides(x, y) {
+            if block_at(terrain, side_x, side_y) != Some(Some(other)) {
+                continue;
+            }
+
+            // the lava is the one that hardens, the water stays
+            let (lava_x, lava_y) = match liquid {
+                BlockType::Lava => (x, y),
+                _ => (side_x, side_y),
+            };
+            set_block_at(terrain, lava_x, lava_y, Some(BlockType::Basalt));
+            self.wake_around(lava_x, lava_y);
+            hardened = liquid == BlockType::Lava;
+            if hardened {
+                break;
+            }
+        }
+        hardened
+    }
+}
+
+/// Where a liquid block flows to next, or None if it has settled
+fn flow_target(terrain: &Terrain, x: i64, y: usize, liquid: BlockType) -> Option<(i64, usize)> {
+    let is_empty = |x: i64, y: usize| block_at(terrain, x, y) == Some(None);
+
+    // fall straight down
+    if is_empty(x, y + 1) {
+        return Some((x, y + 1));
+    }
+
+    // head towards the closest spot to either side that it can fall from
+    for distance in 1..=FLOW_DISTANCE {
+        for direction in [-1, 1] {
+            let path_clear = (1..=distance).all(|step| is_empty(x + direction * step, y));
+            if path_clear && is_empty(x + direction * distance, y + 1) {
+                return Some((x + direction, y));
+            }
+        }
+    }
+
+    // liquid on top pushes it out to the side, which levels out pools
+    let pushed = y > 0 && block_at(terrain, x, y - 1) == Some(Some(liquid));
+    if pushed {
+        for direction in [-1, 1] {
+            if is_empty(x + direction, y) {
+                return Some((x + direction, y));
+            }
+        }
+    }
+
+    None
+}
+
+/// The four blocks around a block
+fn sides(x: i64, y: usize) -> Vec<(i64, usize)> {
+    let mut sides = vec![(x - 1, y), (x + 1, y), (x, y + 1)];
+    if y > 0 {
+        sides.push((x, y - 1));
+    }
+    sides
+}
+
+/// The block type at a world position, Some(None) if there is no block,
+/// or None if the chunk isn't loaded
+pub(super) fn block_at(terrain: &Terrain, x: i64, y: usize) -> Option<Option<BlockType>> {
+    terrain
+        .get_block((x, y).into())
+        .map(|block| block.map(|b| b.block_type))
+}
+
+/// Replace the block at a world position, if its chunk is loaded
+pub(super) fn set_block_at(terrain: &mut Terrain, x: i64, y: usize, block_type: Option<BlockType>) {
+    terrain.set_block((x, y).into(), block_type.map(Block::new));
+}
+
+/// Server system that makes liquids flow, on the game tick
+pub fn flow_liquids(mut terrain: ResMut<Terrain>, mut liquids: ResMut<Liquids>) {
+    liquids.step(&mut terrain);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(liquids: &mut Liquids, terrain: &mut Terrain, ticks: u64) {
+        for _ in 0..ticks {
+            liquids.step(terrain);
+        }
+    }
+
+    fn count(terrain: &Terrain, block_type: BlockType) -> usize {
+        terrain
+            .find_chunk(0, 1)
+            .unwrap()
+            .blocks
+            .iter()
+            .flatten()
+            .flatten()
+            .filter(|b| b.block_type == block_type)
+            .count()
+    }
+
+    #[test]
+    fn water_falls_and_spreads_out() {
+        let mut terrain = Terrain::with_floor(1);
+        let top = CHUNK_HEIGHT + 10;
+        // a column of water in the air
+        for y in top..top + 6 {
+            set_block_at(&mut terrain, 20, y, Some(BlockType::Water));
+        }
+
+        let mut liquids = Liquids::default();
+        run(&mut liquids, &mut terrain, 2000);
+
+        // all of it ends up on the floor, one block deep, none of it lost
+        let floor_row = CHUNK_HEIGHT - 2;
+        let on_floor = terrain.find_chunk(0, 1).unwrap().blocks[floor_row]
+            .iter()
+            .flatten()
+            .filter(|b| b.block_type == BlockType::Water)
+            .count();
+        assert_eq!(on_floor, 6);
+        assert_eq!(count(&terrain, BlockType::Water), 6);
+
+        // and then it stops moving
+        assert!(liquids.active.is_empty());
+    }
+
+    #[test]
+    fn lava_and_water_make_basalt() {
+        let mut terrain = Terrain::with_floor(1);
+        let floor = 2 * CHUNK_HEIGHT - 2;
+        set_block_at(&mut terrain, 10, floor, Some(BlockType::Lava));
+        set_block_at(&mut terrain, 10, floor - 3, Some(BlockType::Water));
+
+        let mut liquids = Liquids::default();
+        run(&mut liquids, &mut terrain, 100);
+
+        assert_eq!(count(&terrain, BlockType::Lava), 0);
+        assert_eq!(count(&terrain, BlockType::Basalt), 1);
+        assert_eq!(count(&terrain, BlockType::Water), 1);
+    }
+
+    #[test]
+    fn settled_liquid_flows_into_mined_space() {
+        let mut terrain = Terrain::with_floor(1);
+        let floor = 2 * CHUNK_HEIGHT - 1;
+        set_block_at(&mut terrain, 30, floor - 1, Some(BlockType::Water));
+
+        // a single block of water on a flat floor has nowhere to go
+        let mut liquids = Liquids::default();
+        run(&mut liquids, &mut terrain, 100);
+        assert!(liquids.active.is_empty());
+
+        // mine out the floor under it
+        set_block_at(&mut terrain, 30, floor, None);
+        liquids.wake_around(30, floor);
+        run(&mut liquids, &mut terrain, 100);
+
+        assert_eq!(block_at(&terrain, 30, floor), Some(Some(BlockType::Water)));
+        assert_eq!(block_at(&terrain, 30, floor - 1), Some(None));
+    }
+}
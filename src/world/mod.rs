@@ -0,0 +1,1358 @@
+use crate::{network::BINCODE_CONFIG, states};
+use bevy::prelude::*;
+use bincode::{BorrowDecode, Decode, Encode};
+use iyes_loopless::prelude::*;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use strum_macros::EnumIter;
+
+use crate::player::PlayerPosition;
+
+/// Module for biomes and the rules for where they are, loaded from the assets folder
+pub mod biomes;
+/// Module for cave systems
+pub mod caves;
+/// Module for blocks that fall when nothing holds them up
+pub mod falling;
+/// Module for the different ways that chunks can be generated
+pub mod generator;
+/// Module for water and lava flowing through the world
+pub mod liquids;
+/// Module for ore veins and how rare each ore is
+pub mod ores;
+/// Module for the coordinates that blocks, chunks and points in the world are measured in
+pub mod position;
+/// Module for drawing chunks on the client, one mesh per chunk
+pub mod render;
+/// Module for keeping chunks on disk while no player is near them
+pub mod storage;
+/// Module for structures built from templates in the assets folder
+pub mod structures;
+
+pub use biomes::{Biome, BiomeTable, ChunkBiomes};
+pub use caves::Cave;
+pub use falling::FallingBlocks;
+pub use generator::{ChunkGenerator, GeneratorType, PendingChunks, WorldGenerator};
+pub use liquids::Liquids;
+pub use ores::{OreRarity, Vein, VeinShape};
+pub use position::{BlockPos, ChunkPos, WorldPos, BLOCK_SIZE};
+pub use render::{BlockAtlas, ChunkMesh, ChunkMeshes};
+pub use storage::ChunkStore;
+
+pub const CHUNK_HEIGHT: usize = 64;
+pub const CHUNK_WIDTH: usize = 128;
+
+// how many chunks should always be generated below the lowest player
+const GEN_CHUNKS_AHEAD: u64 = 3;
+
+// how many chunks should always be generated to the left and right of a player
+const GEN_CHUNKS_SIDEWAYS: i64 = 1;
+
+// how many chunks past the generated area around every player a chunk has to be to get unloaded,
+// so that a player walking back and forth over a chunk border doesn't keep reloading chunks
+const UNLOAD_MARGIN: u64 = 1;
+
+// chunks that stay loaded even with no player near, since new players spawn in them
+pub const SPAWN_CHUNKS: [(i64, u64); 2] = [(0, 0), (0, 1)];
+
+/// The world seed the generation tests share
+#[cfg(test)]
+pub const TEST_SEED: u64 = 82981925813;
+
+pub mod client {
+    use std::path::Path;
+
+    use super::*;
+    use crate::ASSETS_DIR;
+    pub struct WorldPlugin;
+
+    impl Plugin for WorldPlugin {
+        fn build(&self, app: &mut App) {
+            // TODO: get baseline terrain from server, then insert it as a resource
+            // then make a system that spawns in the entities from the resource
+            app.add_enter_system(states::client::GameState::InGame, create_world)
+                .add_system_set(
+                    ConditionSet::new()
+                        .run_in_state(states::client::GameState::InGame)
+                        .with_system(f2_prints_terrain_encoding)
+                        .with_system(f3_prints_terrain_info)
+                        .with_system(render::update_visible_chunks)
+                        .into(),
+                )
+                .add_exit_system(states::client::GameState::InGame, destroy_world);
+        }
+    }
+
+    fn create_world(
+        mut commands: Commands,
+        mut images: ResMut<Assets<Image>>,
+        mut materials: ResMut<Assets<ColorMaterial>>,
+    ) {
+        info!("creating terrain on client");
+
+        // create now, insert as resource later
+        let terrain = Terrain::empty();
+
+        // now add as resource
+        commands.insert_resource(terrain);
+
+        // chunks get drawn as they arrive from the server
+        let atlas = BlockAtlas::new(Path::new(ASSETS_DIR), &mut images, &mut materials);
+        commands.insert_resource(atlas);
+        commands.insert_resource(ChunkMeshes::default());
+    }
+}
+
+pub mod server {
+    use std::collections::HashSet;
+
+    use crate::{args::ServerArgs, network::server::ConnectedClientInfo, save};
+
+    use super::*;
+
+    pub struct WorldPlugin;
+
+    impl Plugin for WorldPlugin {
+        fn build(&self, app: &mut App) {
+            app.add_enter_system(
+                states::server::GameState::Running,
+                create_world.label("create_world"),
+            );
+
+            app.add_exit_system(states::server::GameState::Running, destroy_world);
+        }
+    }
+
+    /// Start generating the chunks around players that the terrain doesn't have yet
+    /// Chunks that were unloaded get read back from the chunk store instead of generated
+    /// They get added to the terrain by add_generated_chunks once they are done
+    pub fn check_generate_new_chunks(
+        query: Query<&PlayerPosition, With<ConnectedClientInfo>>,
+        terrain: Res<Terrain>,
+        seed: Res<WorldSeed>,
+        generator: Res<WorldGenerator>,
+        store: Res<ChunkStore>,
+        mut pending: ResMut<PendingChunks>,
+    ) {
+        for position in query.iter() {
+            // info!("found player at ({}, {})", position.x, position.y);
+
+            for (chunk_x, chunk_number) in chunks_around(position, 0) {
+                // chunks that two players both need only get generated once
+                pending.request(
+                    &generator,
+                    seed.seed,
+                    &store,
+                    &terrain,
+                    chunk_x,
+                    chunk_number,
+                );
+            }
+        }
+    }
+
+    /// Add the chunks that finished generating to the terrain
+    pub fn add_generated_chunks(mut terrain: ResMut<Terrain>, mut pending: ResMut<PendingChunks>) {
+        if pending.is_empty() {
+            return;
+        }
+        for (chunk_x, chunk_number) in pending.merge_finished(&mut terrain) {
+            debug!(
+                "generated chunk ({}, {}), {} still pending",
+                chunk_x,
+                chunk_number,
+                pending.len()
+            );
+        }
+    }
+
+    /// Write the chunks that no player is near to the chunk store and take them out of the terrain
+    /// A chunk only gets unloaded once it is written, so a failed write leaves it loaded
+    /// Chunks that didn't change since they were last saved are already in the store
+    pub fn unload_distant_chunks(
+        query: Query<&PlayerPosition, With<ConnectedClientInfo>>,
+        mut terrain: ResMut<Terrain>,
+        mut liquids: ResMut<Liquids>,
+        store: Res<ChunkStore>,
+    ) {
+        let mut keep: HashSet<(i64, u64)> = SPAWN_CHUNKS.into_iter().collect();
+        for position in query.iter() {
+            keep.extend(chunks_around(position, UNLOAD_MARGIN));
+        }
+
+        let mut distant: Vec<(i64, u64)> = terrain
+            .chunks()
+            .map(|chunk| (chunk.chunk_x, chunk.chunk_number))
+            .filter(|position| !keep.contains(position))
+            .collect();
+        distant.sort();
+
+        let mut unloaded = 0;
+        for (chunk_x, chunk_number) in distant {
+            let chunk = match terrain.find_chunk(chunk_x, chunk_number) {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            let saved = if terrain.is_dirty(chunk_x, chunk_number) {
+                store.save_chunk(chunk)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = saved {
+                error!(
+                    "unable to unload chunk ({}, {}), {}",
+                    chunk_x, chunk_number, e
+                );
+                continue;
+            }
+            terrain.remove_chunk(chunk_x, chunk_number);
+            // its liquids get found again if it gets loaded back in
+            liquids.forget_chunk(chunk_x, chunk_number);
+            unloaded += 1;
+        }
+
+        if unloaded > 0 {
+            info!(
+                "unloaded {} chunk(s), {} still loaded",
+                unloaded,
+                terrain.len()
+            );
+        }
+    }
+
+    /// The chunks that the server keeps loaded around a player: the player's chunk and the one
+    /// above it, more chunks below, and the same to either side
+    /// The area grows by margin chunks in every direction
+    fn chunks_around(position: &PlayerPosition, margin: u64) -> Vec<(i64, u64)> {
+        let player_block = position.world_pos().block();
+        let player_chunk_x = player_block.chunk_x();
+        // players above the surface are treated like they are at the top of it
+        let player_chunk_number = player_block.chunk().map_or(0, |chunk| chunk.chunk_number);
+
+        let chunk_xs = (player_chunk_x - GEN_CHUNKS_SIDEWAYS - margin as i64)
+            ..=(player_chunk_x + GEN_CHUNKS_SIDEWAYS + margin as i64);
+        let chunk_numbers = player_chunk_number.saturating_sub(1 + margin)
+            ..(player_chunk_number + GEN_CHUNKS_AHEAD + margin);
+
+        let mut chunks = Vec::new();
+        for chunk_x in chunk_xs {
+            for chunk_number in chunk_numbers.clone() {
+                chunks.push((chunk_x, chunk_number));
+            }
+        }
+        chunks
+    }
+
+    fn create_world(mut commands: Commands, args: Res<ServerArgs>) {
+        info!("creating terrain on server");
+
+        // use the seed from the arguments, or pick a random one
+        // if a save file gets loaded, its seed replaces this one
+        let seed = WorldSeed {
+            seed: args.seed.unwrap_or_else(rand::random),
+        };
+        info!("world seed is {}", seed.seed);
+
+        // a loaded save file also replaces the generator with the one it was made with
+        let generator = match WorldGenerator::new(args.generator) {
+            Ok(generator) => generator,
+            Err(e) => {
+                error!("unable to create world generator: {}", e);
+                std::process::exit(1);
+            }
+        };
+        info!("world generator is {:?}", generator.generator_type);
+
+        // create now, insert as resource later
+        let mut terrain = Terrain::empty();
+
+        // Generate one chunk
+        create_surface_chunk(generator.generator.as_ref(), seed.seed, &mut terrain);
+
+        // generate another chunk below it
+        let chunk = generator.generator.depth_chunk(seed.seed, 0, 1);
+
+        // add the chunk to our terrain resource
+        terrain.insert_chunk(chunk);
+
+        // now add as resources
+        commands.insert_resource(terrain);
+        commands.insert_resource(seed);
+        commands.insert_resource(generator);
+        commands.insert_resource(PendingChunks::default());
+        commands.insert_resource(ChunkStore::new(save::chunk_store_path(&args.save_file)));
+        commands.insert_resource(Liquids::default());
+        commands.insert_resource(FallingBlocks::default());
+    }
+
+    #[derive(Debug)]
+    pub enum DestroyBlockError {
+        /// Corresponding chunk location is not loaded
+        ChunkNotLoaded,
+        /// Block data at the location is empty (block doesn't exist!)
+        BlockDoesntExist,
+        /// Block at the location is a liquid, which can't be mined
+        BlockIsLiquid,
+        /// Block at the location is protected, which can't be mined
+        BlockIsProtected,
+    }
+
+    /// Mine a block at a global position for one tick, damaging it
+    /// Returns the block once it has taken as much damage as its type's durability and breaks,
+    /// or None if it is only damaged so far
+    pub fn mine_block(
+        position: BlockPos,
+        terrain: &mut Terrain,
+        falling: &mut FallingBlocks,
+    ) -> Result<Option<Block>, DestroyBlockError> {
+        let block = terrain
+            .get_block_mut(position)
+            .ok_or(DestroyBlockError::ChunkNotLoaded)?
+            .as_mut()
+            .ok_or(DestroyBlockError::BlockDoesntExist)?;
+
+        if block.block_type.is_liquid() {
+            return Err(DestroyBlockError::BlockIsLiquid);
+        }
+        if block.state.flags.contains(BlockFlags::PROTECTED) {
+            return Err(DestroyBlockError::BlockIsProtected);
+        }
+
+        block.state.damage = block.state.damage.saturating_add(1);
+        if block.state.damage < block.block_type.durability() {
+            return Ok(None);
+        }
+        destroy_block(position, terrain, falling).map(Some)
+    }
+
+    /// Destroy a block at a global position
+    /// Blocks above it that fall when unsupported get dropped by the falling blocks system
+    pub fn destroy_block(
+        position: BlockPos,
+        terrain: &mut Terrain,
+        falling: &mut FallingBlocks,
+    ) -> Result<Block, DestroyBlockError> {
+        let block_opt = terrain
+            .get_block_mut(position)
+            .ok_or(DestroyBlockError::ChunkNotLoaded)?;
+
+        match block_opt {
+            Some(block) if block.block_type.is_liquid() => Err(DestroyBlockError::BlockIsLiquid),
+            Some(block) => {
+                // clone block data so we can give it to the caller
+                let clone = *block;
+
+                // remove the block from our data array
+                // original block is dropped here
+                *block_opt = None;
+
+                // whatever was resting on it might fall now
+                // (it was in a chunk, so it is below the surface)
+                if let Some((x, y)) = position.terrain_coords() {
+                    falling.wake_above(x, y);
+                }
+
+                // give the clone back to the caller
+                // TODO: maybe give a different data type?
+                Ok(clone)
+            }
+            None => {
+                // warn!("no block exists at ({}, {})", x, y);
+                Err(DestroyBlockError::BlockDoesntExist)
+            }
+        }
+    }
+}
+
+fn destroy_world(mut commands: Commands, query: Query<Entity, With<ChunkMesh>>) {
+    info!("destroying world");
+    // remove all chunk meshes
+    for entity in query.iter() {
+        commands.entity(entity).despawn();
+    }
+
+    commands.remove_resource::<Terrain>();
+    commands.remove_resource::<ChunkMeshes>();
+}
+
+/// Represents a change in world state can be either a complete "terrain" (vec of chunks)
+/// or a list of changes
+#[derive(Encode, Decode, Debug, Clone)]
+pub enum WorldDelta {
+    NewChunks(Terrain),
+    BlockDelete(BlockDelete),
+    LiquidChange(LiquidChange),
+    BlockPlace(BlockPlace),
+}
+
+/// Represents a single-block change (only deletion!) in a chunk
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct BlockDelete {
+    /// The chunk in which the block was deleted
+    pub chunk_x: i64,
+    pub chunk_number: u64,
+    /// X position of changed block within the chunk
+    pub x: usize,
+    /// Y position of changed block within the chunk
+    pub y: usize,
+}
+
+/// Represents a single-block change in a chunk caused by liquids:
+/// liquid flowing in or out, or lava hardening into basalt
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct LiquidChange {
+    /// The chunk in which the block changed
+    pub chunk_x: i64,
+    pub chunk_number: u64,
+    /// X position of changed block within the chunk
+    pub x: usize,
+    /// Y position of changed block within the chunk
+    pub y: usize,
+    /// What the block is now, None if the liquid flowed away
+    pub block_type: Option<BlockType>,
+}
+
+/// Represents a block appearing in a chunk where there was none or a different one,
+/// like a falling block landing (the spot it fell from is sent as a BlockDelete),
+/// or a block's state changing, like it getting damaged by mining
+#[derive(Encode, Decode, Debug, Clone)]
+pub struct BlockPlace {
+    /// The chunk in which the block was placed
+    pub chunk_x: i64,
+    pub chunk_number: u64,
+    /// X position of changed block within the chunk
+    pub x: usize,
+    /// Y position of changed block within the chunk
+    pub y: usize,
+    /// The block that is there now
+    pub block: Block,
+}
+
+/// The seed that all world generation is derived from
+/// Resource on the server, gets stored in the save file
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy)]
+pub struct WorldSeed {
+    pub seed: u64,
+}
+
+/// Represents chunks in the game world
+/// On the server, this represents the entire game world
+/// On the client, this represents the part of the game world that the client knows about
+/// In a packet, this is a baseline transfer from server -> client
+#[derive(Debug, Clone, Default)]
+pub struct Terrain {
+    /// Chunks by their position (chunk_x, chunk_number), which always matches the chunk's own
+    chunks: HashMap<(i64, u64), Chunk>,
+    /// Positions of chunks that were added or changed since they were last saved
+    dirty: HashSet<(i64, u64)>,
+}
+
+// only the chunks matter, not which of them still need saving
+impl PartialEq for Terrain {
+    fn eq(&self, other: &Self) -> bool {
+        self.chunks == other.chunks
+    }
+}
+
+impl Terrain {
+    /// Create a terrain with specified number of chunks
+    /// Chunks are created by the generator and are numbered from 0 to len-1, all at chunk_x 0
+    #[cfg(test)]
+    pub fn new(generator: &dyn ChunkGenerator, seed: u64, num_chunks: u64) -> Terrain {
+        (0..num_chunks)
+            .map(|d| generator.chunk(seed, 0, d))
+            .collect()
+    }
+
+    /// Creates a terrain with no chunks
+    pub fn empty() -> Terrain {
+        Terrain {
+            chunks: HashMap::new(),
+            dirty: HashSet::new(),
+        }
+    }
+
+    /// An empty chunk at (0, chunk_number) with a floor along the bottom, for tests
+    #[cfg(test)]
+    pub fn with_floor(chunk_number: u64) -> Terrain {
+        let mut chunk = Chunk::empty(0, chunk_number);
+        for x in 0..CHUNK_WIDTH {
+            chunk.blocks[CHUNK_HEIGHT - 1][x] = Some(Block::new(BlockType::Limestone));
+        }
+        Terrain::from_iter([chunk])
+    }
+
+    /// How many chunks the terrain has
+    pub fn len(&self) -> usize {
+        self.chunks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// All chunks, in no particular order
+    pub fn chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.chunks.values()
+    }
+
+    /// Take all the chunks, in no particular order
+    pub fn into_chunks(self) -> impl Iterator<Item = Chunk> {
+        self.chunks.into_values()
+    }
+
+    /// Add a chunk at its own position, returning the chunk it replaced if there was one
+    pub fn insert_chunk(&mut self, chunk: Chunk) -> Option<Chunk> {
+        self.dirty.insert((chunk.chunk_x, chunk.chunk_number));
+        self.chunks
+            .insert((chunk.chunk_x, chunk.chunk_number), chunk)
+    }
+
+    /// Take the chunk at a chunk position out of the terrain, if the terrain has it
+    pub fn remove_chunk(&mut self, chunk_x: i64, chunk_number: u64) -> Option<Chunk> {
+        self.dirty.remove(&(chunk_x, chunk_number));
+        self.chunks.remove(&(chunk_x, chunk_number))
+    }
+
+    /// Positions of the chunks that were added or changed since they were last saved, sorted
+    pub fn dirty_chunks(&self) -> Vec<(i64, u64)> {
+        let mut dirty: Vec<(i64, u64)> = self.dirty.iter().copied().collect();
+        dirty.sort();
+        dirty
+    }
+
+    /// Whether the chunk at a chunk position changed since it was last saved
+    pub fn is_dirty(&self, chunk_x: i64, chunk_number: u64) -> bool {
+        self.dirty.contains(&(chunk_x, chunk_number))
+    }
+
+    /// Remember that the chunk at a chunk position is saved as it is now
+    pub fn mark_saved(&mut self, chunk_x: i64, chunk_number: u64) {
+        self.dirty.remove(&(chunk_x, chunk_number));
+    }
+
+    /// Find the chunk at a chunk position, if the terrain has it
+    pub fn find_chunk(&self, chunk_x: i64, chunk_number: u64) -> Option<&Chunk> {
+        self.chunks.get(&(chunk_x, chunk_number))
+    }
+
+    /// Find the chunk at a chunk position to change it, if the terrain has it
+    /// The chunk counts as changed, so it gets saved again
+    pub fn find_chunk_mut(&mut self, chunk_x: i64, chunk_number: u64) -> Option<&mut Chunk> {
+        let chunk = self.chunks.get_mut(&(chunk_x, chunk_number))?;
+        self.dirty.insert((chunk_x, chunk_number));
+        Some(chunk)
+    }
+
+    /// The block at a world position, Some(None) if there is no block,
+    /// or None if the chunk isn't loaded (or the position is above the surface)
+    pub fn get_block(&self, position: BlockPos) -> Option<Option<Block>> {
+        let chunk = position.chunk()?;
+        let (x, y) = position.in_chunk();
+        self.find_chunk(chunk.chunk_x, chunk.chunk_number)
+            .map(|chunk| chunk.blocks[y][x])
+    }
+
+    /// The block at a world position, to change in place, or None if the chunk isn't loaded
+    pub fn get_block_mut(&mut self, position: BlockPos) -> Option<&mut Option<Block>> {
+        let chunk = position.chunk()?;
+        let (x, y) = position.in_chunk();
+        self.find_chunk_mut(chunk.chunk_x, chunk.chunk_number)
+            .map(|chunk| &mut chunk.blocks[y][x])
+    }
+
+    /// Replace the block at a world position, returning the block that was there like get_block
+    /// Nothing changes if the chunk isn't loaded
+    pub fn set_block(&mut self, position: BlockPos, block: Option<Block>) -> Option<Option<Block>> {
+        self.get_block_mut(position)
+            .map(|old| std::mem::replace(old, block))
+    }
+}
+
+impl FromIterator<Chunk> for Terrain {
+    fn from_iter<I: IntoIterator<Item = Chunk>>(chunks: I) -> Self {
+        let mut terrain = Terrain::empty();
+        for chunk in chunks {
+            terrain.insert_chunk(chunk);
+        }
+        terrain
+    }
+}
+
+// encoded as a list of chunks, sorted so that the same terrain always encodes the same way
+// the positions are already in the chunks, so they aren't encoded twice
+impl Encode for Terrain {
+    fn encode<E: bincode::enc::Encoder>(
+        &self,
+        encoder: &mut E,
+    ) -> Result<(), bincode::error::EncodeError> {
+        let mut chunks: Vec<&Chunk> = self.chunks.values().collect();
+        chunks.sort_by_key(|c| (c.chunk_x, c.chunk_number));
+        bincode::Encode::encode(&chunks, encoder)?;
+        Ok(())
+    }
+}
+
+impl Decode for Terrain {
+    fn decode<D: bincode::de::Decoder>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        let chunks: Vec<Chunk> = bincode::Decode::decode(decoder)?;
+        Ok(chunks.into_iter().collect())
+    }
+}
+
+impl<'de> bincode::BorrowDecode<'de> for Terrain {
+    fn borrow_decode<D: bincode::de::BorrowDecoder<'de>>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        let chunks: Vec<Chunk> = bincode::BorrowDecode::borrow_decode(decoder)?;
+        Ok(chunks.into_iter().collect())
+    }
+}
+
+/// Represents a chunk of blocks; stored in the Terrain resource
+/// Encodes as a palette of the block types in it, and runs of palette entries (see ChunkRuns)
+#[derive(Debug, PartialEq, Clone)]
+pub struct Chunk {
+    /// 2D array [x, y]
+    pub blocks: [[Option<Block>; CHUNK_WIDTH]; CHUNK_HEIGHT],
+    /// starting column for blocks is chunk_x * CHUNK_WIDTH, can be negative
+    pub chunk_x: i64,
+    /// starting row for blocks is chunk_number * CHUNK_HEIGHT
+    pub chunk_number: u64,
+}
+
+impl Chunk {
+    pub fn empty(chunk_x: i64, chunk_number: u64) -> Self {
+        Self {
+            blocks: [[None; CHUNK_WIDTH]; CHUNK_HEIGHT],
+            chunk_x,
+            chunk_number,
+        }
+    }
+
+    pub fn position(&self) -> ChunkPos {
+        ChunkPos::new(self.chunk_x, self.chunk_number)
+    }
+}
+
+/// How a chunk gets encoded: every different block (type and state) in the chunk goes in the
+/// palette once, then the blocks row by row are runs of the same palette entry
+/// Most chunks are a few long runs of their biome's block, so this is a lot smaller than every block
+#[derive(Encode, Decode)]
+struct ChunkRuns {
+    chunk_x: i64,
+    chunk_number: u64,
+    /// Blocks in the chunk, None for no block
+    palette: Vec<Option<Block>>,
+    /// (palette index, how many blocks in a row)
+    runs: Vec<(u16, u16)>,
+}
+
+impl From<&Chunk> for ChunkRuns {
+    fn from(chunk: &Chunk) -> Self {
+        let mut palette = Vec::new();
+        let mut runs: Vec<(u16, u16)> = Vec::new();
+
+        for block in chunk.blocks.iter().flatten() {
+            let index = match palette.iter().position(|p| p == block) {
+                Some(index) => index,
+                None => {
+                    palette.push(*block);
+                    palette.len() - 1
+                }
+            } as u16;
+
+            match runs.last_mut() {
+                Some((run_index, length)) if *run_index == index => *length += 1,
+                _ => runs.push((index, 1)),
+            }
+        }
+
+        ChunkRuns {
+            chunk_x: chunk.chunk_x,
+            chunk_number: chunk.chunk_number,
+            palette,
+            runs,
+        }
+    }
+}
+
+impl TryFrom<ChunkRuns> for Chunk {
+    type Error = bincode::error::DecodeError;
+
+    fn try_from(encoded: ChunkRuns) -> Result<Self, Self::Error> {
+        let mut chunk = Chunk::empty(encoded.chunk_x, encoded.chunk_number);
+        let mut blocks = chunk.blocks.iter_mut().flatten();
+
+        for (index, length) in encoded.runs {
+            let entry = *encoded.palette.get(index as usize).ok_or_else(|| {
+                bincode::error::DecodeError::OtherString(format!(
+                    "chunk palette has no entry {}",
+                    index
+                ))
+            })?;
+            for _ in 0..length {
+                let block = blocks.next().ok_or_else(|| {
+                    bincode::error::DecodeError::OtherString(
+                        "chunk has too many blocks".to_string(),
+                    )
+                })?;
+                *block = entry;
+            }
+        }
+
+        if blocks.next().is_some() {
+            return Err(bincode::error::DecodeError::OtherString(
+                "chunk is missing blocks".to_string(),
+            ));
+        }
+        Ok(chunk)
+    }
+}
+
+impl Encode for Chunk {
+    fn encode<E: bincode::enc::Encoder>(
+        &self,
+        encoder: &mut E,
+    ) -> Result<(), bincode::error::EncodeError> {
+        bincode::Encode::encode(&ChunkRuns::from(self), encoder)
+    }
+}
+
+impl Decode for Chunk {
+    fn decode<D: bincode::de::Decoder>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        let encoded: ChunkRuns = bincode::Decode::decode(decoder)?;
+        encoded.try_into()
+    }
+}
+
+impl<'de> bincode::BorrowDecode<'de> for Chunk {
+    fn borrow_decode<D: bincode::de::BorrowDecoder<'de>>(
+        decoder: &mut D,
+    ) -> Result<Self, bincode::error::DecodeError> {
+        let encoded: ChunkRuns = bincode::BorrowDecode::borrow_decode(decoder)?;
+        encoded.try_into()
+    }
+}
+
+/// _Not_ a component; stored in a Chunk
+/// Only the world data, the client draws blocks as part of their chunk's mesh
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Encode, Decode)]
+pub struct Block {
+    /// What kind of block is this
+    pub block_type: BlockType,
+    /// Everything else about this particular block, like how damaged it is
+    pub state: BlockState,
+}
+
+impl Block {
+    /// A block of a type with no damage, the first variant, and no flags
+    pub fn new(block_type: BlockType) -> Block {
+        Block {
+            block_type,
+            state: BlockState::default(),
+        }
+    }
+}
+
+/// Per-block data that goes along with the block type, kept to a few bytes
+/// Blocks with the same type and state share a palette entry when their chunk is encoded,
+/// so most chunks don't get any bigger
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct BlockState {
+    /// Ticks of mining the block has taken, it breaks when this reaches its type's durability
+    pub damage: u8,
+    /// Which look of its block type the block has, like the color of a tree's leaves
+    pub variant: u8,
+    pub flags: BlockFlags,
+}
+
+/// Yes/no facts about a block, as bits
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, Encode, Decode)]
+pub struct BlockFlags(u8);
+
+impl BlockFlags {
+    /// Belongs to the world rather than to players, so it can't be mined (like the spawn platform)
+    pub const PROTECTED: BlockFlags = BlockFlags(1 << 0);
+
+    pub const fn contains(&self, flags: BlockFlags) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    pub fn insert(&mut self, flags: BlockFlags) {
+        self.0 |= flags.0;
+    }
+}
+
+/// A distinct type of block, with its own texture
+#[derive(Copy, Clone, Debug, Encode, Decode, PartialEq, Eq, EnumIter, Hash, Deserialize)]
+pub enum BlockType {
+    Sand, // primary blocks
+    Limestone,
+    Basalt,
+    Granite,
+    Diabase,
+    Gabbro,
+    Clay, // "ores"
+    Coal,
+    Iron,
+    Quartz,
+    Labradorite,
+    Peridot,
+    CaveVoid,
+    PalmTreeBlock,
+    Leaves,
+    Trunk,
+    Water, // liquids
+    Lava,
+}
+
+impl BlockType {
+    /// Return the file path for the image that should be displayed for this block
+    pub const fn image_file_path(&self) -> &str {
+        match self {
+            BlockType::Sand => "Sand.png",
+            BlockType::Limestone => "Limestone.png",
+            BlockType::Basalt => "Basalt.png",
+            BlockType::Granite => "Granite.png",
+            BlockType::Diabase => "Diabase.png",
+            BlockType::Gabbro => "Gabbro.png",
+            BlockType::Clay => "Clay.png",
+            BlockType::Coal => "Coal.png",
+            BlockType::Iron => "Iron.png",
+            BlockType::Quartz => "Quartz.png",
+            BlockType::Labradorite => "Labradorite.png",
+            BlockType::Peridot => "Peridot.png",
+            BlockType::CaveVoid => "",
+            BlockType::PalmTreeBlock => "PalmTreeBlock.png",
+            BlockType::Leaves => "Leaves.png",
+            BlockType::Trunk => "Trunk.png",
+            BlockType::Water => "Water.png",
+            BlockType::Lava => "Lava.png",
+        }
+    }
+
+    pub const fn is_real_block(&self) -> bool {
+        match self {
+            BlockType::CaveVoid | BlockType::PalmTreeBlock => false,
+            _ => !self.is_liquid(),
+        }
+    }
+
+    /// Liquids flow, can't be mined, and players move through them
+    pub const fn is_liquid(&self) -> bool {
+        matches!(self, BlockType::Water | BlockType::Lava)
+    }
+
+    /// Blocks that fall when there is nothing under them, and sink through liquids
+    pub const fn falls(&self) -> bool {
+        matches!(self, BlockType::Sand)
+    }
+
+    /// How many network ticks of mining it takes to break a block of this type
+    pub const fn durability(&self) -> u8 {
+        match self {
+            BlockType::Leaves => 4,
+            BlockType::Sand | BlockType::PalmTreeBlock => 6,
+            BlockType::Clay | BlockType::Trunk => 10,
+            BlockType::Limestone | BlockType::Coal => 15,
+            BlockType::Diabase | BlockType::Gabbro | BlockType::Iron => 24,
+            BlockType::Basalt | BlockType::Granite | BlockType::Quartz => 30,
+            BlockType::Labradorite | BlockType::Peridot => 40,
+            BlockType::CaveVoid | BlockType::Water | BlockType::Lava => 1,
+        }
+    }
+
+    /// How many looks blocks of this type can have, see BlockState::variant
+    pub const fn variants(&self) -> u8 {
+        match self {
+            BlockType::Leaves => 3,
+            _ => 1,
+        }
+    }
+}
+
+/// Generate the surface chunk at chunk_x 0 and add it to the terrain
+pub fn create_surface_chunk(generator: &dyn ChunkGenerator, seed: u64, terrain: &mut Terrain) {
+    // chunk will get rendered by client
+    let chunk = generator.surface_chunk(seed, 0);
+
+    terrain.insert_chunk(chunk);
+}
+
+/// All chunk positions (chunk_x, chunk_number) that overlap an area of blocks
+/// The area is centered on the block at center, and can stick out above the surface
+pub fn chunks_in_area(center: BlockPos, radius_x: usize, radius_y: usize) -> Vec<(i64, u64)> {
+    let (radius_x, radius_y) = (radius_x as i64, radius_y as i64);
+    // the part of the area above the surface has no chunks
+    let top_left = BlockPos::new(center.x - radius_x, (center.y - radius_y).max(0));
+    let (top, bottom) = match (top_left.chunk(), center.offset(radius_x, radius_y).chunk()) {
+        (Some(top), Some(bottom)) => (top, bottom),
+        _ => return Vec::new(),
+    };
+
+    let mut chunks = Vec::new();
+    for chunk_x in top.chunk_x..=bottom.chunk_x {
+        for chunk_number in top.chunk_number..=bottom.chunk_number {
+            chunks.push((chunk_x, chunk_number));
+        }
+    }
+    chunks
+}
+
+fn print_encoding_sizes(terrain: &Terrain) {
+    match bincode::encode_to_vec(Block::new(BlockType::Limestone), BINCODE_CONFIG) {
+        Ok(block) => info!("a sandstone block is {} byte(s)", block.len()),
+        Err(e) => error!("unable to encode block: {}", e),
+    }
+
+    // the client has no generator, so measure one of the chunks it got from the server
+    if let Some(chunk) = terrain.chunks().next() {
+        match bincode::encode_to_vec(chunk, BINCODE_CONFIG) {
+            Ok(encoded) => info!(
+                "chunk ({}, {}) is {} bytes",
+                chunk.chunk_x,
+                chunk.chunk_number,
+                encoded.len()
+            ),
+            Err(e) => error!("unable to encode chunk: {}", e),
+        }
+    }
+}
+
+/// Make the F3 key dump client terrain information
+fn f3_prints_terrain_info(input: Res<Input<KeyCode>>, terrain: Res<Terrain>) {
+    if !input.just_pressed(KeyCode::F3) {
+        return;
+    }
+
+    let mut id_str = String::new();
+
+    for chunk in terrain.chunks() {
+        id_str.push_str(&format!("({}, {}), ", chunk.chunk_x, chunk.chunk_number));
+    }
+
+    info!("terrain has {} chunks: {}", terrain.len(), id_str);
+}
+
+/// Make the F2 key dump the encoded terrain
+fn f2_prints_terrain_encoding(input: Res<Input<KeyCode>>, terrain: Res<Terrain>) {
+    // return early if F2 was not just pressed
+    if !input.just_pressed(KeyCode::F2) {
+        return;
+    }
+
+    print_encoding_sizes(&terrain);
+
+    // try to encode, allocating a vec
+    // in a real packet, we should use a pre-allocated array and encode into its slice
+    match bincode::encode_to_vec(terrain.as_ref(), BINCODE_CONFIG) {
+        Ok(encoded_vec) => {
+            // we have successfully encoded
+            let mut encoded_str = String::new();
+            // print one long string of bytes, hex representation
+            for byte in &encoded_vec {
+                encoded_str.push_str(&format!("{:02x} ", byte));
+            }
+            info!(
+                "current terrain is {} bytes: {}",
+                encoded_vec.len(),
+                encoded_str
+            );
+        }
+        Err(e) => {
+            // unable to encode
+            error!("unable to encode terrain, {}", e);
+        }
+    }
+}
+
+/// unit tests
+#[cfg(test)]
+mod tests {
+    use super::{generator::*, *};
+    use crate::network::BUFFER_SIZE;
+
+    #[test]
+    fn encode_decode_block() {
+        let original = Block::new(BlockType::Limestone);
+        let encoded = bincode::encode_to_vec(original, BINCODE_CONFIG).unwrap();
+        let decoded: Block = bincode::decode_from_slice(&encoded, BINCODE_CONFIG)
+            .unwrap()
+            .0;
+        assert_eq!(original, decoded);
+    }
+
+    #[test]
+    fn encode_decode_chunk() {
+        let generator = DefaultGenerator::new().unwrap();
+        let original = {
+            let mut chunk = generator.depth_chunk(TEST_SEED, -1, 1);
+            // change some block
+            chunk.blocks[1][1] = Some(Block::new(BlockType::Limestone));
+            chunk
+        };
+        let encoded = bincode::encode_to_vec(&original, BINCODE_CONFIG).unwrap();
+        let decoded: Chunk = bincode::decode_from_slice(&encoded, BINCODE_CONFIG)
+            .unwrap()
+            .0;
+        assert_eq!(original, decoded);
+
+        // every kind of chunk, including ones with runs longer than a row and single blocks
+        let round_trip = |original: Chunk| {
+            let encoded = bincode::encode_to_vec(&original, BINCODE_CONFIG).unwrap();
+            let (decoded, size): (Chunk, usize) =
+                bincode::decode_from_slice(&encoded, BINCODE_CONFIG).unwrap();
+            assert_eq!(original, decoded);
+            assert_eq!(
+                (decoded.chunk_x, decoded.chunk_number),
+                (original.chunk_x, original.chunk_number)
+            );
+            assert_eq!(size, encoded.len());
+        };
+        let mut checkerboard = Chunk::empty(3, 7);
+        for (i, block) in checkerboard.blocks.iter_mut().flatten().enumerate() {
+            if i % 2 == 0 {
+                *block = Some(Block::new(BlockType::Granite));
+            }
+        }
+        round_trip(checkerboard);
+        round_trip(Chunk::empty(-5, 2));
+        round_trip(generator.surface_chunk(TEST_SEED, 4));
+        round_trip(generator.depth_chunk(TEST_SEED, 0, 12));
+        round_trip(
+            GeneratorType::Void
+                .create()
+                .unwrap()
+                .surface_chunk(TEST_SEED, 0),
+        );
+    }
+
+    #[test]
+    fn block_state_survives_encoding() {
+        let mut chunk = DefaultGenerator::new()
+            .unwrap()
+            .depth_chunk(TEST_SEED, 2, 3);
+        let mut damaged = Block::new(BlockType::Granite);
+        damaged.state.damage = 7;
+        let mut leaves = Block::new(BlockType::Leaves);
+        leaves.state.variant = 2;
+        leaves.state.flags.insert(BlockFlags::PROTECTED);
+        chunk.blocks[0][0] = Some(damaged);
+        chunk.blocks[0][1] = Some(leaves);
+        chunk.blocks[0][2] = Some(Block::new(BlockType::Granite));
+
+        let encoded = bincode::encode_to_vec(&chunk, BINCODE_CONFIG).unwrap();
+        let decoded: Chunk = bincode::decode_from_slice(&encoded, BINCODE_CONFIG)
+            .unwrap()
+            .0;
+        assert_eq!(decoded.blocks[0][0], Some(damaged));
+        assert_eq!(decoded.blocks[0][1], Some(leaves));
+        assert!(decoded.blocks[0][1]
+            .unwrap()
+            .state
+            .flags
+            .contains(BlockFlags::PROTECTED));
+        // the same type with a different state is a different block
+        assert_ne!(decoded.blocks[0][2], Some(damaged));
+        assert_eq!(chunk, decoded);
+    }
+
+    #[test]
+    fn mining_breaks_blocks_at_their_durability() {
+        let mut falling = FallingBlocks::default();
+        let mut terrain = Terrain::from_iter([Chunk::empty(0, 1)]);
+        let y = CHUNK_HEIGHT as i64 + 5;
+        let (clay, sand) = (BlockPos::new(3, y), BlockPos::new(4, y));
+        terrain.set_block(clay, Some(Block::new(BlockType::Clay)));
+        let mut mine =
+            |terrain: &mut Terrain, position| server::mine_block(position, terrain, &mut falling);
+
+        for damage in 1..BlockType::Clay.durability() {
+            assert!(matches!(mine(&mut terrain, clay), Ok(None)));
+            assert_eq!(
+                terrain.get_block(clay).flatten().unwrap().state.damage,
+                damage
+            );
+        }
+        let mined = mine(&mut terrain, clay).unwrap().unwrap();
+        assert_eq!(mined.block_type, BlockType::Clay);
+        assert_eq!(terrain.get_block(clay), Some(None));
+        assert!(matches!(
+            mine(&mut terrain, clay),
+            Err(server::DestroyBlockError::BlockDoesntExist)
+        ));
+
+        // protected blocks don't even get damaged
+        let mut protected = Block::new(BlockType::Sand);
+        protected.state.flags.insert(BlockFlags::PROTECTED);
+        terrain.set_block(sand, Some(protected));
+        for _ in 0..BlockType::Sand.durability() {
+            assert!(matches!(
+                mine(&mut terrain, sand),
+                Err(server::DestroyBlockError::BlockIsProtected)
+            ));
+        }
+        assert_eq!(terrain.get_block(sand), Some(Some(protected)));
+    }
+
+    #[test]
+    fn bad_chunk_encodings_are_rejected() {
+        let decode = |runs: ChunkRuns| {
+            let encoded = bincode::encode_to_vec(runs, BINCODE_CONFIG).unwrap();
+            bincode::decode_from_slice::<Chunk, _>(&encoded, BINCODE_CONFIG)
+        };
+        let blocks = (CHUNK_WIDTH * CHUNK_HEIGHT) as u16;
+        let runs = |runs: Vec<(u16, u16)>| ChunkRuns {
+            chunk_x: 0,
+            chunk_number: 1,
+            palette: vec![None, Some(Block::new(BlockType::Sand))],
+            runs,
+        };
+
+        assert!(decode(runs(vec![(0, blocks - 1), (1, 1)])).is_ok());
+        // too few blocks
+        assert!(decode(runs(vec![(0, blocks - 1)])).is_err());
+        // too many blocks
+        assert!(decode(runs(vec![(0, blocks), (1, 1)])).is_err());
+        // not in the palette
+        assert!(decode(runs(vec![(0, blocks - 1), (2, 1)])).is_err());
+    }
+
+    #[test]
+    fn encode_decode_terrain() {
+        let original = {
+            let mut terrain = Terrain::new(&DefaultGenerator::new().unwrap(), TEST_SEED, 2);
+            // change some block
+            terrain.set_block(
+                BlockPos::new(1, CHUNK_HEIGHT as i64 + 1),
+                Some(Block::new(BlockType::Limestone)),
+            );
+            terrain
+        };
+        let encoded = bincode::encode_to_vec(&original, BINCODE_CONFIG).unwrap();
+        let decoded: Terrain = bincode::decode_from_slice(&encoded, BINCODE_CONFIG)
+            .unwrap()
+            .0;
+        assert_eq!(original, decoded);
+    }
+
+    #[test]
+    fn terrain_blocks_in_world_coordinates() {
+        // a window of chunks that doesn't start at chunk 0
+        let mut terrain: Terrain = [Chunk::empty(-1, 2), Chunk::empty(3, 5)]
+            .into_iter()
+            .collect();
+        assert_eq!(terrain.len(), 2);
+        assert_eq!(terrain.find_chunk(3, 5).unwrap().chunk_x, 3);
+        assert!(terrain.find_chunk(0, 0).is_none());
+
+        // the last block of chunk (-1, 2)
+        let last = BlockPos::new(-1, 3 * CHUNK_HEIGHT as i64 - 1);
+        assert_eq!(terrain.get_block(last), Some(None));
+        assert_eq!(
+            terrain.set_block(last, Some(Block::new(BlockType::Granite))),
+            Some(None)
+        );
+        assert_eq!(
+            terrain.find_chunk(-1, 2).unwrap().blocks[CHUNK_HEIGHT - 1][CHUNK_WIDTH - 1],
+            Some(Block::new(BlockType::Granite))
+        );
+        assert_eq!(
+            terrain.get_block(last),
+            Some(Some(Block::new(BlockType::Granite)))
+        );
+
+        // chunks that aren't loaded don't change
+        let unloaded = BlockPos::new(0, last.y);
+        assert_eq!(terrain.get_block(unloaded), None);
+        assert_eq!(
+            terrain.set_block(unloaded, Some(Block::new(BlockType::Granite))),
+            None
+        );
+        assert_eq!(terrain.len(), 2);
+        // and nothing is above the surface
+        assert_eq!(terrain.get_block(BlockPos::new(-1, -1)), None);
+
+        // replacing a chunk keeps one chunk per position
+        assert!(terrain.insert_chunk(Chunk::empty(-1, 2)).is_some());
+        assert_eq!(terrain.get_block(last), Some(None));
+        assert_eq!(terrain.len(), 2);
+    }
+
+    #[test]
+    fn only_changed_chunks_need_saving() {
+        let mut terrain = Terrain::from_iter([Chunk::empty(0, 0), Chunk::empty(0, 1)]);
+        assert_eq!(terrain.dirty_chunks(), vec![(0, 0), (0, 1)]);
+        terrain.mark_saved(0, 0);
+        terrain.mark_saved(0, 1);
+        assert!(terrain.dirty_chunks().is_empty());
+
+        // looking doesn't change anything
+        let block = BlockPos::new(5, CHUNK_HEIGHT as i64 + 5);
+        assert_eq!(terrain.get_block(block), Some(None));
+        assert!(terrain.find_chunk(0, 0).is_some());
+        assert!(terrain.dirty_chunks().is_empty());
+
+        terrain.set_block(block, Some(Block::new(BlockType::Sand)));
+        assert_eq!(terrain.dirty_chunks(), vec![(0, 1)]);
+        assert!(terrain.is_dirty(0, 1) && !terrain.is_dirty(0, 0));
+
+        // chunks that aren't loaded anymore don't get saved
+        terrain.remove_chunk(0, 1);
+        assert!(terrain.dirty_chunks().is_empty());
+        terrain.insert_chunk(Chunk::empty(1, 0));
+        assert_eq!(terrain.dirty_chunks(), vec![(1, 0)]);
+    }
+
+    #[test]
+    fn chunks_depend_on_seed() {
+        let g = DefaultGenerator::new().unwrap();
+        // same seed, same chunk
+        assert_eq!(
+            g.depth_chunk(TEST_SEED, 0, 2),
+            g.depth_chunk(TEST_SEED, 0, 2)
+        );
+        assert_eq!(g.surface_chunk(TEST_SEED, 0), g.surface_chunk(TEST_SEED, 0));
+        // different seed, different chunk
+        assert_ne!(
+            g.depth_chunk(TEST_SEED, 0, 2),
+            g.depth_chunk(TEST_SEED + 1, 0, 2)
+        );
+        assert_ne!(
+            g.surface_chunk(TEST_SEED, 0),
+            g.surface_chunk(TEST_SEED + 1, 0)
+        );
+    }
+
+    #[test]
+    fn generators_fill_in_chunk_number() {
+        for generator_type in [
+            GeneratorType::Default,
+            GeneratorType::Flat,
+            GeneratorType::Void,
+        ] {
+            let g = generator_type.create().unwrap();
+            let surface = g.chunk(TEST_SEED, -2, 0);
+            assert_eq!((surface.chunk_x, surface.chunk_number), (-2, 0));
+            let depth = g.chunk(TEST_SEED, 5, 3);
+            assert_eq!((depth.chunk_x, depth.chunk_number), (5, 3));
+        }
+    }
+
+    #[test]
+    fn world_x_to_chunk() {
+        let width = CHUNK_WIDTH as i64;
+        let to_chunk = |x| {
+            let block = BlockPos::new(x, 0);
+            (block.chunk_x(), block.in_chunk().0)
+        };
+        assert_eq!(to_chunk(0), (0, 0));
+        assert_eq!(to_chunk(width), (1, 0));
+        assert_eq!(to_chunk(-1), (-1, CHUNK_WIDTH - 1));
+        assert_eq!(to_chunk(-width), (-1, 0));
+        assert_eq!(to_chunk(-width - 1).0, -2);
+    }
+
+    #[test]
+    fn areas_above_the_surface_have_no_chunks() {
+        let mut around_spawn = chunks_in_area(BlockPos::new(0, -2), 4, 4);
+        around_spawn.sort();
+        assert_eq!(around_spawn, vec![(-1, 0), (0, 0)]);
+        assert!(chunks_in_area(BlockPos::new(0, -10), 4, 4).is_empty());
+
+        let deep = BlockPos::new(10, 2 * CHUNK_HEIGHT as i64);
+        assert_eq!(chunks_in_area(deep, 1, 1), vec![(0, 1), (0, 2)]);
+    }
+
+    #[test]
+    fn surface_continues_across_chunks() {
+        // the first row with ground (not a tree) in some column
+        let ground_y = |chunk: &Chunk, x: usize| {
+            (0..CHUNK_HEIGHT)
+                .find(|y| match chunk.blocks[*y][x] {
+                    Some(block) => !matches!(
+                        block.block_type,
+                        BlockType::PalmTreeBlock | BlockType::Leaves | BlockType::Trunk
+                    ),
+                    None => false,
+                })
+                .unwrap() as i64
+        };
+
+        let generator = DefaultGenerator::new().unwrap();
+        for chunk_x in -2..2 {
+            let left = generator.surface_chunk(TEST_SEED, chunk_x);
+            let right = generator.surface_chunk(TEST_SEED, chunk_x + 1);
+            let step = ground_y(&left, CHUNK_WIDTH - 1) - ground_y(&right, 0);
+            assert!(
+                step.abs() <= 2,
+                "cliff between chunks {} and {}",
+                chunk_x,
+                chunk_x + 1
+            );
+        }
+    }
+
+    #[test]
+    fn size_sanity_check() {
+        let generator = DefaultGenerator::new().unwrap();
+        let block_size = bincode::encode_to_vec(Block::new(BlockType::Limestone), BINCODE_CONFIG)
+            .unwrap()
+            .len();
+        let chunk_size =
+            bincode::encode_to_vec(generator.surface_chunk(TEST_SEED, 0), BINCODE_CONFIG)
+                .unwrap()
+                .len();
+        let terrain_size =
+            bincode::encode_to_vec(Terrain::new(&generator, TEST_SEED, 1), BINCODE_CONFIG)
+                .unwrap()
+                .len();
+        assert!(terrain_size > chunk_size);
+        assert!(terrain_size > block_size);
+        assert!(chunk_size > block_size);
+
+        // chunks are a lot smaller than a byte per block
+        let blocks = CHUNK_WIDTH * CHUNK_HEIGHT;
+        assert!(
+            chunk_size < blocks / 4,
+            "surface chunk is {} bytes",
+            chunk_size
+        );
+        let depth_size =
+            bincode::encode_to_vec(generator.depth_chunk(TEST_SEED, 0, 6), BINCODE_CONFIG)
+                .unwrap()
+                .len();
+        assert!(
+            depth_size < blocks / 2,
+            "depth chunk is {} bytes",
+            depth_size
+        );
+        let empty_size = bincode::encode_to_vec(Chunk::empty(0, 1), BINCODE_CONFIG)
+            .unwrap()
+            .len();
+        assert!(empty_size < 16, "empty chunk is {} bytes", empty_size);
+
+        // a full baseline fits in one packet
+        let baseline_size = bincode::encode_to_vec(
+            WorldDelta::NewChunks(
+                (0..3)
+                    .flat_map(|chunk_x| (0..3).map(move |n| (chunk_x, n)))
+                    .map(|(chunk_x, n)| generator.chunk(TEST_SEED, chunk_x, n))
+                    .collect(),
+            ),
+            BINCODE_CONFIG,
+        )
+        .unwrap()
+        .len();
+        assert!(
+            baseline_size < BUFFER_SIZE / 2,
+            "baseline is {} bytes",
+            baseline_size
+        );
+    }
+}
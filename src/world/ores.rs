@@ -0,0 +1,327 @@
+use bincode::{Decode, Encode};
+use serde::Deserialize;
+use std::iter;
+
+use super::*;
+use crate::procedural_functions::{dist_sq, dist_to_segment, generate_random_vein};
+
+/// How many chunks down ores reach their deep rarity
+pub const DEEP_ORE_DEPTH: u64 = 12;
+
+/// A kind of ore that veins can be made of
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy, EnumIter, Deserialize)]
+pub enum OreType {
+    Clay,
+    Coal,
+    Iron,
+    Quartz,
+    Labradorite,
+    Peridot,
+}
+
+impl OreType {
+    /// The block that veins of this ore are made of
+    pub fn block(&self) -> BlockType {
+        match self {
+            Self::Clay => BlockType::Clay,
+            Self::Coal => BlockType::Coal,
+            Self::Iron => BlockType::Iron,
+            Self::Quartz => BlockType::Quartz,
+            Self::Labradorite => BlockType::Labradorite,
+            Self::Peridot => BlockType::Peridot,
+        }
+    }
+
+    /// The shapes that veins of this ore can have, picked from evenly
+    pub fn vein_shapes(&self) -> &'static [VeinShape] {
+        match self {
+            Self::Clay => &[VeinShape::Blob],
+            Self::Coal => &[VeinShape::Line, VeinShape::Blob],
+            Self::Iron => &[VeinShape::Line, VeinShape::Branching],
+            Self::Quartz => &[VeinShape::Branching, VeinShape::Gems],
+            Self::Labradorite => &[VeinShape::Blob, VeinShape::Gems],
+            Self::Peridot => &[VeinShape::Gems],
+        }
+    }
+}
+
+/// What the blocks of a vein look like
+#[derive(Encode, Decode, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum VeinShape {
+    /// A thick line from start to end
+    Line,
+    /// A lumpy clump of circles around the start
+    Blob,
+    /// A line from start to end with thinner lines splitting off of it
+    Branching,
+    /// Single blocks scattered around the start
+    Gems,
+}
+
+/// How common an ore is in a biome
+/// The weight changes smoothly from the surface down to DEEP_ORE_DEPTH, and stays the same below
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct OreRarity {
+    pub ore: OreType,
+    pub shallow_weight: f32,
+    pub deep_weight: f32,
+}
+
+impl OreRarity {
+    pub fn weight_at(&self, depth: u64) -> f32 {
+        let deepness = depth.min(DEEP_ORE_DEPTH) as f32 / DEEP_ORE_DEPTH as f32;
+        self.shallow_weight * (1. - deepness) + self.deep_weight * deepness
+    }
+}
+
+/// Pick an ore from a rarity table, roll should be random in [0, 1)
+pub fn choose_ore(rarities: &[OreRarity], depth: u64, roll: f32) -> OreType {
+    let total: f32 = rarities.iter().map(|r| r.weight_at(depth)).sum();
+
+    let mut target = roll * total;
+    for rarity in rarities {
+        let weight = rarity.weight_at(depth);
+        if target < weight {
+            return rarity.ore;
+        }
+        target -= weight;
+    }
+
+    // only reachable through float rounding
+    rarities.last().unwrap().ore
+}
+
+/// Represents an ore vein
+/// Positions are relative to the chunk the vein started in
+#[derive(Encode, Decode, Debug, PartialEq, Clone)]
+pub struct Vein {
+    pub ore_type: OreType,
+    pub shape: VeinShape,
+    pub chunk_x: i64,
+    pub chunk_number: u64,
+    pub start_x: usize,
+    pub start_y: usize,
+    pub end_x: i16, // i16 because they can hypothetically be negative - which won't break anything
+    pub end_y: i16,
+    pub thickness_sq: f32, // squared thickness - so we don't need to do square roots
+    /// Circle centers for blobs, branch ends for branching veins, and the blocks of gems
+    pub points: Vec<(i16, i16)>,
+}
+
+impl Vein {
+    pub fn new(
+        seed: u64,
+        chunk_x: i64,
+        chunk_number: u64,
+        vein_number: u64,
+        biome: &ChunkBiomes,
+    ) -> Self {
+        generate_random_vein(seed, chunk_x, chunk_number, vein_number, biome)
+    }
+
+    /// Position of a block in some chunk, relative to the chunk that this vein started in
+    pub fn local_position(
+        &self,
+        chunk_x: i64,
+        chunk_number: u64,
+        x: usize,
+        y: usize,
+    ) -> (f32, f32) {
+        let x_offset = (chunk_x - self.chunk_x) * CHUNK_WIDTH as i64;
+        let y_offset = (chunk_number as i64 - self.chunk_number as i64) * CHUNK_HEIGHT as i64;
+        ((x as i64 + x_offset) as f32, (y as i64 + y_offset) as f32)
+    }
+
+    /// The box (min_x, min_y, max_x, max_y) around all of the vein's blocks, in local positions
+    pub fn bounds(&self) -> (f32, f32, f32, f32) {
+        let reach = self.thickness_sq.sqrt() + 1.;
+        let start = (self.start_x as f32, self.start_y as f32);
+        let end = (self.end_x as f32, self.end_y as f32);
+        let points = self.points.iter().map(|(px, py)| (*px as f32, *py as f32));
+
+        iter::once(start).chain(iter::once(end)).chain(points).fold(
+            (f32::MAX, f32::MAX, f32::MIN, f32::MIN),
+            |(min_x, min_y, max_x, max_y), (x, y)| {
+                (
+                    min_x.min(x - reach),
+                    min_y.min(y - reach),
+                    max_x.max(x + reach),
+                    max_y.max(y + reach),
+                )
+            },
+        )
+    }
+
+    /// Whether any block of this vein could be in a chunk
+    pub fn reaches(&self, chunk_x: i64, chunk_number: u64) -> bool {
+        let (min_x, min_y, max_x, max_y) = self.bounds();
+        let (left, top) = self.local_position(chunk_x, chunk_number, 0, 0);
+        max_x >= left
+            && min_x < left + CHUNK_WIDTH as f32
+            && max_y >= top
+            && min_y < top + CHUNK_HEIGHT as f32
+    }
+
+    /// Whether the block at a local position (see local_position) is part of this vein
+    pub fn contains(&self, x: f32, y: f32) -> bool {
+        let start = (self.start_x as f32, self.start_y as f32);
+        let end = (self.end_x as f32, self.end_y as f32);
+        let points = self.points.iter().map(|(px, py)| (*px as f32, *py as f32));
+
+        match self.shape {
+            VeinShape::Line => dist_to_segment(start, end, x, y) < self.thickness_sq / 2.,
+            VeinShape::Blob => iter::once(start)
+                .chain(points)
+                .any(|(px, py)| dist_sq(x, y, px, py) < self.thickness_sq),
+            VeinShape::Branching => {
+                if dist_to_segment(start, end, x, y) < self.thickness_sq / 2. {
+                    return true;
+                }
+
+                // branches split off evenly spaced along the main line, and are half as thick
+                let branch_count = self.points.len() as f32;
+                points.enumerate().any(|(i, branch_end)| {
+                    let along = (i as f32 + 1.) / (branch_count + 1.);
+                    let branch_start = (
+                        start.0 + (end.0 - start.0) * along,
+                        start.1 + (end.1 - start.1) * along,
+                    );
+                    dist_to_segment(branch_start, branch_end, x, y) < self.thickness_sq / 4.
+                })
+            }
+            VeinShape::Gems => points.into_iter().any(|(px, py)| px == x && py == y),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{biomes::*, generator::*, TEST_SEED};
+    use strum::IntoEnumIterator;
+
+    /// A made up row of chunks that is one biome all the way through
+    fn test_biomes(biome: &Biome) -> ChunkBiomes<'_> {
+        ChunkBiomes {
+            upper: biome,
+            lower: biome,
+            change_depth: 0,
+        }
+    }
+
+    #[test]
+    fn veins_depend_only_on_seed_and_position() {
+        let table = test_table();
+        let biomes = test_biomes(table.biome("felsic"));
+        for vein_number in 0..16 {
+            assert_eq!(
+                Vein::new(TEST_SEED, -3, 7, vein_number, &biomes),
+                Vein::new(TEST_SEED, -3, 7, vein_number, &biomes)
+            );
+        }
+        let veins = |seed| {
+            (0..16)
+                .map(|n| Vein::new(seed, -3, 7, n, &biomes))
+                .collect::<Vec<_>>()
+        };
+        assert_ne!(veins(TEST_SEED), veins(TEST_SEED + 1));
+    }
+
+    #[test]
+    fn rarity_changes_with_depth() {
+        let rarity = OreRarity {
+            ore: OreType::Iron,
+            shallow_weight: 1.,
+            deep_weight: 5.,
+        };
+        assert_eq!(rarity.weight_at(0), 1.);
+        assert_eq!(rarity.weight_at(DEEP_ORE_DEPTH), 5.);
+        assert_eq!(rarity.weight_at(DEEP_ORE_DEPTH * 4), 5.);
+        assert!(rarity.weight_at(DEEP_ORE_DEPTH / 2) > 1.);
+        assert!(rarity.weight_at(DEEP_ORE_DEPTH / 2) < 5.);
+
+        let rarities = [
+            OreRarity {
+                ore: OreType::Coal,
+                shallow_weight: 1.,
+                deep_weight: 0.,
+            },
+            rarity,
+        ];
+        // coal can't be chosen once it has no weight
+        assert_eq!(choose_ore(&rarities, 0, 0.1), OreType::Coal);
+        assert_eq!(choose_ore(&rarities, DEEP_ORE_DEPTH, 0.), OreType::Iron);
+        assert_eq!(choose_ore(&rarities, DEEP_ORE_DEPTH, 0.999), OreType::Iron);
+    }
+
+    #[test]
+    fn every_biome_has_ores_at_every_depth() {
+        for biome in &test_table().biomes {
+            let rarities = &biome.ores;
+            for depth in 0..=DEEP_ORE_DEPTH + 1 {
+                let total: f32 = rarities.iter().map(|r| r.weight_at(depth)).sum();
+                assert!(total > 0., "{} has no ores at depth {}", biome.name, depth);
+            }
+            assert!(rarities.len() > 1, "{} only has one ore", biome.name);
+        }
+    }
+
+    #[test]
+    fn vein_shapes() {
+        let table = test_table();
+        let biomes = test_biomes(table.biome("mafic"));
+        let veins: Vec<Vein> = (0..64)
+            .map(|n| Vein::new(TEST_SEED, 0, DEEP_ORE_DEPTH, n, &biomes))
+            .collect();
+
+        for vein in &veins {
+            assert!(vein.ore_type.vein_shapes().contains(&vein.shape));
+
+            let (start_x, start_y) = (vein.start_x as f32, vein.start_y as f32);
+            match vein.shape {
+                VeinShape::Gems => {
+                    // gems are single blocks, and nothing around them
+                    let mut gem_blocks = 0;
+                    for x in (vein.start_x as i16 - 16)..(vein.start_x as i16 + 16) {
+                        for y in (vein.start_y as i16 - 16)..(vein.start_y as i16 + 16) {
+                            if vein.contains(x as f32, y as f32) {
+                                assert!(vein.points.contains(&(x, y)));
+                                gem_blocks += 1;
+                            }
+                        }
+                    }
+                    assert!(gem_blocks > 0);
+                }
+                _ => assert!(vein.contains(start_x, start_y)),
+            }
+        }
+
+        // mafic rock has more than one ore and shape
+        for shape in [VeinShape::Blob, VeinShape::Gems] {
+            assert!(
+                veins.iter().any(|v| v.shape == shape),
+                "no {:?} veins",
+                shape
+            );
+        }
+        assert!(veins.iter().any(|v| v.ore_type != veins[0].ore_type));
+    }
+
+    #[test]
+    fn chunks_have_many_ores() {
+        let generator = DefaultGenerator::new().unwrap();
+        let mut ores = Vec::new();
+        for chunk_x in 0..3 {
+            let chunk = generator.depth_chunk(TEST_SEED, chunk_x, 2);
+            for block in chunk.blocks.iter().flatten().flatten() {
+                let ore = OreType::iter().find(|ore| ore.block() == block.block_type);
+                if let Some(ore) = ore {
+                    if !ores.contains(&ore) {
+                        ores.push(ore);
+                    }
+                }
+            }
+        }
+        assert!(ores.len() > 1, "only found {:?}", ores);
+    }
+}
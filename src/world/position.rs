@@ -0,0 +1,201 @@
+use bevy::prelude::*;
+use bincode::{Decode, Encode};
+
+use super::{CHUNK_HEIGHT, CHUNK_WIDTH};
+
+/// How big a block (and a player) is in rendering coordinates, in pixels
+pub const BLOCK_SIZE: f32 = 32.;
+
+/// Where a block is in the world, counted in blocks
+/// x grows to the right and is negative left of the spawn column,
+/// y grows downwards from the top of the surface chunks and is negative above them,
+/// where there are no chunks
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Encode, Decode)]
+pub struct BlockPos {
+    pub x: i64,
+    pub y: i64,
+}
+
+impl BlockPos {
+    pub const fn new(x: i64, y: i64) -> Self {
+        Self { x, y }
+    }
+
+    /// The block dx to the right of and dy below this one
+    pub const fn offset(&self, dx: i64, dy: i64) -> Self {
+        Self::new(self.x + dx, self.y + dy)
+    }
+
+    /// The column of chunks that the block is in
+    pub const fn chunk_x(&self) -> i64 {
+        self.x.div_euclid(CHUNK_WIDTH as i64)
+    }
+
+    /// The chunk that the block is in, None above the surface
+    pub fn chunk(&self) -> Option<ChunkPos> {
+        let (_, y) = self.terrain_coords()?;
+        Some(ChunkPos::new(self.chunk_x(), (y / CHUNK_HEIGHT) as u64))
+    }
+
+    /// Where the block is inside of its chunk, (x, y) from the chunk's top left block
+    pub const fn in_chunk(&self) -> (usize, usize) {
+        (
+            self.x.rem_euclid(CHUNK_WIDTH as i64) as usize,
+            self.y.rem_euclid(CHUNK_HEIGHT as i64) as usize,
+        )
+    }
+
+    /// The block as the (x, y) that the terrain simulations (liquids, falling blocks) use,
+    /// with y as a row counted from the top of the surface chunks; None above the surface
+    pub fn terrain_coords(&self) -> Option<(i64, usize)> {
+        usize::try_from(self.y).ok().map(|y| (self.x, y))
+    }
+
+    /// The middle of the block
+    pub fn center(&self) -> WorldPos {
+        WorldPos::new(self.x as f32, -self.y as f32)
+    }
+}
+
+impl From<(i64, usize)> for BlockPos {
+    fn from((x, y): (i64, usize)) -> Self {
+        Self::new(x, y as i64)
+    }
+}
+
+/// Where a chunk is in the world, counted in chunks
+/// Chunks only go down from the surface, so chunk_number can't be negative
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash, PartialOrd, Ord, Encode, Decode)]
+pub struct ChunkPos {
+    /// starting column for blocks is chunk_x * CHUNK_WIDTH, can be negative
+    pub chunk_x: i64,
+    /// starting row for blocks is chunk_number * CHUNK_HEIGHT
+    pub chunk_number: u64,
+}
+
+impl ChunkPos {
+    pub const fn new(chunk_x: i64, chunk_number: u64) -> Self {
+        Self {
+            chunk_x,
+            chunk_number,
+        }
+    }
+
+    /// The block at (x, y) inside of the chunk, from its top left block
+    pub const fn block(&self, x: usize, y: usize) -> BlockPos {
+        BlockPos::new(
+            self.chunk_x * CHUNK_WIDTH as i64 + x as i64,
+            (self.chunk_number as usize * CHUNK_HEIGHT + y) as i64,
+        )
+    }
+
+    /// How many blocks (horizontally plus vertically) a block is from the closest block of the chunk
+    pub fn distance_to(&self, block: BlockPos) -> i64 {
+        let top_left = self.block(0, 0);
+        let bottom_right = self.block(CHUNK_WIDTH - 1, CHUNK_HEIGHT - 1);
+
+        let x_distance = (top_left.x - block.x).max(block.x - bottom_right.x).max(0);
+        let y_distance = (top_left.y - block.y).max(block.y - bottom_right.y).max(0);
+        x_distance + y_distance
+    }
+}
+
+impl From<ChunkPos> for (i64, u64) {
+    fn from(chunk: ChunkPos) -> Self {
+        (chunk.chunk_x, chunk.chunk_number)
+    }
+}
+
+/// A point in the world, counted in blocks, like where a player is
+/// y goes up like it does for rendering, so it is negative below the surface,
+/// and blocks are centered on whole numbers
+#[derive(Copy, Clone, Debug, Default, PartialEq, Encode, Decode)]
+pub struct WorldPos {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl WorldPos {
+    pub const fn new(x: f32, y: f32) -> Self {
+        Self { x, y }
+    }
+
+    /// The point at a position in rendering coordinates, in pixels
+    pub fn from_render(position: Vec2) -> Self {
+        let position = position / BLOCK_SIZE;
+        Self::new(position.x, position.y)
+    }
+
+    /// Where the point is in rendering coordinates, in pixels
+    pub fn to_render(self) -> Vec2 {
+        Vec2::new(self.x, self.y) * BLOCK_SIZE
+    }
+
+    /// The block that the point is inside of
+    /// Blocks are centered on whole numbers, so this rounds to the closest one
+    pub fn block(&self) -> BlockPos {
+        BlockPos::new(self.x.round() as i64, (-self.y).round() as i64)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn blocks_find_their_chunk() {
+        let width = CHUNK_WIDTH as i64;
+        let height = CHUNK_HEIGHT as i64;
+        let chunk = |x, y| BlockPos::new(x, y).chunk().map(<(i64, u64)>::from);
+
+        assert_eq!(chunk(0, 0), Some((0, 0)));
+        assert_eq!(chunk(width, height), Some((1, 1)));
+        assert_eq!(chunk(-1, height - 1), Some((-1, 0)));
+        assert_eq!(chunk(-width - 1, 0), Some((-2, 0)));
+        assert_eq!(BlockPos::new(-1, 5).in_chunk(), (CHUNK_WIDTH - 1, 5));
+        assert_eq!(BlockPos::new(-width, 0).in_chunk(), (0, 0));
+
+        // above the surface there are no chunks
+        assert_eq!(chunk(0, -1), None);
+        assert_eq!(BlockPos::new(3, -1).chunk_x(), 0);
+        assert_eq!(BlockPos::new(3, -1).terrain_coords(), None);
+        assert_eq!(BlockPos::new(3, 7).terrain_coords(), Some((3, 7)));
+
+        // and back again
+        let block = BlockPos::new(-200, 150);
+        let (x, y) = block.in_chunk();
+        assert_eq!(block.chunk().unwrap().block(x, y), block);
+    }
+
+    #[test]
+    fn points_round_to_the_closest_block() {
+        // the block at the top of the surface goes from half a block above y = 0 to half below
+        assert_eq!(WorldPos::new(0.4, 0.4).block(), BlockPos::new(0, 0));
+        assert_eq!(WorldPos::new(0.6, -0.6).block(), BlockPos::new(1, 1));
+        assert_eq!(WorldPos::new(-0.6, 0.6).block(), BlockPos::new(-1, -1));
+        assert_eq!(WorldPos::new(-2., -3.).block(), BlockPos::new(-2, 3));
+
+        // clicking on a block picks the block that is drawn there
+        for block in [BlockPos::new(5, 9), BlockPos::new(-7, -2)] {
+            let render = block.center().to_render() + Vec2::new(15., -15.);
+            assert_eq!(WorldPos::from_render(render).block(), block);
+        }
+        assert_eq!(
+            BlockPos::new(2, 3).center().to_render(),
+            Vec2::new(2. * BLOCK_SIZE, -3. * BLOCK_SIZE)
+        );
+    }
+
+    #[test]
+    fn distance_to_chunks() {
+        let chunk = ChunkPos::new(1, 2);
+        let top_left = chunk.block(0, 0);
+        assert_eq!(chunk.distance_to(top_left), 0);
+        assert_eq!(chunk.distance_to(top_left.offset(5, 5)), 0);
+        assert_eq!(chunk.distance_to(top_left.offset(-3, -4)), 7);
+        assert_eq!(
+            chunk.distance_to(chunk.block(CHUNK_WIDTH - 1, 0).offset(2, 0)),
+            2
+        );
+    }
+}
@@ -0,0 +1,477 @@
+use bevy::{
+    render::{
+        mesh::{Indices, VertexAttributeValues},
+        render_resource::{Extent3d, PrimitiveTopology, TextureDimension, TextureFormat},
+        texture::ImageSampler,
+    },
+    sprite::{MaterialMesh2dBundle, Mesh2dHandle},
+};
+use image::{imageops, Rgba, RgbaImage};
+use std::{collections::HashSet, path::Path};
+use strum::IntoEnumIterator;
+
+use super::*;
+use crate::{CharacterCamera, WIN_H, WIN_W};
+
+/// How far past the edges of the screen chunks get drawn, in pixels,
+/// so that they are ready before they scroll into view
+const VIEW_MARGIN: f32 = 8. * BLOCK_SIZE;
+/// Blocks whose texture can't be loaded get a tile of this color
+const MISSING_COLOR: Rgba<u8> = Rgba([255, 0, 255, 255]);
+/// How much darker a block is drawn right before mining breaks it
+const MAX_DAMAGE_SHADE: f32 = 0.6;
+/// What the texture gets multiplied by for each variant of leaves
+const LEAF_TINTS: [[f32; 3]; 3] = [[1., 1., 1.], [1., 0.95, 0.5], [1., 0.55, 0.35]];
+
+/// Where a block type's texture is in the atlas
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AtlasTile {
+    /// Top left corner of the texture in the atlas, in UV coordinates
+    uv_min: Vec2,
+    /// Bottom right corner of the texture in the atlas, in UV coordinates
+    uv_max: Vec2,
+    /// Size of the texture in pixels, which is how big the block gets drawn,
+    /// so textures bigger than a block (like palm trees) stick out around it
+    size: Vec2,
+}
+
+/// Resource on the client with every block texture packed into one image,
+/// so that a whole chunk can be drawn as one mesh
+pub struct BlockAtlas {
+    material: Handle<ColorMaterial>,
+    tiles: HashMap<BlockType, AtlasTile>,
+}
+
+impl BlockAtlas {
+    /// Load the block textures from the assets folder and pack them into an atlas
+    pub fn new(
+        assets: &Path,
+        images: &mut Assets<Image>,
+        materials: &mut Assets<ColorMaterial>,
+    ) -> Self {
+        let (atlas, tiles) = pack_textures(assets);
+
+        let mut image = Image::new(
+            Extent3d {
+                width: atlas.width(),
+                height: atlas.height(),
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            atlas.into_raw(),
+            TextureFormat::Rgba8UnormSrgb,
+        );
+        // neighbouring textures would bleed into each other with linear filtering
+        image.sampler_descriptor = ImageSampler::nearest();
+
+        Self {
+            material: materials.add(ColorMaterial::from(images.add(image))),
+            tiles,
+        }
+    }
+}
+
+/// Pack the texture of every block type side by side into one image
+/// Block types without a texture (like cave voids) don't get a tile, so they aren't drawn
+fn pack_textures(assets: &Path) -> (RgbaImage, HashMap<BlockType, AtlasTile>) {
+    let textures: Vec<(BlockType, RgbaImage)> = BlockType::iter()
+        .filter(|block_type| !block_type.image_file_path().is_empty())
+        .map(|block_type| {
+            let texture = match image::open(assets.join(block_type.image_file_path())) {
+                Ok(texture) => texture.to_rgba8(),
+                Err(e) => {
+                    warn!("unable to load texture for {:?}, {}", block_type, e);
+                    RgbaImage::from_pixel(BLOCK_SIZE as u32, BLOCK_SIZE as u32, MISSING_COLOR)
+                }
+            };
+            (block_type, texture)
+        })
+        .collect();
+
+    let width = textures.iter().map(|(_, t)| t.width()).sum::<u32>().max(1);
+    let height = textures.iter().map(|(_, t)| t.height()).max().unwrap_or(1);
+    let mut atlas = RgbaImage::new(width, height);
+
+    let mut tiles = HashMap::new();
+    let mut left = 0;
+    for (block_type, texture) in textures {
+        imageops::replace(&mut atlas, &texture, left as i64, 0);
+        let size = Vec2::new(texture.width() as f32, texture.height() as f32);
+        tiles.insert(
+            block_type,
+            AtlasTile {
+                uv_min: Vec2::new(left as f32 / width as f32, 0.),
+                uv_max: Vec2::new(
+                    (left + texture.width()) as f32 / width as f32,
+                    texture.height() as f32 / height as f32,
+                ),
+                size,
+            },
+        );
+        left += texture.width();
+    }
+    (atlas, tiles)
+}
+
+/// Marker component for the entity that draws a chunk
+#[derive(Component)]
+pub struct ChunkMesh;
+
+/// Resource on the client with the entity and mesh that draws each chunk
+/// Meshes have room for every block in the chunk, so that a block can change
+/// without building the whole mesh again
+#[derive(Default)]
+pub struct ChunkMeshes {
+    chunks: HashMap<(i64, u64), (Entity, Handle<Mesh>)>,
+}
+
+impl ChunkMeshes {
+    /// Draw a chunk, or draw it again if it already is
+    pub fn render(
+        &mut self,
+        commands: &mut Commands,
+        meshes: &mut Assets<Mesh>,
+        atlas: &BlockAtlas,
+        chunk: &Chunk,
+    ) {
+        info!(
+            "rendering chunk ({}, {})",
+            chunk.chunk_x, chunk.chunk_number
+        );
+        let position = (chunk.chunk_x, chunk.chunk_number);
+        let mesh = build_mesh(chunk, &atlas.tiles);
+
+        // reuse the entity, only the mesh changes
+        if let Some((_, handle)) = self.chunks.get(&position) {
+            if let Some(old_mesh) = meshes.get_mut(handle) {
+                *old_mesh = mesh;
+                return;
+            }
+        }
+
+        let handle = meshes.add(mesh);
+        let entity = commands
+            .spawn_bundle(MaterialMesh2dBundle {
+                mesh: Mesh2dHandle(handle.clone()),
+                material: atlas.material.clone(),
+                transform: Transform::from_translation(
+                    chunk.position().block(0, 0).center().to_render().extend(1.),
+                ),
+                ..default()
+            })
+            .insert(ChunkMesh)
+            .id();
+        self.chunks.insert(position, (entity, handle));
+    }
+
+    /// Stop drawing a chunk
+    pub fn remove(&mut self, commands: &mut Commands, chunk_x: i64, chunk_number: u64) {
+        if let Some((entity, _)) = self.chunks.remove(&(chunk_x, chunk_number)) {
+            info!("derendering chunk ({}, {})", chunk_x, chunk_number);
+            commands.entity(entity).despawn();
+        }
+    }
+
+    /// Positions of the chunks being drawn
+    pub fn positions(&self) -> Vec<(i64, u64)> {
+        self.chunks.keys().copied().collect()
+    }
+
+    /// Whether a chunk is being drawn
+    pub fn is_rendered(&self, chunk_x: i64, chunk_number: u64) -> bool {
+        self.chunks.contains_key(&(chunk_x, chunk_number))
+    }
+
+    /// Redraw the block at (x, y) in a chunk, which changed to block
+    pub fn set_block(
+        &self,
+        meshes: &mut Assets<Mesh>,
+        atlas: &BlockAtlas,
+        (chunk_x, chunk_number): (i64, u64),
+        (x, y): (usize, usize),
+        block: Option<Block>,
+    ) {
+        let mesh = self
+            .chunks
+            .get(&(chunk_x, chunk_number))
+            .and_then(|(_, handle)| meshes.get_mut(handle));
+        if let Some(mesh) = mesh {
+            set_quad(mesh, &atlas.tiles, x, y, block);
+        }
+    }
+}
+
+/// Client system that draws the chunks that the camera can see, and stops drawing the rest
+/// Chunks that go off screen stay in the terrain, so they can be drawn again when they come back
+pub fn update_visible_chunks(
+    mut commands: Commands,
+    camera: Query<&Transform, With<CharacterCamera>>,
+    windows: Res<Windows>,
+    terrain: Res<Terrain>,
+    mut chunk_meshes: ResMut<ChunkMeshes>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    atlas: Res<BlockAtlas>,
+) {
+    let camera = match camera.get_single() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    let window_size = windows
+        .get_primary()
+        .map(|window| Vec2::new(window.width(), window.height()))
+        .unwrap_or(Vec2::new(WIN_W, WIN_H));
+    let view_size = window_size * camera.scale.truncate() + 2. * VIEW_MARGIN;
+
+    let visible: HashSet<(i64, u64)> = visible_chunks(camera.translation.truncate(), view_size)
+        .into_iter()
+        .collect();
+
+    for (chunk_x, chunk_number) in chunk_meshes.positions() {
+        if !visible.contains(&(chunk_x, chunk_number)) {
+            chunk_meshes.remove(&mut commands, chunk_x, chunk_number);
+        }
+    }
+    for (chunk_x, chunk_number) in visible {
+        if chunk_meshes.is_rendered(chunk_x, chunk_number) {
+            continue;
+        }
+        if let Some(chunk) = terrain.find_chunk(chunk_x, chunk_number) {
+            chunk_meshes.render(&mut commands, &mut meshes, &atlas, chunk);
+        }
+    }
+}
+
+/// The chunks that overlap a view of view_size pixels centered on center, in rendering coordinates
+fn visible_chunks(center: Vec2, view_size: Vec2) -> Vec<(i64, u64)> {
+    // blocks are centered on their position, so they stick out half a block past it
+    let radius = view_size / 2. / BLOCK_SIZE + 0.5;
+    chunks_in_area(
+        WorldPos::from_render(center).block(),
+        radius.x.ceil() as usize,
+        radius.y.ceil() as usize,
+    )
+}
+
+/// Build the mesh for a chunk, a quad for every block, relative to the chunk's top left block
+/// Empty blocks get a quad with no area, so that they are there to fill in later
+fn build_mesh(chunk: &Chunk, tiles: &HashMap<BlockType, AtlasTile>) -> Mesh {
+    let blocks = CHUNK_WIDTH * CHUNK_HEIGHT;
+    let mut positions = Vec::with_capacity(blocks * 4);
+    let mut uvs = Vec::with_capacity(blocks * 4);
+    let mut colors = Vec::with_capacity(blocks * 4);
+    let mut indices = Vec::with_capacity(blocks * 6);
+
+    for (y, row) in chunk.blocks.iter().enumerate() {
+        for (x, block) in row.iter().enumerate() {
+            let (quad_positions, quad_uvs) = quad(tiles, x, y, block.map(|b| b.block_type));
+            let first = positions.len() as u16;
+            positions.extend(quad_positions);
+            uvs.extend(quad_uvs);
+            colors.extend([block_color(*block); 4]);
+            indices.extend([0, 3, 2, 0, 2, 1].map(|i| first + i));
+        }
+    }
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, vec![[0., 0., 1.]; positions.len()]);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_COLOR, colors);
+    mesh.set_indices(Some(Indices::U16(indices)));
+    mesh
+}
+
+/// Change the quad for the block at (x, y) in a chunk's mesh
+fn set_quad(
+    mesh: &mut Mesh,
+    tiles: &HashMap<BlockType, AtlasTile>,
+    x: usize,
+    y: usize,
+    block: Option<Block>,
+) {
+    let (quad_positions, quad_uvs) = quad(tiles, x, y, block.map(|b| b.block_type));
+    let first = (y * CHUNK_WIDTH + x) * 4;
+
+    if let Some(VertexAttributeValues::Float32x3(positions)) =
+        mesh.attribute_mut(Mesh::ATTRIBUTE_POSITION)
+    {
+        positions[first..first + 4].copy_from_slice(&quad_positions);
+    }
+    if let Some(VertexAttributeValues::Float32x2(uvs)) = mesh.attribute_mut(Mesh::ATTRIBUTE_UV_0) {
+        uvs[first..first + 4].copy_from_slice(&quad_uvs);
+    }
+    if let Some(VertexAttributeValues::Float32x4(colors)) =
+        mesh.attribute_mut(Mesh::ATTRIBUTE_COLOR)
+    {
+        colors[first..first + 4].fill(block_color(block));
+    }
+}
+
+/// What a block's texture gets multiplied by, from its state:
+/// its variant tints it, and it gets darker the more it has been mined
+fn block_color(block: Option<Block>) -> [f32; 4] {
+    let block = match block {
+        Some(block) => block,
+        None => return [1.; 4],
+    };
+
+    let [r, g, b] = match block.block_type {
+        BlockType::Leaves => LEAF_TINTS[block.state.variant as usize % LEAF_TINTS.len()],
+        _ => [1.; 3],
+    };
+    let damage = block.state.damage as f32 / block.block_type.durability() as f32;
+    let shade = 1. - MAX_DAMAGE_SHADE * damage.min(1.);
+    [r * shade, g * shade, b * shade, 1.]
+}
+
+/// The corners of a block's quad and where they are in the atlas,
+/// top left, top right, bottom right, bottom left
+fn quad(
+    tiles: &HashMap<BlockType, AtlasTile>,
+    x: usize,
+    y: usize,
+    block_type: Option<BlockType>,
+) -> ([[f32; 3]; 4], [[f32; 2]; 4]) {
+    let center = Vec2::new(x as f32 * BLOCK_SIZE, -(y as f32) * BLOCK_SIZE);
+    let tile = match block_type.and_then(|block_type| tiles.get(&block_type)) {
+        Some(tile) => tile,
+        None => return ([[center.x, center.y, 0.]; 4], [[0., 0.]; 4]),
+    };
+
+    let half = tile.size / 2.;
+    let (left, right) = (center.x - half.x, center.x + half.x);
+    let (top, bottom) = (center.y + half.y, center.y - half.y);
+    let (min, max) = (tile.uv_min, tile.uv_max);
+    (
+        [
+            [left, top, 0.],
+            [right, top, 0.],
+            [right, bottom, 0.],
+            [left, bottom, 0.],
+        ],
+        [
+            [min.x, min.y],
+            [max.x, min.y],
+            [max.x, max.y],
+            [min.x, max.y],
+        ],
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ASSETS_DIR;
+
+    fn positions(mesh: &Mesh) -> &Vec<[f32; 3]> {
+        match mesh.attribute(Mesh::ATTRIBUTE_POSITION) {
+            Some(VertexAttributeValues::Float32x3(positions)) => positions,
+            _ => panic!("mesh has no positions"),
+        }
+    }
+
+    /// Area of the quad for the block at (x, y)
+    fn quad_area(mesh: &Mesh, x: usize, y: usize) -> f32 {
+        let corners = &positions(mesh)[(y * CHUNK_WIDTH + x) * 4..];
+        (corners[1][0] - corners[0][0]) * (corners[0][1] - corners[3][1])
+    }
+
+    #[test]
+    fn chunks_on_screen_are_visible() {
+        let view = Vec2::new(WIN_W, WIN_H);
+        // the top left block of chunk (0, 0) in the middle of the screen
+        let mut visible = visible_chunks(Vec2::ZERO, view);
+        visible.sort();
+        assert_eq!(visible, vec![(-1, 0), (0, 0)]);
+
+        // in the middle of chunk (2, 3), nothing else is close enough
+        let middle = Vec2::new(
+            (2.5 * CHUNK_WIDTH as f32) * BLOCK_SIZE,
+            -(3.5 * CHUNK_HEIGHT as f32) * BLOCK_SIZE,
+        );
+        assert_eq!(visible_chunks(middle, view), vec![(2, 3)]);
+
+        // a much bigger view sees the chunks around it too
+        let mut visible = visible_chunks(middle, view * 4.);
+        visible.sort();
+        assert_eq!(visible.len(), 9);
+        assert_eq!(visible[0], (1, 2));
+
+        // far above the surface there is nothing to see
+        assert!(visible_chunks(Vec2::new(0., 10000.), view).is_empty());
+    }
+
+    #[test]
+    fn atlas_has_every_texture() {
+        let (atlas, tiles) = pack_textures(Path::new(ASSETS_DIR));
+        assert!(!tiles.contains_key(&BlockType::CaveVoid));
+        assert_eq!(tiles[&BlockType::Sand].size, Vec2::new(32., 32.));
+        // the palm tree is the biggest texture
+        assert_eq!(tiles[&BlockType::PalmTreeBlock].size, Vec2::new(128., 512.));
+        assert_eq!(atlas.height(), 512);
+
+        // no tiles overlap
+        let mut tiles: Vec<AtlasTile> = tiles.into_values().collect();
+        tiles.sort_by(|a, b| a.uv_min.x.total_cmp(&b.uv_min.x));
+        for pair in tiles.windows(2) {
+            assert!(pair[0].uv_max.x <= pair[1].uv_min.x);
+        }
+    }
+
+    #[test]
+    fn chunk_mesh_patches_in_place() {
+        let (_, tiles) = pack_textures(Path::new(ASSETS_DIR));
+        let mut chunk = Chunk::empty(0, 0);
+        chunk.blocks[2][3] = Some(Block::new(BlockType::Limestone));
+        chunk.blocks[2][4] = Some(Block::new(BlockType::CaveVoid));
+
+        let mut mesh = build_mesh(&chunk, &tiles);
+        assert_eq!(positions(&mesh).len(), CHUNK_WIDTH * CHUNK_HEIGHT * 4);
+        assert_eq!(
+            mesh.indices().unwrap().len(),
+            CHUNK_WIDTH * CHUNK_HEIGHT * 6
+        );
+        assert_eq!(quad_area(&mesh, 3, 2), BLOCK_SIZE * BLOCK_SIZE);
+        assert_eq!(quad_area(&mesh, 4, 2), 0.);
+        assert_eq!(quad_area(&mesh, 0, 0), 0.);
+
+        // mine the block and place a different one, which is the same as building it again
+        set_quad(&mut mesh, &tiles, 3, 2, None);
+        set_quad(
+            &mut mesh,
+            &tiles,
+            10,
+            20,
+            Some(Block::new(BlockType::Water)),
+        );
+        assert_eq!(quad_area(&mesh, 3, 2), 0.);
+        chunk.blocks[2][3] = None;
+        chunk.blocks[20][10] = Some(Block::new(BlockType::Water));
+        assert_eq!(positions(&mesh), positions(&build_mesh(&chunk, &tiles)));
+    }
+
+    #[test]
+    fn block_state_changes_color() {
+        let (_, tiles) = pack_textures(Path::new(ASSETS_DIR));
+        let mut block = Block::new(BlockType::Granite);
+        assert_eq!(block_color(Some(block)), [1.; 4]);
+
+        // darker the more it has been mined
+        let mut mesh = build_mesh(&Chunk::empty(0, 0), &tiles);
+        block.state.damage = block.block_type.durability() / 2;
+        set_quad(&mut mesh, &tiles, 7, 1, Some(block));
+        let colors = match mesh.attribute(Mesh::ATTRIBUTE_COLOR) {
+            Some(VertexAttributeValues::Float32x4(colors)) => colors,
+            _ => panic!("mesh has no colors"),
+        };
+        let shaded = colors[(CHUNK_WIDTH + 7) * 4];
+        assert!(shaded[0] < 1. && shaded[0] > 1. - MAX_DAMAGE_SHADE);
+        assert_eq!(shaded[3], 1.);
+
+        // leaves of different variants look different
+        let mut leaves = Block::new(BlockType::Leaves);
+        let green = block_color(Some(leaves));
+        leaves.state.variant = 2;
+        assert_ne!(block_color(Some(leaves)), green);
+    }
+}
@@ -0,0 +1,453 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{ErrorKind, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use super::*;
+
+/// How many chunks across a region file holds
+const REGION_WIDTH: i64 = 8;
+/// How many chunks down a region file holds
+const REGION_HEIGHT: u64 = 8;
+/// How many chunks a region file holds
+const REGION_CHUNKS: usize = REGION_WIDTH as usize * REGION_HEIGHT as usize;
+
+/// Every region file starts with this, so that other files don't get mistaken for one
+const REGION_MAGIC: &[u8; 4] = b"KRGN";
+/// Offset, length and checksum, each a little-endian u32
+const ENTRY_SIZE: usize = 12;
+/// The magic and the offset table, chunk data comes after it
+const HEADER_SIZE: usize = REGION_MAGIC.len() + REGION_CHUNKS * ENTRY_SIZE;
+
+/// A region file gets rewritten without its old chunk data once there is more of it
+/// than chunks that are still used, and at least this many bytes
+const MIN_COMPACT_WASTE: u64 = 16 * 1024;
+
+/// Resource on the server that every chunk of a saved world is kept in
+/// Chunks are grouped into region files of REGION_WIDTH x REGION_HEIGHT chunks,
+/// each with an offset table so that one chunk can be read or rewritten without the rest
+/// Each chunk has a checksum, so a broken chunk only loses that chunk
+#[derive(Clone, Debug)]
+pub struct ChunkStore {
+    dir: PathBuf,
+    /// Held while a region file is read or written,
+    /// since chunks get loaded from tasks while the game tick saves others
+    lock: Arc<Mutex<()>>,
+}
+
+/// Where a chunk is in a region file, length 0 if the region doesn't have it
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct RegionEntry {
+    offset: u32,
+    length: u32,
+    checksum: u32,
+}
+
+type RegionTable = [RegionEntry; REGION_CHUNKS];
+
+impl ChunkStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self {
+            dir,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// The region file that a chunk is in, and the chunk's index in its offset table
+    fn region_of(&self, chunk_x: i64, chunk_number: u64) -> (PathBuf, usize) {
+        let region_x = chunk_x.div_euclid(REGION_WIDTH);
+        let region_y = chunk_number / REGION_HEIGHT;
+        let index = (chunk_number % REGION_HEIGHT) as usize * REGION_WIDTH as usize
+            + chunk_x.rem_euclid(REGION_WIDTH) as usize;
+        (
+            self.dir.join(format!("{}_{}.region", region_x, region_y)),
+            index,
+        )
+    }
+
+    /// Write a chunk to its region file, replacing the one at its position if it was stored before
+    pub fn save_chunk(&self, chunk: &Chunk) -> Result<(), String> {
+        let encoded = bincode::encode_to_vec(chunk, BINCODE_CONFIG)
+            .map_err(|e| format!("unable to encode chunk, {}", e))?;
+        let checksum = checksum(&encoded);
+
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let (path, index) = self.region_of(chunk.chunk_x, chunk.chunk_number);
+        let (mut file, mut table) = self.open_region(&path)?;
+
+        // the new data goes at the end, and the old data stays until the region gets compacted,
+        // so that a crash while writing can't break the copy that the table still points to
+        let write_error = |e: std::io::Error| format!("unable to write region {:?}, {}", path, e);
+        let end = file.seek(SeekFrom::End(0)).map_err(write_error)?;
+        let offset = u32::try_from(end).map_err(|_| format!("region {:?} is too big", path))?;
+        file.write_all(&encoded).map_err(write_error)?;
+
+        table[index] = RegionEntry {
+            offset,
+            length: encoded.len() as u32,
+            checksum,
+        };
+        write_entry(&mut file, index, &table[index]).map_err(write_error)?;
+
+        let used: u64 = table.iter().map(|entry| entry.length as u64).sum();
+        let waste = end + encoded.len() as u64 - HEADER_SIZE as u64 - used;
+        if waste > used.max(MIN_COMPACT_WASTE) {
+            compact_region(&path, &mut file, &table)?;
+        }
+        Ok(())
+    }
+
+    /// Read a chunk from its region file, or None if it was never stored
+    pub fn load_chunk(&self, chunk_x: i64, chunk_number: u64) -> Result<Option<Chunk>, String> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let (path, index) = self.region_of(chunk_x, chunk_number);
+        let mut file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("unable to open region {:?}, {}", path, e)),
+        };
+
+        let entry = read_table(&path, &mut file)?[index];
+        if entry.length == 0 {
+            return Ok(None);
+        }
+
+        let encoded = read_entry(&mut file, &entry).map_err(|e| {
+            format!(
+                "unable to read chunk ({}, {}) from region {:?}, {}",
+                chunk_x, chunk_number, path, e
+            )
+        })?;
+        if checksum(&encoded) != entry.checksum {
+            return Err(format!(
+                "chunk ({}, {}) in region {:?} is corrupted",
+                chunk_x, chunk_number, path
+            ));
+        }
+
+        let (chunk, _size): (Chunk, usize) = bincode::decode_from_slice(&encoded, BINCODE_CONFIG)
+            .map_err(|e| {
+            format!(
+                "unable to decode chunk ({}, {}) from region {:?}, {}",
+                chunk_x, chunk_number, path, e
+            )
+        })?;
+        if (chunk.chunk_x, chunk.chunk_number) != (chunk_x, chunk_number) {
+            return Err(format!(
+                "region {:?} has chunk ({}, {}) where ({}, {}) should be",
+                path, chunk.chunk_x, chunk.chunk_number, chunk_x, chunk_number
+            ));
+        }
+        Ok(Some(chunk))
+    }
+
+    /// Read a chunk from the store, or generate it if it was never stored
+    /// A chunk that can't be read gets generated again, which only loses the changes made to it
+    pub fn load_or_generate(
+        &self,
+        generator: &dyn ChunkGenerator,
+        seed: u64,
+        chunk_x: i64,
+        chunk_number: u64,
+    ) -> Chunk {
+        match self.load_chunk(chunk_x, chunk_number) {
+            Ok(Some(chunk)) => chunk,
+            Ok(None) => generator.chunk(seed, chunk_x, chunk_number),
+            Err(e) => {
+                error!("{}, generating it again", e);
+                generator.chunk(seed, chunk_x, chunk_number)
+            }
+        }
+    }
+
+    /// Delete every stored chunk
+    pub fn clear(&self) -> Result<(), String> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        match fs::remove_dir_all(&self.dir) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                Err(format!("unable to remove region dir {:?}, {}", self.dir, e))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The positions of every stored chunk, leaving out regions that can't be read
+    pub fn positions(&self) -> Vec<(i64, u64)> {
+        let _guard = self.lock.lock().unwrap_or_else(|e| e.into_inner());
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(_) => return Vec::new(),
+        };
+
+        let mut positions = Vec::new();
+        for entry in entries.flatten() {
+            let name = entry.file_name().into_string().unwrap_or_default();
+            let region = name
+                .strip_suffix(".region")
+                .and_then(|name| name.split_once('_'))
+                .and_then(|(x, y)| Some((x.parse::<i64>().ok()?, y.parse::<u64>().ok()?)));
+            let (region_x, region_y) = match region {
+                Some(region) => region,
+                None => continue,
+            };
+
+            let path = entry.path();
+            let table = match File::open(&path)
+                .map_err(|e| format!("unable to open region {:?}, {}", path, e))
+                .and_then(|mut file| read_table(&path, &mut file))
+            {
+                Ok(table) => table,
+                Err(e) => {
+                    warn!("{}", e);
+                    continue;
+                }
+            };
+
+            for (index, entry) in table.iter().enumerate() {
+                if entry.length > 0 {
+                    positions.push((
+                        region_x * REGION_WIDTH + (index % REGION_WIDTH as usize) as i64,
+                        region_y * REGION_HEIGHT + (index / REGION_WIDTH as usize) as u64,
+                    ));
+                }
+            }
+        }
+        positions.sort();
+        positions
+    }
+
+    /// Open a region file to write to, creating it if it doesn't exist yet
+    /// A region with a broken offset table gets moved out of the way and started over
+    fn open_region(&self, path: &Path) -> Result<(File, RegionTable), String> {
+        fs::create_dir_all(&self.dir)
+            .map_err(|e| format!("unable to create region dir {:?}, {}", self.dir, e))?;
+
+        let open = || {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+                .map_err(|e| format!("unable to open region {:?}, {}", path, e))
+        };
+        let mut file = open()?;
+
+        let length = file
+            .metadata()
+            .map_err(|e| format!("unable to open region {:?}, {}", path, e))?
+            .len();
+        if length > 0 {
+            match read_table(path, &mut file) {
+                Ok(table) => return Ok((file, table)),
+                Err(e) => {
+                    // keep it around, in case someone wants to get the chunks out of it by hand
+                    let broken = path.with_extension("region.broken");
+                    error!("{}, moving it to {:?}", e, broken);
+                    drop(file);
+                    fs::rename(path, &broken)
+                        .map_err(|e| format!("unable to move region {:?}, {}", path, e))?;
+                    file = open()?;
+                }
+            }
+        }
+
+        let table = [RegionEntry::default(); REGION_CHUNKS];
+        write_header(&mut file, &table)
+            .map_err(|e| format!("unable to write region {:?}, {}", path, e))?;
+        Ok((file, table))
+    }
+}
+
+/// Read and check the offset table at the start of a region file
+/// An entry that points past the end of the file only breaks that chunk, load_chunk finds it
+fn read_table(path: &Path, file: &mut File) -> Result<RegionTable, String> {
+    let mut header = vec![0; HEADER_SIZE];
+    file.seek(SeekFrom::Start(0))
+        .and_then(|_| file.read_exact(&mut header))
+        .map_err(|e| format!("unable to read region {:?}, {}", path, e))?;
+    if !header.starts_with(REGION_MAGIC) {
+        return Err(format!("{:?} is not a region file", path));
+    }
+
+    let mut table = [RegionEntry::default(); REGION_CHUNKS];
+    for (entry, bytes) in table
+        .iter_mut()
+        .zip(header[REGION_MAGIC.len()..].chunks_exact(ENTRY_SIZE))
+    {
+        let field = |i: usize| u32::from_le_bytes(bytes[i * 4..i * 4 + 4].try_into().unwrap());
+        *entry = RegionEntry {
+            offset: field(0),
+            length: field(1),
+            checksum: field(2),
+        };
+        if entry.length > 0 && (entry.offset as usize) < HEADER_SIZE {
+            return Err(format!("region {:?} has a broken offset table", path));
+        }
+    }
+    Ok(table)
+}
+
+/// Read the data of a chunk in a region file, without checking it
+fn read_entry(file: &mut File, entry: &RegionEntry) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0; entry.length as usize];
+    file.seek(SeekFrom::Start(entry.offset as u64))?;
+    file.read_exact(&mut data)?;
+    Ok(data)
+}
+
+/// Write the magic and the whole offset table to the start of a region file
+fn write_header(file: &mut File, table: &RegionTable) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(REGION_MAGIC)?;
+    for (index, entry) in table.iter().enumerate() {
+        write_entry(file, index, entry)?;
+    }
+    Ok(())
+}
+
+/// Write one entry of the offset table of a region file
+fn write_entry(file: &mut File, index: usize, entry: &RegionEntry) -> std::io::Result<()> {
+    let mut bytes = [0; ENTRY_SIZE];
+    for (i, field) in [entry.offset, entry.length, entry.checksum]
+        .iter()
+        .enumerate()
+    {
+        bytes[i * 4..i * 4 + 4].copy_from_slice(&field.to_le_bytes());
+    }
+    file.seek(SeekFrom::Start(
+        (REGION_MAGIC.len() + index * ENTRY_SIZE) as u64,
+    ))?;
+    file.write_all(&bytes)
+}
+
+/// Rewrite a region file with only the chunk data that its table still points to
+/// The new file replaces the old one once it is completely written
+fn compact_region(path: &Path, file: &mut File, table: &RegionTable) -> Result<(), String> {
+    let error = |e: std::io::Error| format!("unable to compact region {:?}, {}", path, e);
+
+    let temp_path = path.with_extension("region.tmp");
+    let mut compacted = File::create(&temp_path).map_err(error)?;
+    let mut new_table = *table;
+    let mut offset = HEADER_SIZE as u32;
+    compacted
+        .seek(SeekFrom::Start(offset as u64))
+        .map_err(error)?;
+
+    for (entry, new_entry) in table.iter().zip(new_table.iter_mut()) {
+        if entry.length == 0 {
+            continue;
+        }
+        read_entry(file, entry)
+            .and_then(|data| compacted.write_all(&data))
+            .map_err(error)?;
+        new_entry.offset = offset;
+        offset += entry.length;
+    }
+
+    write_header(&mut compacted, &new_table).map_err(error)?;
+    drop(compacted);
+    fs::rename(&temp_path, path).map_err(error)
+}
+
+/// FNV-1a hash of some bytes, to tell if a chunk in a region file got corrupted
+fn checksum(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0x811c9dc5, |hash, byte| {
+        (hash ^ *byte as u32).wrapping_mul(0x01000193)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A store in its own empty folder
+    fn test_store(name: &str) -> ChunkStore {
+        let dir = std::env::temp_dir().join(format!("krabs-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        ChunkStore::new(dir)
+    }
+
+    fn region_length(store: &ChunkStore, chunk_x: i64, chunk_number: u64) -> u64 {
+        let (path, _) = store.region_of(chunk_x, chunk_number);
+        fs::metadata(path).unwrap().len()
+    }
+
+    #[test]
+    fn chunks_round_trip_through_regions() {
+        let store = test_store("round-trip");
+        assert_eq!(store.load_chunk(-3, 4), Ok(None));
+        assert!(store.positions().is_empty());
+
+        let mut chunk = Chunk::empty(-3, 4);
+        chunk.blocks[5][6] = Some(Block::new(BlockType::Iron));
+        store.save_chunk(&chunk).unwrap();
+        // one in the same region, and one in another
+        store.save_chunk(&Chunk::empty(-1, 0)).unwrap();
+        store.save_chunk(&Chunk::empty(2, 9)).unwrap();
+
+        assert_eq!(store.load_chunk(-3, 4), Ok(Some(chunk)));
+        assert_eq!(store.load_chunk(-1, 0), Ok(Some(Chunk::empty(-1, 0))));
+        assert_eq!(store.load_chunk(-2, 4), Ok(None));
+        assert_eq!(store.positions(), vec![(-3, 4), (-1, 0), (2, 9)]);
+
+        // saving again replaces it
+        store.save_chunk(&Chunk::empty(-3, 4)).unwrap();
+        assert_eq!(store.load_chunk(-3, 4), Ok(Some(Chunk::empty(-3, 4))));
+
+        store.clear().unwrap();
+        assert!(store.positions().is_empty());
+        assert_eq!(store.load_chunk(2, 9), Ok(None));
+        store.clear().unwrap();
+    }
+
+    #[test]
+    fn regions_get_compacted() {
+        let store = test_store("compact");
+        let generator = generator::DefaultGenerator::new().unwrap();
+        let mut chunk = generator.chunk(0, 0, 1);
+        store.save_chunk(&chunk).unwrap();
+        store.save_chunk(&generator.chunk(0, 1, 1)).unwrap();
+        let length = region_length(&store, 0, 1);
+
+        // a chunk that keeps changing leaves old copies behind, until the region gets compacted
+        for x in 0..CHUNK_WIDTH {
+            chunk.blocks[0][x] = None;
+            store.save_chunk(&chunk).unwrap();
+            assert!(region_length(&store, 0, 1) < length + 2 * MIN_COMPACT_WASTE);
+        }
+        assert_eq!(store.load_chunk(0, 1), Ok(Some(chunk)));
+        assert_eq!(store.load_chunk(1, 1), Ok(Some(generator.chunk(0, 1, 1))));
+        store.clear().unwrap();
+    }
+
+    #[test]
+    fn broken_chunks_only_lose_themselves() {
+        let store = test_store("broken");
+        store.save_chunk(&Chunk::empty(0, 1)).unwrap();
+        store.save_chunk(&Chunk::empty(1, 1)).unwrap();
+
+        // flip a byte in the first chunk's data
+        let (path, index) = store.region_of(0, 1);
+        let entry = read_table(&path, &mut File::open(&path).unwrap()).unwrap()[index];
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[entry.offset as usize] ^= 0xff;
+        fs::write(&path, &bytes).unwrap();
+
+        assert!(store.load_chunk(0, 1).is_err());
+        assert_eq!(store.load_chunk(1, 1), Ok(Some(Chunk::empty(1, 1))));
+        // and it can be saved over
+        store.save_chunk(&Chunk::empty(0, 1)).unwrap();
+        assert_eq!(store.load_chunk(0, 1), Ok(Some(Chunk::empty(0, 1))));
+
+        // a region that isn't one anymore gets started over
+        fs::write(&path, [1, 2, 3]).unwrap();
+        assert!(store.load_chunk(1, 1).is_err());
+        store.save_chunk(&Chunk::empty(0, 1)).unwrap();
+        assert_eq!(store.positions(), vec![(0, 1)]);
+        assert!(path.with_extension("region.broken").exists());
+        store.clear().unwrap();
+    }
+}
@@ -0,0 +1,513 @@
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use std::{collections::HashMap, fs, path::Path};
+
+use super::*;
+use crate::procedural_functions::{generate_seed, seed_from_str};
+
+/// Where the structure templates are loaded from
+pub const STRUCTURES_DIR: &str = "assets/structures";
+
+/// What a character in a structure's grid turns into
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StructureCell {
+    /// Place this block
+    Block(BlockType),
+    /// Clear out whatever block is there
+    Air,
+}
+
+/// Where a structure's anchor can go
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Location {
+    /// Right on top of the ground, only in the surface chunks
+    Surface,
+    /// Right on top of the floor of a cave
+    CaveFloor,
+    /// Anywhere at all, including inside of solid rock
+    Buried,
+}
+
+/// What has to already be where a structure's blocks go for it to be placed
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fit {
+    /// Nothing, so that the structure doesn't cut into the terrain
+    Air,
+    /// Blocks, so that the structure is hidden in the rock
+    Solid,
+    /// Don't check, the structure replaces whatever is there
+    Any,
+}
+
+/// Rules for where and how often a structure gets placed
+#[derive(Deserialize, Debug, Clone)]
+pub struct Placement {
+    pub location: Location,
+    pub fit: Fit,
+    /// How many random places in each chunk to try
+    pub attempts: u32,
+    /// Chance that each of the attempts happens at all
+    pub chance: f32,
+    /// Shallowest chunk number the structure can be in
+    #[serde(default)]
+    pub min_depth: u64,
+    /// Deepest chunk number the structure can be in, no limit if not given
+    #[serde(default)]
+    pub max_depth: Option<u64>,
+}
+
+/// A structure from a template file in STRUCTURES_DIR
+#[derive(Deserialize, Debug, Clone)]
+pub struct StructureTemplate {
+    pub name: String,
+    /// Rows of the structure from top to bottom, one character per block
+    /// Spaces leave the terrain as it is
+    pub grid: Vec<String>,
+    /// What each character (other than space) in the grid means
+    pub palette: HashMap<char, StructureCell>,
+    /// The (x, y) cell of the grid that goes on the chosen position
+    pub anchor: (usize, usize),
+    pub placement: Placement,
+}
+
+impl StructureTemplate {
+    /// Parse a template and make sure it makes sense
+    pub fn from_ron(text: &str) -> Result<Self, String> {
+        let template: StructureTemplate = ron::from_str(text).map_err(|e| e.to_string())?;
+
+        let width = template.width();
+        if template.grid.is_empty() || width == 0 {
+            return Err(format!("{}: grid is empty", template.name));
+        }
+        for (y, row) in template.grid.iter().enumerate() {
+            if row.chars().count() != width {
+                return Err(format!(
+                    "{}: row {} is {} wide instead of {}",
+                    template.name,
+                    y,
+                    row.chars().count(),
+                    width
+                ));
+            }
+            if let Some(c) = row
+                .chars()
+                .find(|c| *c != ' ' && !template.palette.contains_key(c))
+            {
+                return Err(format!("{}: '{}' is not in the palette", template.name, c));
+            }
+        }
+
+        if template.anchor.0 >= width || template.anchor.1 >= template.grid.len() {
+            return Err(format!(
+                "{}: anchor {:?} is outside of the grid",
+                template.name, template.anchor
+            ));
+        }
+
+        let placement = &template.placement;
+        if !(0. ..=1.).contains(&placement.chance) {
+            return Err(format!("{}: chance must be from 0 to 1", template.name));
+        }
+        if placement.max_depth.unwrap_or(u64::MAX) < placement.min_depth {
+            return Err(format!("{}: max_depth is above min_depth", template.name));
+        }
+
+        Ok(template)
+    }
+
+    fn width(&self) -> usize {
+        self.grid.first().map_or(0, |row| row.chars().count())
+    }
+
+    fn height(&self) -> usize {
+        self.grid.len()
+    }
+
+    /// Every block of the structure, as (x, y) from the anchor
+    pub fn cells(&self) -> impl Iterator<Item = (i64, i64, StructureCell)> + '_ {
+        let (anchor_x, anchor_y) = (self.anchor.0 as i64, self.anchor.1 as i64);
+        self.grid.iter().enumerate().flat_map(move |(y, row)| {
+            row.chars().enumerate().filter_map(move |(x, c)| {
+                let cell = self.palette.get(&c)?;
+                Some((x as i64 - anchor_x, y as i64 - anchor_y, *cell))
+            })
+        })
+    }
+
+    /// Whether this structure can be in a row of chunks
+    fn allowed_at_depth(&self, chunk_number: u64) -> bool {
+        let placement = &self.placement;
+        if placement.location == Location::Surface && chunk_number != 0 {
+            return false;
+        }
+        chunk_number >= placement.min_depth
+            && chunk_number <= placement.max_depth.unwrap_or(u64::MAX)
+    }
+}
+
+/// Load every template in a folder, sorted by file name
+/// Templates that can't be loaded are left out, with an error in the log
+pub fn load_structures(dir: &Path) -> Vec<StructureTemplate> {
+    let mut paths: Vec<_> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "ron"))
+            .collect(),
+        Err(e) => {
+            error!("could not read structures from {:?}: {}", dir, e);
+            return Vec::new();
+        }
+    };
+    paths.sort();
+
+    let mut templates = Vec::new();
+    for path in paths {
+        let template = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|text| StructureTemplate::from_ron(&text));
+        match template {
+            Ok(template) => templates.push(template),
+            Err(e) => error!("could not load structure {:?}: {}", path, e),
+        }
+    }
+
+    info!("loaded {} structure(s)", templates.len());
+    templates
+}
+
+/// A structure in the world, anchored at a world block position
+#[derive(Debug, Clone)]
+pub struct PlacedStructure<'a> {
+    pub template: &'a StructureTemplate,
+    pub x: i64,
+    pub y: i64,
+}
+
+impl PlacedStructure<'_> {
+    /// Put the blocks of the structure that are inside of a chunk into it
+    pub fn stamp(&self, chunk: &mut Chunk) {
+        let left = chunk.chunk_x * CHUNK_WIDTH as i64;
+        let top = (chunk.chunk_number as usize * CHUNK_HEIGHT) as i64;
+
+        for (dx, dy, cell) in self.template.cells() {
+            let (x, y) = (self.x + dx - left, self.y + dy - top);
+            if x < 0 || y < 0 || x >= CHUNK_WIDTH as i64 || y >= CHUNK_HEIGHT as i64 {
+                continue;
+            }
+
+            chunk.blocks[y as usize][x as usize] = match cell {
+                StructureCell::Block(block_type) => Some(Block {
+                    block_type,
+                    state: BlockState {
+                        variant: self.variant(block_type),
+                        ..default()
+                    },
+                }),
+                StructureCell::Air => None,
+            };
+        }
+    }
+
+    /// Which variant the structure's blocks of a type are, the same for every block of it
+    /// (like all of a tree's leaves being one color), even when it is split across chunks
+    fn variant(&self, block_type: BlockType) -> u8 {
+        let hash = generate_seed(self.x as u64, vec![self.y as u64]);
+        (hash % block_type.variants() as u64) as u8
+    }
+}
+
+/// A random spot in a chunk to try placing a structure at
+struct Candidate<'a> {
+    template: &'a StructureTemplate,
+    x: usize,
+    y: usize,
+}
+
+/// The random spots to try placing structures at in a chunk
+/// Only depends on the seed, so these can be found without generating the chunk
+fn candidates(
+    templates: &[StructureTemplate],
+    seed: u64,
+    chunk_x: i64,
+    chunk_number: u64,
+) -> Vec<Candidate<'_>> {
+    let mut candidates = Vec::new();
+    for template in templates {
+        if !template.allowed_at_depth(chunk_number) {
+            continue;
+        }
+
+        for attempt in 0..template.placement.attempts {
+            let mut rand = StdRng::seed_from_u64(generate_seed(
+                seed,
+                vec![
+                    chunk_x as u64,
+                    chunk_number,
+                    seed_from_str(&template.name),
+                    attempt as u64,
+                ],
+            ));
+
+            if rand.gen::<f32>() >= template.placement.chance {
+                continue;
+            }
+
+            candidates.push(Candidate {
+                template,
+                x: rand.gen_range(0..CHUNK_WIDTH),
+                y: rand.gen_range(0..CHUNK_HEIGHT),
+            });
+        }
+    }
+    candidates
+}
+
+impl Candidate<'_> {
+    /// Whether the structure could reach into a chunk, wherever its anchor ends up
+    /// The anchor moves down from the candidate spot to the ground, but stays in its chunk
+    fn could_reach(&self, origin: (i64, u64), target: (i64, u64)) -> bool {
+        let template = self.template;
+        let origin_left = origin.0 * CHUNK_WIDTH as i64;
+        let origin_top = (origin.1 as usize * CHUNK_HEIGHT) as i64;
+
+        let (lowest_anchor_y, highest_anchor_y) = match template.placement.location {
+            Location::Surface => (0, CHUNK_HEIGHT - 1),
+            Location::CaveFloor => (self.y, CHUNK_HEIGHT - 1),
+            Location::Buried => (self.y, self.y),
+        };
+
+        let left = origin_left + self.x as i64 - template.anchor.0 as i64;
+        let right = left + template.width() as i64 - 1;
+        let top = origin_top + lowest_anchor_y as i64 - template.anchor.1 as i64;
+        let bottom = origin_top + highest_anchor_y as i64 - template.anchor.1 as i64
+            + template.height() as i64
+            - 1;
+
+        let target_left = target.0 * CHUNK_WIDTH as i64;
+        let target_top = (target.1 as usize * CHUNK_HEIGHT) as i64;
+        right >= target_left
+            && left < target_left + CHUNK_WIDTH as i64
+            && bottom >= target_top
+            && top < target_top + CHUNK_HEIGHT as i64
+    }
+
+    /// Find where the anchor goes in the chunk the candidate is in (without structures),
+    /// or None if the structure doesn't fit there
+    /// Only the part of the structure inside of that chunk is checked
+    fn fit(&self, chunk: &Chunk) -> Option<(usize, usize)> {
+        let blocks = &chunk.blocks;
+        let is_floor = |y: usize| blocks[y][self.x].is_none() && blocks[y + 1][self.x].is_some();
+
+        let (x, y) = match self.template.placement.location {
+            Location::Surface => (self.x, (0..CHUNK_HEIGHT - 1).find(|y| is_floor(*y))?),
+            Location::CaveFloor => (self.x, (self.y..CHUNK_HEIGHT - 1).find(|y| is_floor(*y))?),
+            Location::Buried => (self.x, self.y),
+        };
+
+        for (dx, dy, _) in self.template.cells() {
+            let (cell_x, cell_y) = (x as i64 + dx, y as i64 + dy);
+            if cell_x < 0
+                || cell_y < 0
+                || cell_x >= CHUNK_WIDTH as i64
+                || cell_y >= CHUNK_HEIGHT as i64
+            {
+                continue;
+            }
+
+            let block = blocks[cell_y as usize][cell_x as usize];
+            let fits = match self.template.placement.fit {
+                Fit::Air => block.is_none(),
+                Fit::Solid => block.is_some(),
+                Fit::Any => true,
+            };
+            if !fits {
+                return None;
+            }
+        }
+
+        Some((x, y))
+    }
+}
+
+/// All structures that reach into a chunk, including ones from the chunks around it
+/// base_chunk generates chunks without structures, for checking where structures fit;
+/// it is only called for the chunks around that have structures that could reach this one
+/// Structures are in the same order for every chunk, so overlapping structures come out the same
+pub fn placed_structures<'a>(
+    templates: &'a [StructureTemplate],
+    seed: u64,
+    chunk: &Chunk,
+    base_chunk: impl Fn(i64, u64) -> Chunk,
+) -> Vec<PlacedStructure<'a>> {
+    let target = (chunk.chunk_x, chunk.chunk_number);
+
+    let mut placed = Vec::new();
+    for origin_x in (target.0 - 1)..=(target.0 + 1) {
+        for origin_number in target.1.saturating_sub(1)..=(target.1 + 1) {
+            let origin = (origin_x, origin_number);
+            let candidates: Vec<Candidate> = candidates(templates, seed, origin_x, origin_number)
+                .into_iter()
+                .filter(|candidate| candidate.could_reach(origin, target))
+                .collect();
+            if candidates.is_empty() {
+                continue;
+            }
+
+            let generated;
+            let origin_chunk = if origin == target {
+                chunk
+            } else {
+                generated = base_chunk(origin_x, origin_number);
+                &generated
+            };
+
+            for candidate in candidates {
+                if let Some((x, y)) = candidate.fit(origin_chunk) {
+                    placed.push(PlacedStructure {
+                        template: candidate.template,
+                        x: origin_x * CHUNK_WIDTH as i64 + x as i64,
+                        y: (origin_number as usize * CHUNK_HEIGHT + y) as i64,
+                    });
+                }
+            }
+        }
+    }
+    placed
+}
+
+/// Add all structures that reach into a chunk to it, see placed_structures
+pub fn place_structures(
+    templates: &[StructureTemplate],
+    seed: u64,
+    chunk: &mut Chunk,
+    base_chunk: impl Fn(i64, u64) -> Chunk,
+) {
+    for structure in placed_structures(templates, seed, chunk, base_chunk) {
+        structure.stamp(chunk);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::world::{generator::*, TEST_SEED};
+
+    /// A five block wide line of basalt that can go anywhere, very often
+    const MARKER: &str = r#"(
+        name: "marker",
+        grid: ["BBBBB"],
+        palette: { 'B': Block(Basalt) },
+        anchor: (2, 0),
+        placement: (location: Buried, fit: Any, attempts: 64, chance: 1.0, min_depth: 1),
+    )"#;
+
+    #[test]
+    fn structure_assets_load() {
+        let files = fs::read_dir(STRUCTURES_DIR).unwrap().count();
+        let templates = load_structures(Path::new(STRUCTURES_DIR));
+        assert_eq!(templates.len(), files);
+        assert!(templates.iter().any(|t| t.name == "tree"));
+    }
+
+    #[test]
+    fn bad_templates_are_rejected() {
+        let ragged = MARKER.replace(r#"["BBBBB"]"#, r#"["BBBBB", "BB"]"#);
+        assert!(StructureTemplate::from_ron(&ragged).is_err());
+
+        let unknown_block = MARKER.replace(r#"["BBBBB"]"#, r#"["BBXBB"]"#);
+        assert!(StructureTemplate::from_ron(&unknown_block).is_err());
+
+        let bad_anchor = MARKER.replace("anchor: (2, 0)", "anchor: (5, 0)");
+        assert!(StructureTemplate::from_ron(&bad_anchor).is_err());
+
+        assert!(StructureTemplate::from_ron("not a template").is_err());
+        assert!(StructureTemplate::from_ron(MARKER).is_ok());
+    }
+
+    #[test]
+    fn structures_cross_chunk_borders() {
+        let templates = vec![StructureTemplate::from_ron(MARKER).unwrap()];
+        let base_chunk = |x, n| FlatGenerator.chunk(TEST_SEED, x, n);
+
+        let left = base_chunk(0, 1);
+        let right = base_chunk(1, 1);
+        let border = CHUNK_WIDTH as i64;
+
+        // both chunks agree on the structures across the border between them
+        let crossing = |chunk: &Chunk| {
+            let mut crossing: Vec<(i64, i64)> =
+                placed_structures(&templates, TEST_SEED, chunk, base_chunk)
+                    .iter()
+                    .filter(|s| s.x - 2 < border && s.x + 2 >= border)
+                    .map(|s| (s.x, s.y))
+                    .collect();
+            crossing.sort();
+            crossing
+        };
+        let crossing_left = crossing(&left);
+        assert!(!crossing_left.is_empty(), "no structures across the border");
+        assert_eq!(crossing_left, crossing(&right));
+
+        // and both halves get placed
+        let mut left = left;
+        let mut right = right;
+        place_structures(&templates, TEST_SEED, &mut left, base_chunk);
+        place_structures(&templates, TEST_SEED, &mut right, base_chunk);
+        for (x, y) in crossing_left {
+            let y = y as usize - CHUNK_HEIGHT;
+            for dx in -2..=2 {
+                let world_x = x + dx;
+                let block = if world_x < border {
+                    left.blocks[y][world_x as usize]
+                } else {
+                    right.blocks[y][(world_x - border) as usize]
+                };
+                assert_eq!(block, Some(Block::new(BlockType::Basalt)));
+            }
+        }
+    }
+
+    #[test]
+    fn structures_only_go_where_they_fit() {
+        let tree = load_structures(Path::new(STRUCTURES_DIR))
+            .into_iter()
+            .find(|t| t.name == "tree")
+            .unwrap();
+        let templates = vec![tree];
+
+        // solid ground everywhere, so no cave floors
+        let solid = |x, n| FlatGenerator.chunk(TEST_SEED, x, n);
+        let chunk = solid(0, 3);
+        assert!(placed_structures(&templates, TEST_SEED, &chunk, solid).is_empty());
+
+        // trees go on top of the ground, in the air
+        let mut chunk = Chunk::empty(0, 3);
+        for x in 0..CHUNK_WIDTH {
+            chunk.blocks[CHUNK_HEIGHT - 1][x] = Some(Block::new(BlockType::Limestone));
+        }
+        let floor = |x, n| {
+            let mut chunk = Chunk::empty(x, n);
+            for x in 0..CHUNK_WIDTH {
+                chunk.blocks[CHUNK_HEIGHT - 1][x] = Some(Block::new(BlockType::Limestone));
+            }
+            chunk
+        };
+        let trees = placed_structures(&templates, TEST_SEED, &chunk, floor);
+        assert!(!trees.is_empty());
+        for tree in trees {
+            assert_eq!(tree.y as usize % CHUNK_HEIGHT, CHUNK_HEIGHT - 2);
+        }
+    }
+
+    #[test]
+    fn default_chunks_have_structures() {
+        let generator = DefaultGenerator::new().unwrap();
+        let trunks = (1..6)
+            .map(|n| generator.chunk(TEST_SEED, 0, n))
+            .flat_map(|chunk| chunk.blocks.into_iter().flatten().flatten())
+            .filter(|block| block.block_type == BlockType::Trunk)
+            .count();
+        assert!(trunks > 0);
+    }
+}